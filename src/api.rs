@@ -0,0 +1,897 @@
+/* src/api.rs */
+
+use crate::config::ApiConfig;
+use crate::location::{GeoGroups, LocationKey, parse_asn};
+use crate::records::{MXRecord, RecordSet, ZoneConfig};
+use crate::reload;
+use crate::resolver::DnsResolver;
+use crate::zone::{CompiledZone, UNAVAILABLE_KEY, UNKNOWN_KEY};
+use axum::Router;
+use axum::extract::{self, Json, Query, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Table};
+use tracing::{error, info, warn};
+
+/// Zone file keys that cannot be used as subdomain labels.
+const RESERVED_LABELS: [&str; 9] = [
+    "apex",
+    "asn",
+    "asn_precedence",
+    "country",
+    "pops",
+    "soa",
+    "ttl",
+    "unavailable_policy",
+    "unknown_policy",
+];
+
+struct ApiState {
+    resolver: Arc<DnsResolver>,
+    /// Serializes edits, so two requests cannot both read a zone file and
+    /// then overwrite each other's change.
+    edit_lock: Mutex<()>,
+}
+
+type Shared = Arc<ApiState>;
+
+/// A failed request: the status and a message, sent as `{"error": "..."}`.
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(message: impl ToString) -> Self {
+        Self(StatusCode::BAD_REQUEST, message.to_string())
+    }
+
+    fn not_found(message: impl ToString) -> Self {
+        Self(StatusCode::NOT_FOUND, message.to_string())
+    }
+
+    fn internal(message: impl ToString) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, message.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Serves the admin API until `shutdown` is cancelled.
+pub fn spawn_server(
+    api: &ApiConfig,
+    resolver: Arc<DnsResolver>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let listener = std::net::TcpListener::bind(&api.listen)?;
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+
+    if api.tokens.iter().all(|token| token.is_empty()) {
+        warn!("HTTP API has no tokens configured; every /api request will be refused.");
+    }
+    info!("HTTP API listening on {}", api.listen);
+
+    let state = Arc::new(ApiState {
+        resolver,
+        edit_lock: Mutex::new(()),
+    });
+    tokio::spawn(async move {
+        let server =
+            axum::serve(listener, router(state)).with_graceful_shutdown(shutdown.cancelled_owned());
+        if let Err(e) = server.await {
+            error!("HTTP API server failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn router(state: Shared) -> Router {
+    Router::new()
+        .route("/api/zones", get(list_zones))
+        .route("/api/zones/{zone}", get(get_zone))
+        .route(
+            "/api/zones/{zone}/records/{name}/{rtype}",
+            get(get_records)
+                .put(put_records)
+                .post(add_records)
+                .delete(delete_records),
+        )
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+/// Rejects requests without one of the configured bearer tokens.
+async fn authorize(State(state): State<Shared>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let config = state.resolver.config();
+    let allowed = presented.is_some_and(|presented| {
+        config
+            .api
+            .tokens
+            .iter()
+            .filter(|token| !token.is_empty())
+            .any(|token| constant_time_eq(token.as_bytes(), presented.as_bytes()))
+    });
+    if !allowed {
+        let error = ApiError(
+            StatusCode::UNAUTHORIZED,
+            "missing or invalid bearer token".to_string(),
+        );
+        return ([(header::WWW_AUTHENTICATE, "Bearer")], error).into_response();
+    }
+    next.run(request).await
+}
+
+/// Compares tokens without leaking how many leading bytes matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+struct ZoneSummary {
+    name: String,
+    serial: Option<u32>,
+    records: Option<usize>,
+    file: PathBuf,
+    error: Option<String>,
+}
+
+async fn list_zones(State(state): State<Shared>) -> Json<Vec<ZoneSummary>> {
+    let config = state.resolver.config();
+    let mut zones: Vec<ZoneSummary> = config
+        .zone_files
+        .iter()
+        .map(|(name, file)| {
+            let zone = config.zones.get(name);
+            ZoneSummary {
+                name: name.clone(),
+                serial: zone.and_then(|zone| zone.serial()),
+                records: zone.map(|zone| zone.record_count),
+                file: file.clone(),
+                error: config.zone_errors.get(name).cloned(),
+            }
+        })
+        .collect();
+    zones.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(zones)
+}
+
+/// The zone file as JSON, with the serial currently being served.
+async fn get_zone(
+    State(state): State<Shared>,
+    extract::Path(zone): extract::Path<String>,
+) -> ApiResult<Json<ZoneConfig>> {
+    let zone = normalize_zone(&zone);
+    let (path, _) = zone_file(&state.resolver, &zone)?;
+    let text = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(ApiError::internal)?;
+    let mut parsed: ZoneConfig = toml::from_str(&text).map_err(ApiError::internal)?;
+    let serial = state
+        .resolver
+        .config()
+        .zones
+        .get(&zone)
+        .and_then(|compiled| compiled.serial());
+    if let (Some(soa), Some(serial)) = (&mut parsed.soa, serial) {
+        soa.serial = serial;
+    }
+    Ok(Json(parsed))
+}
+
+#[derive(Deserialize)]
+struct RecordQuery {
+    country: Option<String>,
+    asn: Option<String>,
+}
+
+type RecordPath = extract::Path<(String, String, String)>;
+
+async fn get_records(
+    State(state): State<Shared>,
+    extract::Path((zone, name, rtype)): RecordPath,
+    Query(query): Query<RecordQuery>,
+) -> ApiResult<Json<Value>> {
+    let zone = normalize_zone(&zone);
+    let target = Target::parse(
+        &name,
+        &rtype,
+        query.country.as_deref(),
+        query.asn.as_deref(),
+        &state.resolver.config().geo_groups,
+    )?;
+    let (path, _) = zone_file(&state.resolver, &zone)?;
+    let text = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(ApiError::internal)?;
+    let parsed: ZoneConfig = toml::from_str(&text).map_err(ApiError::internal)?;
+    let target = target.resolve(&parsed);
+    let values = target.values(&parsed);
+    if values.as_array().is_none_or(|values| values.is_empty()) {
+        return Err(ApiError::not_found(format!("no {}", target)));
+    }
+    Ok(Json(values))
+}
+
+/// Creates or replaces a record set with the JSON array in the body.
+async fn put_records(
+    State(state): State<Shared>,
+    extract::Path((zone, name, rtype)): RecordPath,
+    Query(query): Query<RecordQuery>,
+    Json(body): Json<Value>,
+) -> ApiResult<Json<Value>> {
+    let target = Target::parse(
+        &name,
+        &rtype,
+        query.country.as_deref(),
+        query.asn.as_deref(),
+        &state.resolver.config().geo_groups,
+    )?;
+    edit(&state, &normalize_zone(&zone), &target, Edit::Replace(body)).await
+}
+
+/// Adds the values in the body to a record set, skipping ones already present.
+async fn add_records(
+    State(state): State<Shared>,
+    extract::Path((zone, name, rtype)): RecordPath,
+    Query(query): Query<RecordQuery>,
+    Json(body): Json<Value>,
+) -> ApiResult<Json<Value>> {
+    let target = Target::parse(
+        &name,
+        &rtype,
+        query.country.as_deref(),
+        query.asn.as_deref(),
+        &state.resolver.config().geo_groups,
+    )?;
+    edit(&state, &normalize_zone(&zone), &target, Edit::Append(body)).await
+}
+
+async fn delete_records(
+    State(state): State<Shared>,
+    extract::Path((zone, name, rtype)): RecordPath,
+    Query(query): Query<RecordQuery>,
+) -> ApiResult<Json<Value>> {
+    let target = Target::parse(
+        &name,
+        &rtype,
+        query.country.as_deref(),
+        query.asn.as_deref(),
+        &state.resolver.config().geo_groups,
+    )?;
+    edit(&state, &normalize_zone(&zone), &target, Edit::Delete).await
+}
+
+enum Edit {
+    Replace(Value),
+    Append(Value),
+    Delete,
+}
+
+/// Applies one change to a zone file, validates the result, writes it back
+/// and swaps the recompiled zone in with a bumped serial.
+async fn edit(state: &ApiState, zone: &str, target: &Target, edit: Edit) -> ApiResult<Json<Value>> {
+    let _guard = state.edit_lock.lock().await;
+    let (path, default_ttl) = zone_file(&state.resolver, zone)?;
+    let geo_groups = state.resolver.config().geo_groups.clone();
+
+    let text = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(ApiError::internal)?;
+    let current: ZoneConfig = toml::from_str(&text).map_err(ApiError::internal)?;
+    let mut doc: DocumentMut = text.parse().map_err(ApiError::internal)?;
+    let target = &target.clone().resolve(&current);
+
+    let existing = target.values(&current);
+    match edit {
+        Edit::Replace(values) => target.set(&mut doc, target.kind.to_toml(values)?)?,
+        Edit::Append(values) => {
+            let mut merged = existing.as_array().cloned().unwrap_or_default();
+            let Value::Array(values) = values else {
+                return Err(ApiError::bad_request("expected a JSON array of values"));
+            };
+            for value in values {
+                if !merged.contains(&value) {
+                    merged.push(value);
+                }
+            }
+            target.set(&mut doc, target.kind.to_toml(Value::Array(merged))?)?;
+        }
+        Edit::Delete => {
+            if existing.as_array().is_none_or(|values| values.is_empty()) {
+                return Err(ApiError::not_found(format!("no {}", target)));
+            }
+            target.remove(&mut doc);
+        }
+    }
+
+    // Nothing is written unless the new zone would load.
+    let new_text = doc.to_string();
+    let updated: ZoneConfig = toml::from_str(&new_text).map_err(ApiError::bad_request)?;
+    CompiledZone::compile(zone, &updated, default_ttl, &geo_groups)
+        .map_err(ApiError::bad_request)?;
+
+    write_atomically(&path, &new_text)
+        .await
+        .map_err(|e| ApiError::internal(format!("failed to write {:?}: {}", path, e)))?;
+    let report = reload::reload_zone(&state.resolver, zone)
+        .await
+        .map_err(ApiError::internal)?;
+    if let Some(failure) = report.failed.first() {
+        return Err(ApiError::internal(&failure.error));
+    }
+    report.log();
+    info!("HTTP API updated {} in zone '{}'", target, zone);
+
+    let serial = state
+        .resolver
+        .config()
+        .zones
+        .get(zone)
+        .and_then(|compiled| compiled.serial());
+    Ok(Json(json!({
+        "zone": zone,
+        "serial": serial,
+        "values": target.values(&updated),
+    })))
+}
+
+fn normalize_zone(zone: &str) -> String {
+    zone.trim_end_matches('.').to_lowercase()
+}
+
+/// The file and default TTL of a configured zone.
+fn zone_file(resolver: &DnsResolver, zone: &str) -> ApiResult<(PathBuf, u32)> {
+    let config = resolver.config();
+    match config.zone_files.get(zone) {
+        Some(path) => Ok((path.clone(), config.default_ttl)),
+        None => Err(ApiError::not_found(format!(
+            "zone '{}' is not configured",
+            zone
+        ))),
+    }
+}
+
+/// Replaces `path` in one step, so the file watcher and readers never see a
+/// half-written zone.
+async fn write_atomically(path: &Path, content: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    tokio::fs::write(&tmp, content).await?;
+    if let Ok(metadata) = tokio::fs::metadata(path).await {
+        tokio::fs::set_permissions(&tmp, metadata.permissions()).await?;
+    }
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[derive(Clone, Copy, Debug)]
+enum RecordKind {
+    A,
+    Aaaa,
+    Cname,
+    Mx,
+    Txt,
+    Ns,
+}
+
+impl RecordKind {
+    fn parse(rtype: &str) -> ApiResult<Self> {
+        match rtype.to_lowercase().as_str() {
+            "a" => Ok(Self::A),
+            "aaaa" => Ok(Self::Aaaa),
+            "cname" => Ok(Self::Cname),
+            "mx" => Ok(Self::Mx),
+            "txt" => Ok(Self::Txt),
+            "ns" => Ok(Self::Ns),
+            _ => Err(ApiError::bad_request(format!(
+                "unsupported record type '{}'",
+                rtype
+            ))),
+        }
+    }
+
+    /// The key of this record type in a zone file table.
+    fn key(self) -> &'static str {
+        match self {
+            Self::A => "a",
+            Self::Aaaa => "aaaa",
+            Self::Cname => "cname",
+            Self::Mx => "mx",
+            Self::Txt => "txt",
+            Self::Ns => "ns",
+        }
+    }
+
+    fn values(self, set: &RecordSet) -> Value {
+        match self {
+            Self::A => json!(set.a),
+            Self::Aaaa => json!(set.aaaa),
+            Self::Cname => json!(set.cname),
+            Self::Mx => json!(set.mx),
+            Self::Txt => json!(set.txt),
+            Self::Ns => json!(set.ns),
+        }
+    }
+
+    /// Converts a JSON array from a request into the zone file representation.
+    fn to_toml(self, values: Value) -> ApiResult<Array> {
+        let mut array = Array::new();
+        match self {
+            Self::Mx => {
+                let records: Vec<MXRecord> = serde_json::from_value(values).map_err(|e| {
+                    ApiError::bad_request(format!(
+                        "expected an array of {{\"preference\", \"exchange\"}} objects: {}",
+                        e
+                    ))
+                })?;
+                for mx in records {
+                    let mut table = InlineTable::new();
+                    table.insert("preference", i64::from(mx.preference).into());
+                    table.insert("exchange", mx.exchange.into());
+                    array.push(table);
+                }
+            }
+            _ => {
+                let records: Vec<String> = serde_json::from_value(values).map_err(|e| {
+                    ApiError::bad_request(format!("expected an array of strings: {}", e))
+                })?;
+                for record in records {
+                    array.push(record);
+                }
+            }
+        }
+        if array.is_empty() {
+            return Err(ApiError::bad_request(
+                "no values given; use DELETE to remove a record set",
+            ));
+        }
+        Ok(array)
+    }
+}
+
+/// One record set in a zone file: an owner, an optional GeoIP location key or
+/// ASN, and a type.
+#[derive(Clone)]
+struct Target {
+    /// `None` for the zone apex.
+    label: Option<String>,
+    country: Option<String>,
+    /// E.g. `AS4134`.
+    asn: Option<String>,
+    kind: RecordKind,
+}
+
+impl Target {
+    /// `country` is a location key, the name of a `[geo_groups]` group, or
+    /// `_unknown` or `_unavailable`.
+    fn parse(
+        name: &str,
+        rtype: &str,
+        country: Option<&str>,
+        asn: Option<&str>,
+        groups: &GeoGroups,
+    ) -> ApiResult<Self> {
+        let kind = RecordKind::parse(rtype)?;
+        let label = match name.to_lowercase().trim_end_matches('.') {
+            "@" => None,
+            "" => return Err(ApiError::bad_request("empty record name")),
+            label if RESERVED_LABELS.contains(&label) => {
+                return Err(ApiError::bad_request(format!(
+                    "'{}' is reserved and cannot be used as a record name",
+                    label
+                )));
+            }
+            label => Some(label.to_string()),
+        };
+        let country = match country.filter(|c| !c.is_empty()) {
+            Some(key @ (UNKNOWN_KEY | UNAVAILABLE_KEY)) => Some(key.to_string()),
+            Some(group) if groups.get(group).is_some() => Some(group.trim().to_lowercase()),
+            Some(key) => Some(
+                key.parse::<LocationKey>()
+                    .map_err(ApiError::bad_request)?
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let asn = match asn.filter(|a| !a.is_empty()) {
+            Some(key) => Some(format!(
+                "AS{}",
+                parse_asn(key).map_err(ApiError::bad_request)?
+            )),
+            None => None,
+        };
+        if country.is_some() && asn.is_some() {
+            return Err(ApiError::bad_request(
+                "give either country or asn, not both",
+            ));
+        }
+        if (country.is_some() || asn.is_some()) && matches!(kind, RecordKind::Ns) {
+            return Err(ApiError::bad_request(
+                "NS records cannot be overridden per country or ASN",
+            ));
+        }
+        Ok(Self {
+            label,
+            country,
+            asn,
+            kind,
+        })
+    }
+
+    /// The same record set, spelled the way the zone file already spells its
+    /// owner, country and ASN, so lookups find them and edits land on the existing
+    /// tables instead of adding duplicates. Owners compare case-insensitively,
+    /// as in `CompiledZone::compile`; location keys as `LocationKey` spells them and ASNs by number.
+    fn resolve(self, zone: &ZoneConfig) -> Self {
+        let label = self.label.map(|label| {
+            existing_key(zone.subdomains.keys(), &label, str::to_lowercase).unwrap_or(label)
+        });
+        let countries = match &label {
+            None => Some(&zone.country),
+            Some(label) => zone.subdomains.get(label).map(|sub| &sub.country),
+        };
+        let country = self.country.map(|code| {
+            countries
+                .and_then(|countries| existing_key(countries.keys(), &code, location_key))
+                .unwrap_or(code)
+        });
+        let asns = match &label {
+            None => Some(&zone.asn),
+            Some(label) => zone.subdomains.get(label).map(|sub| &sub.asn),
+        };
+        let asn = self.asn.map(|asn| {
+            asns.and_then(|asns| existing_key(asns.keys(), &asn, asn_key))
+                .unwrap_or(asn)
+        });
+        Self {
+            label,
+            country,
+            asn,
+            kind: self.kind,
+        }
+    }
+
+    /// The GeoIP table and key of this record set, if it is an override.
+    fn geo_key(&self) -> Option<(&'static str, &str)> {
+        match (&self.country, &self.asn) {
+            (Some(code), _) => Some(("country", code.as_str())),
+            (None, Some(asn)) => Some(("asn", asn.as_str())),
+            (None, None) => None,
+        }
+    }
+
+    /// Keys of the table holding this record set, from the document root.
+    fn table_path(&self) -> Vec<&str> {
+        let mut path = Vec::new();
+        match (&self.label, self.geo_key()) {
+            (None, None) => path.push("apex"),
+            (None, Some((table, key))) => path.extend([table, key]),
+            (Some(label), None) => path.push(label.as_str()),
+            (Some(label), Some((table, key))) => path.extend([label.as_str(), table, key]),
+        }
+        path
+    }
+
+    fn record_set<'a>(&self, zone: &'a ZoneConfig) -> Option<&'a RecordSet> {
+        let (records, country, asn) = match &self.label {
+            None => (&zone.apex, &zone.country, &zone.asn),
+            Some(label) => {
+                let sub = zone.subdomains.get(label)?;
+                (&sub.records, &sub.country, &sub.asn)
+            }
+        };
+        match self.geo_key() {
+            None => Some(records),
+            Some(("asn", key)) => asn.get(key),
+            Some((_, key)) => country.get(key),
+        }
+    }
+
+    /// The current values as a JSON array; empty when the set does not exist.
+    fn values(&self, zone: &ZoneConfig) -> Value {
+        match self.record_set(zone) {
+            Some(set) => self.kind.values(set),
+            None => json!([]),
+        }
+    }
+
+    fn set(&self, doc: &mut DocumentMut, values: Array) -> ApiResult<()> {
+        let mut item = doc.as_item_mut();
+        for key in self.table_path() {
+            if item.get(key).is_none() {
+                // Follow the style of the siblings: a table whose entries are
+                // inline tables, like `[country]`, gets another inline one.
+                let siblings_inline = item
+                    .as_table()
+                    .is_some_and(|table| table.iter().any(|(_, v)| v.is_inline_table()));
+                let child = if item.is_table() && !siblings_inline {
+                    let mut table = Table::new();
+                    table.set_implicit(true);
+                    Item::Table(table)
+                } else {
+                    Item::Value(InlineTable::new().into())
+                };
+                if let Some(table) = item.as_table_like_mut() {
+                    table.insert(key, child);
+                }
+            }
+            item = match item.get_mut(key) {
+                Some(child) if child.is_table_like() => child,
+                _ => {
+                    return Err(ApiError::internal(format!(
+                        "'{}' in the zone file is not a table",
+                        key
+                    )));
+                }
+            };
+        }
+        if let Some(table) = item.as_table_like_mut() {
+            table.insert(self.kind.key(), Item::Value(values.into()));
+        }
+        if let Some(table) = item.as_inline_table_mut() {
+            table.fmt();
+        }
+        Ok(())
+    }
+
+    /// Removes the record set, then any tables it leaves empty.
+    fn remove(&self, doc: &mut DocumentMut) {
+        let path = self.table_path();
+        if let Some(table) = table_like_mut(doc, &path) {
+            table.remove(self.kind.key());
+        }
+        for depth in (1..=path.len()).rev() {
+            let (parent, key) = (&path[..depth - 1], path[depth - 1]);
+            let Some(parent) = table_like_mut(doc, parent) else {
+                break;
+            };
+            let is_empty = parent
+                .get(key)
+                .and_then(|item| item.as_table_like())
+                .is_some_and(|table| table.is_empty());
+            if !is_empty {
+                break;
+            }
+            parent.remove(key);
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} records of '{}'",
+            self.kind.key().to_uppercase(),
+            self.label.as_deref().unwrap_or("@")
+        )?;
+        if let Some(code) = &self.country {
+            write!(f, " (country {})", code)?;
+        }
+        if let Some(asn) = &self.asn {
+            write!(f, " ({})", asn)?;
+        }
+        Ok(())
+    }
+}
+
+/// The key among `keys` that means the same as `wanted` once both are normalized.
+fn existing_key<'a>(
+    mut keys: impl Iterator<Item = &'a String>,
+    wanted: &str,
+    normalize: fn(&str) -> String,
+) -> Option<String> {
+    let wanted = normalize(wanted);
+    keys.find(|key| normalize(key) == wanted).cloned()
+}
+
+/// `key` as a parsed location key writes it, or unchanged if it is not one.
+fn location_key(key: &str) -> String {
+    key.parse::<LocationKey>()
+        .map(|key| key.to_string())
+        .unwrap_or_else(|_| key.to_string())
+}
+
+/// `key` as `AS` and its number, or unchanged if it is not an ASN.
+fn asn_key(key: &str) -> String {
+    parse_asn(key)
+        .map(|asn| format!("AS{}", asn))
+        .unwrap_or_else(|_| key.to_string())
+}
+
+/// Walks to an existing table without creating anything on the way.
+fn table_like_mut<'a>(
+    doc: &'a mut DocumentMut,
+    path: &[&str],
+) -> Option<&'a mut dyn toml_edit::TableLike> {
+    let mut table: &mut dyn toml_edit::TableLike = doc.as_table_mut();
+    for key in path {
+        table = table.get_mut(key)?.as_table_like_mut()?;
+    }
+    Some(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, GeoIpCacheConfig};
+    use crate::geo_provider::NoopProvider;
+    use crate::geoip::GeoIpClient;
+    use std::fs;
+
+    const ZONE: &str = r#"
+[soa]
+mname = "ns1.example.com."
+rname = "admin.example.com."
+
+[apex]
+a = ["192.0.2.1"]
+
+[WWW]
+a = ["192.0.2.2"]
+
+[WWW.country]
+us = { a = ["192.0.2.3"] }
+"City:us/Los Angeles" = { a = ["192.0.2.4"] }
+
+[WWW.asn]
+as64500 = { a = ["192.0.2.6"] }
+"#;
+
+    /// API state serving `ZONE` as example.com from a fresh directory.
+    fn state(name: &str) -> (ApiState, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("lazy-dns-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("config.toml"),
+            "default_ttl = 5\n[zones]\n\"example.com\" = \"example.com.toml\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("example.com.toml"), ZONE).unwrap();
+
+        let config = AppConfig::load(&dir).unwrap();
+        let resolver = DnsResolver::new(
+            Arc::new(config),
+            Arc::new(GeoIpClient::new(
+                Arc::new(NoopProvider),
+                &GeoIpCacheConfig::default(),
+            )),
+            None,
+            None,
+        );
+        let state = ApiState {
+            resolver: Arc::new(resolver),
+            edit_lock: Mutex::new(()),
+        };
+        (state, dir)
+    }
+
+    fn target(name: &str, rtype: &str, country: Option<&str>) -> Target {
+        Target::parse(name, rtype, country, None, &GeoGroups::default()).unwrap()
+    }
+
+    #[test]
+    fn resolves_names_to_the_spelling_in_the_zone_file() {
+        let zone: ZoneConfig = toml::from_str(ZONE).unwrap();
+
+        let resolved = target("www", "a", None).resolve(&zone);
+        assert_eq!(resolved.table_path(), ["WWW"]);
+        assert_eq!(resolved.values(&zone), json!(["192.0.2.2"]));
+
+        let resolved = target("Www.", "a", Some("US")).resolve(&zone);
+        assert_eq!(resolved.table_path(), ["WWW", "country", "us"]);
+        assert_eq!(resolved.values(&zone), json!(["192.0.2.3"]));
+
+        let resolved = target("www", "a", Some("city:US/Los Angeles")).resolve(&zone);
+        assert_eq!(
+            resolved.table_path(),
+            ["WWW", "country", "City:us/Los Angeles"]
+        );
+        assert_eq!(resolved.values(&zone), json!(["192.0.2.4"]));
+
+        let resolved = Target::parse("www", "a", None, Some("AS64500"), &GeoGroups::default())
+            .unwrap()
+            .resolve(&zone);
+        assert_eq!(resolved.table_path(), ["WWW", "asn", "as64500"]);
+        assert_eq!(resolved.values(&zone), json!(["192.0.2.6"]));
+
+        // Names not in the file keep their normalized spelling.
+        let resolved = target("mail", "a", Some("de")).resolve(&zone);
+        assert_eq!(resolved.table_path(), ["mail", "country", "DE"]);
+        assert_eq!(resolved.values(&zone), json!([]));
+    }
+
+    #[test]
+    fn rejects_zone_file_keys_as_record_names() {
+        let groups = GeoGroups::default();
+        for name in [
+            "unknown_policy",
+            "Unavailable_Policy.",
+            "asn_precedence",
+            "soa",
+        ] {
+            match Target::parse(name, "a", None, None, &groups) {
+                Err(ApiError(status, message)) => {
+                    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", name);
+                    assert!(message.contains("reserved"), "{}: {}", name, message);
+                }
+                Ok(_) => panic!("{} was accepted", name),
+            }
+        }
+        assert!(Target::parse("policy", "a", None, None, &groups).is_ok());
+    }
+
+    #[tokio::test]
+    async fn edits_mixed_case_entries_in_place() {
+        let (state, dir) = state("api-mixed-case");
+        let path = dir.join("example.com.toml");
+
+        let result = edit(
+            &state,
+            "example.com",
+            &target("www", "a", None),
+            Edit::Replace(json!(["192.0.2.4"])),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.0["values"], json!(["192.0.2.4"]));
+
+        let result = edit(
+            &state,
+            "example.com",
+            &target("www", "a", Some("US")),
+            Edit::Append(json!(["192.0.2.5"])),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.0["values"], json!(["192.0.2.3", "192.0.2.5"]));
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(!text.contains("[www]"), "{}", text);
+        assert!(!text.contains("US ="), "{}", text);
+        assert!(
+            text.contains(r#"us = { a = ["192.0.2.3", "192.0.2.5"] }"#),
+            "{}",
+            text
+        );
+
+        let config = state.resolver.config();
+        let node = config.zones["example.com"].node(Some("www")).unwrap();
+        assert_eq!(node.default.a[0].data().to_string(), "192.0.2.4");
+        assert_eq!(node.country["US"].a.len(), 2);
+
+        let result = edit(
+            &state,
+            "example.com",
+            &target("WWW", "a", Some("us")),
+            Edit::Delete,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.0["values"], json!([]));
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(!text.contains("us ="), "{}", text);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
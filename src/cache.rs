@@ -0,0 +1,457 @@
+/* src/cache.rs */
+
+use crate::config::CacheConfig;
+use crate::query_log::LoggedAnswer;
+use hickory_proto::op::{Edns, Query, ResponseCode};
+use hickory_proto::rr::{DNSClass, RecordType};
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const SHARD_COUNT: usize = 16;
+
+/// Rough per-entry bookkeeping overhead (key, LRU node, Arc), in bytes.
+const ENTRY_OVERHEAD: usize = 96;
+
+/// Offset of the question section in a DNS message.
+const QUESTION_OFFSET: usize = 12;
+
+/// Everything that can change the bytes of an answer, except the message ID
+/// and the letter case of the question name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    qname: String,
+    qtype: RecordType,
+    qclass: DNSClass,
+    /// `None` without EDNS, otherwise the DO bit.
+    edns_do: Option<bool>,
+    geo_bucket: Option<String>,
+}
+
+impl CacheKey {
+    pub fn new(query: &Query, edns: Option<&Edns>, geo_bucket: Option<&str>) -> Self {
+        Self {
+            qname: query.name().to_ascii().to_ascii_lowercase(),
+            qtype: query.query_type(),
+            qclass: query.query_class(),
+            edns_do: edns.map(|e| e.flags().dnssec_ok),
+            geo_bucket: geo_bucket.map(str::to_string),
+        }
+    }
+
+    fn shard(&self) -> usize {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish() as usize % SHARD_COUNT
+    }
+}
+
+/// An encoded response, plus the text that is logged when it is served.
+pub struct CachedResponse {
+    pub bytes: Vec<u8>,
+    /// The zone the name is in, for the metrics of cache hits.
+    pub zone: Option<String>,
+    pub summary: String,
+    pub rcode: ResponseCode,
+    pub answers: Arc<[LoggedAnswer]>,
+}
+
+impl CachedResponse {
+    fn cost(&self, key: &CacheKey) -> usize {
+        let answers: usize = self
+            .answers
+            .iter()
+            .map(|answer| answer.rtype.len() + answer.data.len() + size_of::<LoggedAnswer>())
+            .sum();
+        self.bytes.len() + self.summary.len() + key.qname.len() + answers + ENTRY_OVERHEAD
+    }
+}
+
+struct Shard {
+    entries: LruCache<CacheKey, Arc<CachedResponse>>,
+    bytes: usize,
+}
+
+/// Sharded LRU of wire-format responses, bounded by entry count and bytes.
+pub struct ResponseCache {
+    enabled: bool,
+    max_entries: usize,
+    max_bytes: usize,
+    shards: Vec<Mutex<Shard>>,
+    /// Bumped by `clear`; inserts computed from an older generation are ignored.
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Point-in-time cache figures for the control socket.
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        let shards = (0..SHARD_COUNT)
+            .map(|_| {
+                Mutex::new(Shard {
+                    entries: LruCache::unbounded(),
+                    bytes: 0,
+                })
+            })
+            .collect();
+        Self {
+            enabled: config.enabled,
+            max_entries: (config.max_entries / SHARD_COUNT).max(1),
+            max_bytes: (config.max_bytes / SHARD_COUNT).max(1),
+            shards,
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<CachedResponse>> {
+        if !self.enabled {
+            return None;
+        }
+        let found = self.shards[key.shard()].lock().entries.get(key).cloned();
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Read this before routing a query and pass it to `insert`, so answers
+    /// built from data older than the last `clear` are never stored.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn insert(&self, key: CacheKey, response: CachedResponse, generation: u64) {
+        if !self.enabled {
+            return;
+        }
+        let cost = response.cost(&key);
+        if cost > self.max_bytes {
+            return;
+        }
+
+        let mut shard = self.shards[key.shard()].lock();
+        if self.generation() != generation {
+            return;
+        }
+        if let Some(old) = shard.entries.pop(&key) {
+            shard.bytes -= old.cost(&key);
+        }
+        while shard.entries.len() >= self.max_entries || shard.bytes + cost > self.max_bytes {
+            match shard.entries.pop_lru() {
+                Some((old_key, old)) => shard.bytes -= old.cost(&old_key),
+                None => break,
+            }
+        }
+        shard.bytes += cost;
+        shard.entries.put(key, Arc::new(response));
+    }
+
+    /// Drops every entry, e.g. after the zone data changed, and returns how
+    /// many there were.
+    pub fn clear(&self) -> usize {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        let mut removed = 0;
+        for shard in &self.shards {
+            let mut shard = shard.lock();
+            removed += shard.entries.len();
+            shard.entries.clear();
+            shard.bytes = 0;
+        }
+        removed
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (entries, bytes) = self.shards.iter().fold((0, 0), |(entries, bytes), shard| {
+            let shard = shard.lock();
+            (entries + shard.entries.len(), bytes + shard.bytes)
+        });
+        CacheStats {
+            entries,
+            bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Rewrites a cached response so it answers `request`: copies the message ID,
+/// the RD and CD flags, and the exact letter case of the question name.
+pub fn patch_response(cached: &[u8], request: &[u8]) -> Vec<u8> {
+    let mut response = cached.to_vec();
+    if response.len() < QUESTION_OFFSET || request.len() < QUESTION_OFFSET {
+        return response;
+    }
+
+    response[..2].copy_from_slice(&request[..2]);
+    // RD is the lowest bit of byte 2, CD is bit 4 of byte 3.
+    response[2] = (response[2] & !0x01) | (request[2] & 0x01);
+    response[3] = (response[3] & !0x10) | (request[3] & 0x10);
+
+    if let Some(end) = question_name_end(request) {
+        let name = &request[QUESTION_OFFSET..end];
+        if let Some(target) = response.get_mut(QUESTION_OFFSET..end)
+            && target.eq_ignore_ascii_case(name)
+        {
+            target.copy_from_slice(name);
+        }
+    }
+    response
+}
+
+/// Finds the end of the (uncompressed) first question name in a message.
+fn question_name_end(message: &[u8]) -> Option<usize> {
+    let mut pos = QUESTION_OFFSET;
+    loop {
+        let len = *message.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        pos += len + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{Message, MessageType};
+    use hickory_proto::rr::rdata::A;
+    use hickory_proto::rr::{Name, RData, Record};
+    use std::str::FromStr;
+
+    fn query(name: &str) -> Query {
+        Query::query(Name::from_str(name).unwrap(), RecordType::A)
+    }
+
+    fn message(id: u16, name: &str, rd: bool, cd: bool) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(id)
+            .set_recursion_desired(rd)
+            .set_checking_disabled(cd)
+            .add_query(query(name));
+        message
+    }
+
+    /// `www.example.com.` in wire format, in mixed case. Encoding a `Name`
+    /// always writes lower case, so requests get this patched in instead.
+    const MIXED_CASE: &[u8] = b"\x03WwW\x07ExAmple\x03COM\x00";
+
+    fn mixed_case(request: Message) -> Vec<u8> {
+        let mut bytes = request.to_vec().unwrap();
+        bytes[QUESTION_OFFSET..QUESTION_OFFSET + MIXED_CASE.len()].copy_from_slice(MIXED_CASE);
+        bytes
+    }
+
+    fn cached_response(name: &str) -> Vec<u8> {
+        let mut response = message(0, name, false, false);
+        response
+            .set_message_type(MessageType::Response)
+            .set_authoritative(true)
+            .add_answer(Record::from_rdata(
+                Name::from_str(name).unwrap(),
+                300,
+                RData::A(A::new(192, 0, 2, 1)),
+            ));
+        response.to_vec().unwrap()
+    }
+
+    #[test]
+    fn patch_copies_id_flags_and_question_case() {
+        let cached = cached_response("www.example.com.");
+        let request = mixed_case(message(0xBEEF, "www.example.com.", true, true));
+
+        let patched_bytes = patch_response(&cached, &request);
+        let patched = Message::from_vec(&patched_bytes).unwrap();
+        assert_eq!(patched.id(), 0xBEEF);
+        assert!(patched.recursion_desired());
+        assert!(patched.checking_disabled());
+        assert!(patched.authoritative());
+        assert_eq!(patched.message_type(), MessageType::Response);
+        assert_eq!(
+            &patched_bytes[QUESTION_OFFSET..QUESTION_OFFSET + MIXED_CASE.len()],
+            MIXED_CASE
+        );
+        assert_eq!(patched.answers().len(), 1);
+        assert_eq!(patched.answers()[0].data(), &RData::A(A::new(192, 0, 2, 1)));
+
+        // Flags and case are cleared again for a plain request.
+        let request = message(7, "www.example.com.", false, false)
+            .to_vec()
+            .unwrap();
+        let patched_bytes = patch_response(&patched_bytes, &request);
+        assert_eq!(patched_bytes[QUESTION_OFFSET..], cached[QUESTION_OFFSET..]);
+        let patched = Message::from_vec(&patched_bytes).unwrap();
+        assert_eq!(patched.id(), 7);
+        assert!(!patched.recursion_desired());
+        assert!(!patched.checking_disabled());
+    }
+
+    #[test]
+    fn patch_leaves_other_names_and_short_messages_alone() {
+        let cached = cached_response("www.example.com.");
+        let mut request = mixed_case(message(1, "www.example.com.", false, false));
+        request[QUESTION_OFFSET + 1] = b'X';
+        let patched = patch_response(&cached, &request);
+        assert_eq!(Message::from_vec(&patched).unwrap().id(), 1);
+        assert_eq!(patched[QUESTION_OFFSET..], cached[QUESTION_OFFSET..]);
+
+        assert_eq!(patch_response(&cached, &request[..11]), cached);
+        assert_eq!(patch_response(&cached[..5], &request), &cached[..5]);
+    }
+
+    #[test]
+    fn question_name_end_stops_at_compression() {
+        let request = message(1, "a.bc.", false, false).to_vec().unwrap();
+        assert_eq!(question_name_end(&request), Some(QUESTION_OFFSET + 6));
+        let mut compressed = request[..QUESTION_OFFSET].to_vec();
+        compressed.extend_from_slice(&[0xC0, 0x0C]);
+        assert_eq!(question_name_end(&compressed), None);
+        assert_eq!(question_name_end(&request[..QUESTION_OFFSET + 3]), None);
+    }
+
+    fn cache(max_entries: usize, max_bytes: usize) -> ResponseCache {
+        ResponseCache::new(&CacheConfig {
+            enabled: true,
+            max_entries,
+            max_bytes,
+        })
+    }
+
+    fn response(bytes: usize) -> CachedResponse {
+        CachedResponse {
+            bytes: vec![0; bytes],
+            zone: None,
+            summary: String::new(),
+            rcode: ResponseCode::NoError,
+            answers: Arc::from([]),
+        }
+    }
+
+    fn entries(cache: &ResponseCache) -> usize {
+        cache
+            .shards
+            .iter()
+            .map(|shard| shard.lock().entries.len())
+            .sum()
+    }
+
+    fn bytes(cache: &ResponseCache) -> usize {
+        cache.shards.iter().map(|shard| shard.lock().bytes).sum()
+    }
+
+    /// Keys that all land in the same shard, so they compete for its room.
+    fn same_shard_keys(count: usize) -> Vec<CacheKey> {
+        let mut keys: Vec<CacheKey> = Vec::new();
+        for i in 0.. {
+            let key = CacheKey::new(&query(&format!("{}.example.com.", i)), None, None);
+            if keys
+                .first()
+                .is_none_or(|first| first.shard() == key.shard())
+            {
+                keys.push(key);
+            }
+            if keys.len() == count {
+                break;
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn key_ignores_case_but_not_bucket() {
+        let lower = CacheKey::new(&query("www.example.com."), None, None);
+        let upper = CacheKey::new(&query("WWW.Example.com."), None, None);
+        let bucket = CacheKey::new(&query("www.example.com."), None, Some("US"));
+        assert_eq!(lower, upper);
+        assert_ne!(lower, bucket);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_beyond_max_entries() {
+        // Two entries per shard.
+        let cache = cache(2 * SHARD_COUNT, usize::MAX);
+        let keys = same_shard_keys(3);
+        let generation = cache.generation();
+        cache.insert(keys[0].clone(), response(10), generation);
+        cache.insert(keys[1].clone(), response(10), generation);
+        assert!(cache.get(&keys[0]).is_some());
+        cache.insert(keys[2].clone(), response(10), generation);
+
+        assert!(cache.get(&keys[0]).is_some());
+        assert!(cache.get(&keys[1]).is_none());
+        assert!(cache.get(&keys[2]).is_some());
+        assert_eq!(entries(&cache), 2);
+    }
+
+    #[test]
+    fn evicts_beyond_max_bytes_and_tracks_size() {
+        let keys = same_shard_keys(3);
+        let cost = |key: &CacheKey| response(100).cost(key);
+        // Room for two entries per shard, not three.
+        let cache = cache(100, (cost(&keys[1]) + cost(&keys[2]) + 10) * SHARD_COUNT);
+        let generation = cache.generation();
+        for key in &keys {
+            cache.insert(key.clone(), response(100), generation);
+        }
+        assert!(cache.get(&keys[0]).is_none());
+        assert!(cache.get(&keys[1]).is_some());
+        assert!(cache.get(&keys[2]).is_some());
+        assert_eq!(bytes(&cache), cost(&keys[1]) + cost(&keys[2]));
+
+        // Replacing an entry does not count it twice.
+        cache.insert(keys[2].clone(), response(100), generation);
+        assert_eq!(bytes(&cache), cost(&keys[1]) + cost(&keys[2]));
+
+        // An entry larger than a whole shard is never stored.
+        cache.insert(keys[0].clone(), response(3 * cost(&keys[0])), generation);
+        assert!(cache.get(&keys[0]).is_none());
+        assert_eq!(entries(&cache), 2);
+    }
+
+    #[test]
+    fn clear_drops_entries_and_stale_inserts() {
+        let cache = cache(100, usize::MAX);
+        let key = CacheKey::new(&query("www.example.com."), None, None);
+        let generation = cache.generation();
+        cache.insert(key.clone(), response(10), generation);
+        cache.clear();
+        assert_eq!(entries(&cache), 0);
+        assert_eq!(bytes(&cache), 0);
+
+        cache.insert(key.clone(), response(10), generation);
+        assert!(cache.get(&key).is_none());
+        cache.insert(key.clone(), response(10), cache.generation());
+        assert!(cache.get(&key).is_some());
+    }
+
+    #[test]
+    fn disabled_cache_stores_nothing() {
+        let cache = ResponseCache::new(&CacheConfig {
+            enabled: false,
+            ..CacheConfig::default()
+        });
+        let key = CacheKey::new(&query("www.example.com."), None, None);
+        cache.insert(key.clone(), response(10), cache.generation());
+        assert!(cache.get(&key).is_none());
+        assert_eq!(entries(&cache), 0);
+    }
+}
@@ -0,0 +1,219 @@
+/* src/cidr.rs */
+
+use crate::privacy;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network such as `203.0.113.0/24`. A bare address is a
+/// network of one; host bits are cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Self {
+        let prefix = prefix.min(max_prefix(addr));
+        Self {
+            addr: privacy::truncate(addr, prefix, prefix),
+            prefix,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid address in '{}'", s))?;
+        let prefix = match prefix {
+            Some(prefix) => match prefix.trim().parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix(addr) => prefix,
+                _ => return Err(format!("invalid prefix length in '{}'", s)),
+            },
+            None => max_prefix(addr),
+        };
+        Ok(Self::new(addr, prefix))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+/// Values keyed by network, found by longest matching prefix.
+pub struct CidrTable<T> {
+    /// Networks grouped by prefix length, longest first.
+    v4: Vec<(u8, HashMap<IpAddr, T>)>,
+    v6: Vec<(u8, HashMap<IpAddr, T>)>,
+    len: usize,
+}
+
+impl<T> Default for CidrTable<T> {
+    fn default() -> Self {
+        Self {
+            v4: Vec::new(),
+            v6: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> CidrTable<T> {
+    /// Adds `network`, returning the value it replaces.
+    pub fn insert(&mut self, network: Cidr, value: T) -> Option<T> {
+        let groups = if network.addr.is_ipv4() {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        };
+        let i = match groups.binary_search_by(|(prefix, _)| network.prefix.cmp(prefix)) {
+            Ok(i) => i,
+            Err(i) => {
+                groups.insert(i, (network.prefix, HashMap::new()));
+                i
+            }
+        };
+        let replaced = groups[i].1.insert(network.addr, value);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        replaced
+    }
+
+    /// The most specific network containing `ip`, with its value. An
+    /// IPv4-mapped IPv6 address matches IPv4 networks.
+    pub fn lookup(&self, ip: IpAddr) -> Option<(Cidr, &T)> {
+        let ip = ip.to_canonical();
+        let groups = if ip.is_ipv4() { &self.v4 } else { &self.v6 };
+        groups.iter().find_map(|(prefix, networks)| {
+            let addr = privacy::truncate(ip, *prefix, *prefix);
+            networks.get(&addr).map(|value| {
+                (
+                    Cidr {
+                        addr,
+                        prefix: *prefix,
+                    },
+                    value,
+                )
+            })
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(networks: &[(&str, &'static str)]) -> CidrTable<&'static str> {
+        let mut table = CidrTable::default();
+        for (network, value) in networks {
+            table.insert(network.parse().unwrap(), *value);
+        }
+        table
+    }
+
+    fn lookup(table: &CidrTable<&'static str>, ip: &str) -> Option<(String, &'static str)> {
+        table
+            .lookup(ip.parse().unwrap())
+            .map(|(network, value)| (network.to_string(), *value))
+    }
+
+    fn found(network: &str, value: &'static str) -> Option<(String, &'static str)> {
+        Some((network.to_string(), value))
+    }
+
+    #[test]
+    fn parses_and_clears_host_bits() {
+        let cidr = |s: &str| s.parse::<Cidr>().map(|c| c.to_string());
+        assert_eq!(cidr("203.0.113.77/24"), Ok("203.0.113.0/24".to_string()));
+        assert_eq!(cidr(" 2001:db8::1 / 32 "), Ok("2001:db8::/32".to_string()));
+        assert_eq!(cidr("192.0.2.1"), Ok("192.0.2.1/32".to_string()));
+        assert_eq!(cidr("2001:db8::1"), Ok("2001:db8::1/128".to_string()));
+        assert_eq!(cidr("0.0.0.0/0"), Ok("0.0.0.0/0".to_string()));
+        assert!(cidr("192.0.2.0/33").is_err());
+        assert!(cidr("2001:db8::/129").is_err());
+        assert!(cidr("192.0.2.0/x").is_err());
+        assert!(cidr("example.com/24").is_err());
+    }
+
+    #[test]
+    fn finds_the_longest_matching_prefix() {
+        let table = table(&[
+            ("10.0.0.0/8", "wide"),
+            ("10.1.0.0/16", "narrow"),
+            ("10.1.2.3", "host"),
+            ("0.0.0.0/0", "any"),
+        ]);
+        assert_eq!(lookup(&table, "10.1.2.3"), found("10.1.2.3/32", "host"));
+        assert_eq!(lookup(&table, "10.1.2.4"), found("10.1.0.0/16", "narrow"));
+        assert_eq!(lookup(&table, "10.2.0.1"), found("10.0.0.0/8", "wide"));
+        assert_eq!(lookup(&table, "192.0.2.1"), found("0.0.0.0/0", "any"));
+    }
+
+    #[test]
+    fn keeps_ipv4_and_ipv6_apart() {
+        let table = table(&[
+            ("192.0.2.0/24", "v4"),
+            ("2001:db8::/32", "v6"),
+            ("2001:db8:1::/48", "v6 narrow"),
+        ]);
+        assert_eq!(lookup(&table, "192.0.2.9"), found("192.0.2.0/24", "v4"));
+        assert_eq!(
+            lookup(&table, "2001:db8:1::9"),
+            found("2001:db8:1::/48", "v6 narrow")
+        );
+        assert_eq!(
+            lookup(&table, "2001:db8:2::9"),
+            found("2001:db8::/32", "v6")
+        );
+        assert_eq!(lookup(&table, "2001:db9::1"), None);
+        assert_eq!(lookup(&table, "198.51.100.1"), None);
+        // The IPv6 form of 192.0.2.9 is a different address...
+        assert_eq!(lookup(&table, "::c000:209"), None);
+        // ...but its IPv4-mapped form is the same client.
+        assert_eq!(
+            lookup(&table, "::ffff:192.0.2.9"),
+            found("192.0.2.0/24", "v4")
+        );
+    }
+
+    #[test]
+    fn insert_replaces_the_same_network() {
+        let mut table = table(&[("192.0.2.0/24", "first")]);
+        assert_eq!(
+            table.insert("192.0.2.128/24".parse().unwrap(), "second"),
+            Some("first")
+        );
+        assert_eq!(table.insert("192.0.2.0/25".parse().unwrap(), "third"), None);
+        assert_eq!(table.len(), 2);
+        assert_eq!(
+            lookup(&table, "192.0.2.200"),
+            found("192.0.2.0/24", "second")
+        );
+        assert_eq!(
+            lookup(&table, "192.0.2.100"),
+            found("192.0.2.0/25", "third")
+        );
+    }
+}
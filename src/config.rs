@@ -1,15 +1,21 @@
 /* src/config.rs */
 
+use crate::cidr::{Cidr, CidrTable};
+use crate::location::{GeoGroups, LocationKey};
 use crate::records::ZoneConfig;
+use crate::zone::CompiledZone;
 use chrono::{DateTime, Utc};
-use fancy_log::{LogLevel, log};
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::env;
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
 
 const DEFAULT_MAIN_CONFIG: &str = r#"
 default_ttl = 5
@@ -55,6 +61,455 @@ struct MainConfig {
     default_ttl: u32,
     #[serde(default)]
     zones: HashMap<String, String>,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    server: ServerConfig,
+    #[serde(default)]
+    limits: LimitsConfig,
+    #[serde(default)]
+    control: ControlConfig,
+    #[serde(default)]
+    api: ApiConfig,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    #[serde(default)]
+    health: HealthConfig,
+    #[serde(default)]
+    query_log: QueryLogConfig,
+    #[serde(default)]
+    dnstap: DnstapConfig,
+    #[serde(default)]
+    privacy: PrivacyConfig,
+    #[serde(default)]
+    geoip: GeoIpConfig,
+    #[serde(default)]
+    geoip_cache: GeoIpCacheConfig,
+    /// Network to location, e.g. `"203.0.113.0/24" = "JP"`.
+    #[serde(default)]
+    geo_overrides: HashMap<String, String>,
+    /// Group name to locations, e.g. `apac = ["JP", "KR", "SG", "AU"]`.
+    #[serde(default)]
+    geo_groups: HashMap<String, Vec<String>>,
+}
+
+/// Just the `[control]` section, for `lazy-dns ctl`, which must not load zones.
+#[cfg(unix)]
+#[derive(Debug, Default, Deserialize)]
+struct ControlOnly {
+    #[serde(default)]
+    control: ControlConfig,
+}
+
+/// Settings for the wire-format response cache (`[cache]` in config.toml).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub max_entries: usize,
+    pub max_bytes: usize,
+}
+
+/// Listener tuning (`[server]` in config.toml). Read once at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Number of UDP sockets bound to the DNS port, each read by its own task
+    /// on the shared runtime. More than one turns on SO_REUSEPORT.
+    pub udp_workers: usize,
+    /// Datagrams received or sent per `recvmmsg`/`sendmmsg` call on Linux.
+    pub udp_batch_size: usize,
+    /// Queries a single UDP worker may have in flight before it stops reading.
+    pub udp_max_inflight: usize,
+    /// How long in-flight queries may take to finish after SIGTERM/SIGINT.
+    pub shutdown_grace_secs: f64,
+}
+
+impl ServerConfig {
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs_f64(self.shutdown_grace_secs.max(0.0))
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            udp_workers: 1,
+            udp_batch_size: 32,
+            udp_max_inflight: 1024,
+            shutdown_grace_secs: 10.0,
+        }
+    }
+}
+
+/// What to do with a query that arrives while `max_inflight_queries` are busy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverloadPolicy {
+    Drop,
+    Refused,
+}
+
+/// Connection and concurrency limits (`[limits]` in config.toml). Read once at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub max_tcp_clients: usize,
+    pub max_tcp_clients_per_ip: usize,
+    pub tcp_read_timeout_secs: f64,
+    pub tcp_write_timeout_secs: f64,
+    pub max_inflight_queries: usize,
+    pub overload_policy: OverloadPolicy,
+}
+
+impl LimitsConfig {
+    pub fn tcp_read_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.tcp_read_timeout_secs.max(0.0))
+    }
+
+    pub fn tcp_write_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.tcp_write_timeout_secs.max(0.0))
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_tcp_clients: 1024,
+            max_tcp_clients_per_ip: 32,
+            tcp_read_timeout_secs: 5.0,
+            tcp_write_timeout_secs: 5.0,
+            max_inflight_queries: 10_000,
+            overload_policy: OverloadPolicy::Drop,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: 100_000,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Local admin socket (`[control]` in config.toml). Read once at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    /// Socket path, relative to the config directory unless absolute.
+    pub socket: PathBuf,
+    /// Permission bits of the socket file, e.g. `0o660` to admit a group.
+    pub mode: u32,
+}
+
+#[cfg(unix)]
+impl ControlConfig {
+    pub fn socket_path(&self, base_path: &Path) -> PathBuf {
+        base_path.join(&self.socket)
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: PathBuf::from("control.sock"),
+            mode: 0o600,
+        }
+    }
+}
+
+/// HTTP admin API (`[api]` in config.toml). `enabled` and `listen` are read
+/// once at startup; `tokens` take effect on the next reload.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub listen: String,
+    /// Bearer tokens accepted on `/api` requests. With none, every request is refused.
+    pub tokens: Vec<String>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:8053".to_string(),
+            tokens: Vec::new(),
+        }
+    }
+}
+
+/// Listener for `/metrics`, `/healthz` and `/readyz` (`[metrics]` in
+/// config.toml), separate from the API so scrapers and probes never need the
+/// zone-editing endpoints. Read once at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub listen: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen: "0.0.0.0:9153".to_string(),
+        }
+    }
+}
+
+/// Readiness rules for `/readyz` (`[health]` in config.toml).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Report not ready while no GeoIP provider is available.
+    pub require_geoip: bool,
+}
+
+/// Where the structured query log is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryLogSink {
+    Stdout,
+    File,
+    Syslog,
+}
+
+/// Structured query log (`[query_log]` in config.toml). `enabled` and the
+/// sink settings are read once at startup; sampling and zone flags take
+/// effect on the next reload.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QueryLogConfig {
+    pub enabled: bool,
+    pub sink: QueryLogSink,
+    /// Log file for the `file` sink, relative to the config directory unless absolute.
+    pub path: PathBuf,
+    /// Size at which the log file is rotated.
+    pub max_bytes: u64,
+    /// Rotated files kept next to the active one.
+    pub max_files: usize,
+    /// Syslog socket path, or `host:port` for syslog over UDP.
+    pub syslog: String,
+    /// Fraction of queries logged, from 0.0 to 1.0.
+    pub sample_rate: f64,
+    /// Per-zone switches; zones not listed are logged.
+    pub zones: HashMap<String, bool>,
+    /// Whether to log queries for names outside every configured zone.
+    pub log_unmatched: bool,
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sink: QueryLogSink::Stdout,
+            path: PathBuf::from("queries.log"),
+            max_bytes: 100 * 1024 * 1024,
+            max_files: 5,
+            syslog: "/dev/log".to_string(),
+            sample_rate: 1.0,
+            zones: HashMap::new(),
+            log_unmatched: true,
+        }
+    }
+}
+
+/// dnstap export (`[dnstap]` in config.toml). Read once at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DnstapConfig {
+    pub enabled: bool,
+    /// `unix:<path>`, `tcp:<host:port>` or `file:<path>`.
+    pub output: String,
+    /// Sent as the dnstap `identity`; the host name when unset.
+    pub identity: Option<String>,
+    pub version: String,
+    /// Emit AUTH_QUERY messages.
+    pub queries: bool,
+    /// Emit AUTH_RESPONSE messages.
+    pub responses: bool,
+}
+
+impl Default for DnstapConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            output: "unix:/var/run/dnstap.sock".to_string(),
+            identity: None,
+            version: format!("lazy-dns {}", env!("CARGO_PKG_VERSION")),
+            queries: true,
+            responses: true,
+        }
+    }
+}
+
+/// A source of client locations, as named in `[geoip] providers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GeoProviderKind {
+    /// The lazy-mmdb service on its Unix socket.
+    LazyMmdb,
+    /// A MaxMind or DB-IP `.mmdb` file read in-process.
+    Mmdb,
+    /// A CSV file of `network,location` lines.
+    Csv,
+    /// No locations at all.
+    None,
+}
+
+/// A source of client ASNs, as named in `[geoip] asn_providers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AsnProviderKind {
+    /// lazy-mmdb's `asn_lookup_path` endpoint.
+    LazyMmdb,
+    /// An ASN `.mmdb` file such as GeoLite2-ASN, read in-process.
+    Mmdb,
+}
+
+/// GeoIP providers and connections to lazy-mmdb (`[geoip]` in config.toml).
+/// Read once at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    /// Tried in order until one knows the client's location.
+    pub providers: Vec<GeoProviderKind>,
+    /// Database for the `mmdb` provider, relative to the config directory.
+    /// It is reopened whenever the file is replaced.
+    pub mmdb_path: PathBuf,
+    /// Table for the `csv` provider, relative to the config directory. It is
+    /// read again whenever the file changes.
+    pub csv_path: PathBuf,
+    /// Deadline for one lookup, connecting included. Past it, the query is
+    /// answered without GeoIP.
+    pub lookup_timeout_ms: u64,
+    /// lazy-mmdb endpoint queried with `?ip=`. `/lookup/city` also returns
+    /// the continent, subdivisions and city.
+    pub lookup_path: String,
+    /// Tried in order for the client's ASN, alongside `providers`. Empty
+    /// disables `asn` tables.
+    pub asn_providers: Vec<AsnProviderKind>,
+    /// ASN database for the `mmdb` ASN provider, relative to the config
+    /// directory.
+    pub asn_mmdb_path: PathBuf,
+    /// lazy-mmdb endpoint for the `lazy-mmdb` ASN provider.
+    pub asn_lookup_path: String,
+    /// Keep-alive connections kept open for reuse.
+    pub pool_size: usize,
+    /// Pooled connections idle for longer than this are not reused.
+    pub idle_timeout_secs: u64,
+}
+
+impl GeoIpConfig {
+    pub fn lookup_timeout(&self) -> Duration {
+        Duration::from_millis(self.lookup_timeout_ms)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl Default for GeoIpConfig {
+    fn default() -> Self {
+        Self {
+            providers: vec![GeoProviderKind::LazyMmdb],
+            mmdb_path: PathBuf::from("GeoLite2-Country.mmdb"),
+            csv_path: PathBuf::from("geo.csv"),
+            lookup_timeout_ms: 100,
+            lookup_path: "/lookup/country".to_string(),
+            asn_providers: Vec::new(),
+            asn_mmdb_path: PathBuf::from("GeoLite2-ASN.mmdb"),
+            asn_lookup_path: "/lookup/asn".to_string(),
+            pool_size: 32,
+            idle_timeout_secs: 30,
+        }
+    }
+}
+
+/// In-process cache of GeoIP results (`[geoip_cache]` in config.toml). Read
+/// once at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GeoIpCacheConfig {
+    pub enabled: bool,
+    pub max_entries: usize,
+    /// How long a location found by a provider is reused.
+    pub ttl_secs: u64,
+    /// How long an address without a location is remembered.
+    pub negative_ttl_secs: u64,
+    /// IPv4 addresses sharing this many leading bits share an entry.
+    pub ipv4_prefix: u8,
+    /// IPv6 addresses sharing this many leading bits share an entry.
+    pub ipv6_prefix: u8,
+    /// Addresses to look up at startup, one per line, relative to the config directory.
+    pub prewarm_file: Option<PathBuf>,
+}
+
+impl GeoIpCacheConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+
+    pub fn negative_ttl(&self) -> Duration {
+        Duration::from_secs(self.negative_ttl_secs)
+    }
+}
+
+impl Default for GeoIpCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: 100_000,
+            ttl_secs: 3600,
+            negative_ttl_secs: 300,
+            ipv4_prefix: 32,
+            ipv6_prefix: 128,
+            prewarm_file: None,
+        }
+    }
+}
+
+/// How client addresses appear in logs and exports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientIpMode {
+    Full,
+    Truncate,
+    Hash,
+    Remove,
+}
+
+/// Client address anonymization (`[privacy]` in config.toml). Applies to the
+/// operational log, the query log and dnstap; routing always sees the full address.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    pub client_ip: ClientIpMode,
+    /// Bits of an IPv4 address kept in `truncate` mode.
+    pub ipv4_prefix: u8,
+    /// Bits of an IPv6 address kept in `truncate` mode.
+    pub ipv6_prefix: u8,
+    /// HMAC key for `hash` mode. When unset, a random key is drawn at startup,
+    /// so hashes only correlate within one run of the server.
+    pub hash_key: Option<String>,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self {
+            client_ip: ClientIpMode::Full,
+            ipv4_prefix: 24,
+            ipv6_prefix: 48,
+            hash_key: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -76,23 +531,41 @@ impl FromStr for UnconfiguredPolicy {
     }
 }
 
+#[derive(Clone)]
 pub struct AppConfig {
+    /// Directory holding config.toml; zone file paths are relative to it.
+    pub base_path: PathBuf,
+    /// Zone file of every configured zone, including zones that failed to load.
+    pub zone_files: HashMap<String, PathBuf>,
+    /// Zones that could not be loaded, with the reason.
+    pub zone_errors: HashMap<String, String>,
+    /// Default TTL in minutes, for zones that do not set their own.
     pub default_ttl: u32,
-    pub zones: HashMap<String, ZoneConfig>,
+    /// Hash of config.toml, to tell whether anything besides the zones changed.
+    pub fingerprint: u64,
+    pub zones: HashMap<String, Arc<CompiledZone>>,
     pub unconfigured_policy: UnconfiguredPolicy,
+    pub cache: CacheConfig,
+    pub server: ServerConfig,
+    pub limits: LimitsConfig,
+    pub control: ControlConfig,
+    pub api: ApiConfig,
+    pub metrics: MetricsConfig,
+    pub health: HealthConfig,
+    pub query_log: QueryLogConfig,
+    pub dnstap: DnstapConfig,
+    pub privacy: PrivacyConfig,
+    pub geoip: GeoIpConfig,
+    pub geoip_cache: GeoIpCacheConfig,
+    /// Locations from `[geo_overrides]`, which take precedence over GeoIP.
+    pub geo_overrides: Arc<CidrTable<LocationKey>>,
+    /// Groups from `[geo_groups]`, expanded into zones when they load.
+    pub geo_groups: Arc<GeoGroups>,
 }
 
 impl AppConfig {
     pub fn load_from_env() -> Result<Self, Box<dyn std::error::Error>> {
-        let base_path = env::var("CONFIG_PATH")
-            .ok()
-            .filter(|s| !s.is_empty())
-            .map(PathBuf::from)
-            .unwrap_or_else(|| {
-                dirs::home_dir()
-                    .expect("Could not find home directory")
-                    .join("lazy-dns")
-            });
+        let base_path = base_path_from_env();
 
         if !base_path.exists() {
             fs::create_dir_all(&base_path)?;
@@ -101,81 +574,303 @@ impl AppConfig {
         let main_config_path = base_path.join("config.toml");
 
         if !main_config_path.exists() {
-            log(
-                LogLevel::Warn,
-                &format!(
-                    "Main config not found. Creating default at {:?}",
-                    main_config_path
-                ),
+            warn!(
+                "Main config not found. Creating default at {:?}",
+                main_config_path
             );
             fs::write(&main_config_path, DEFAULT_MAIN_CONFIG)?;
             let example_zone_path = base_path.join("example.com.zone.toml");
             fs::write(&example_zone_path, DEFAULT_ZONE_FILE)?;
         }
 
-        log(
-            LogLevel::Info,
-            &format!("Loading main config from {:?}", main_config_path),
-        );
+        Self::load(&base_path)
+    }
+
+    /// A config serving `zones`, with every other setting at its default.
+    #[cfg(test)]
+    pub fn with_zones(zones: HashMap<String, Arc<CompiledZone>>) -> Self {
+        AppConfig {
+            base_path: PathBuf::new(),
+            zone_files: HashMap::new(),
+            zone_errors: HashMap::new(),
+            default_ttl: 5,
+            fingerprint: 0,
+            zones,
+            unconfigured_policy: UnconfiguredPolicy::NxDomain,
+            cache: CacheConfig::default(),
+            server: ServerConfig::default(),
+            limits: LimitsConfig::default(),
+            control: ControlConfig::default(),
+            api: ApiConfig::default(),
+            metrics: MetricsConfig::default(),
+            health: HealthConfig::default(),
+            query_log: QueryLogConfig::default(),
+            dnstap: DnstapConfig::default(),
+            privacy: PrivacyConfig::default(),
+            geo_overrides: Arc::new(CidrTable::default()),
+            geo_groups: Arc::new(GeoGroups::default()),
+            geoip: GeoIpConfig::default(),
+            geoip_cache: GeoIpCacheConfig::default(),
+        }
+    }
+
+    /// Reads config.toml and every zone file in `base_path`. Zones that fail
+    /// to load are left out and recorded in `zone_errors`.
+    pub fn load(base_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let main_config_path = base_path.join("config.toml");
+        info!("Loading main config from {:?}", main_config_path);
         let main_config_str = fs::read_to_string(&main_config_path)?;
         let main_config: MainConfig = toml::from_str(&main_config_str)?;
+        let mut hasher = DefaultHasher::new();
+        main_config_str.hash(&mut hasher);
+        let fingerprint = hasher.finish();
+        let geo_groups = compile_geo_groups(&main_config.geo_groups)?;
 
         let mut loaded_zones = HashMap::new();
+        let mut zone_files = HashMap::new();
+        let mut zone_errors = HashMap::new();
         for (domain, file_name) in main_config.zones {
             let zone_path = base_path.join(file_name);
-            match load_zone_file(&zone_path) {
-                Ok(zone_config) => {
-                    log(
-                        LogLevel::Info,
-                        &format!("Loaded zone for '{}' from {:?}", domain, zone_path),
-                    );
-                    if !zone_config.apex.ns.is_empty() && zone_config.soa.is_none() {
-                        log(
-                            LogLevel::Error,
-                            &format!("Zone '{}' has NS records but no SOA record.", domain),
-                        );
-                        continue;
-                    }
-                    loaded_zones.insert(domain, zone_config);
+            zone_files.insert(domain.clone(), zone_path.clone());
+            match load_zone(&domain, &zone_path, main_config.default_ttl, &geo_groups) {
+                Ok(zone) => {
+                    info!("Loaded zone for '{}' from {:?}", domain, zone_path);
+                    loaded_zones.insert(domain, Arc::new(zone));
                 }
                 Err(e) => {
-                    log(
-                        LogLevel::Error,
-                        &format!("Failed to load zone file {:?}: {}", zone_path, e),
+                    error!(
+                        "Failed to load zone '{}' from {:?}: {}",
+                        domain, zone_path, e
                     );
+                    zone_errors.insert(domain, e.to_string());
                 }
             }
         }
 
         if loaded_zones.is_empty() {
-            log(
-                LogLevel::Warn,
-                "Config loaded, but no zones are configured or loaded successfully.",
-            );
+            warn!("Config loaded, but no zones are configured or loaded successfully.");
         }
 
+        let geo_overrides = compile_geo_overrides(&main_config.geo_overrides)?;
+
         let unconfigured_policy = env::var("UNCONFIGURED_DOMAIN_POLICY")
             .ok()
             .and_then(|s| s.parse().ok())
             .unwrap_or(UnconfiguredPolicy::NxDomain);
 
-        log(
-            LogLevel::Info,
-            &format!(
-                "Unconfigured domain policy set to: {:?}",
-                unconfigured_policy
-            ),
+        info!(
+            "Unconfigured domain policy set to: {:?}",
+            unconfigured_policy
         );
 
         Ok(AppConfig {
+            base_path: base_path.to_path_buf(),
+            zone_files,
+            zone_errors,
             default_ttl: main_config.default_ttl,
+            fingerprint,
             zones: loaded_zones,
             unconfigured_policy,
+            cache: main_config.cache,
+            server: main_config.server,
+            limits: main_config.limits,
+            control: main_config.control,
+            api: main_config.api,
+            metrics: main_config.metrics,
+            health: main_config.health,
+            query_log: main_config.query_log,
+            dnstap: main_config.dnstap,
+            privacy: main_config.privacy,
+            geoip: main_config.geoip,
+            geoip_cache: main_config.geoip_cache,
+            geo_overrides: Arc::new(geo_overrides),
+            geo_groups: Arc::new(geo_groups),
         })
     }
 }
 
-fn load_zone_file(path: &Path) -> Result<ZoneConfig, Box<dyn std::error::Error>> {
+/// Parses the `[geo_overrides]` networks. Locations are written like zone
+/// `country` keys (`JP`, `US-CA`, `continent:EU`, `city:US/Los Angeles`).
+fn compile_geo_overrides(
+    overrides: &HashMap<String, String>,
+) -> Result<CidrTable<LocationKey>, Box<dyn std::error::Error>> {
+    let mut table = CidrTable::default();
+    for (network, location) in overrides {
+        let cidr: Cidr = network
+            .parse()
+            .map_err(|e| format!("[geo_overrides]: {}", e))?;
+        let location: LocationKey = location
+            .parse()
+            .map_err(|e| format!("[geo_overrides]: {} for '{}'", e, network))?;
+        if table.insert(cidr, location).is_some() {
+            return Err(format!("[geo_overrides]: {} is listed twice", cidr).into());
+        }
+    }
+    if table.len() > 0 {
+        info!("Loaded {} GeoIP override network(s)", table.len());
+    }
+    Ok(table)
+}
+
+/// Parses `[geo_groups]` and warns about locations that are in more than one
+/// group.
+fn compile_geo_groups(
+    groups: &HashMap<String, Vec<String>>,
+) -> Result<GeoGroups, Box<dyn std::error::Error>> {
+    let mut compiled = HashMap::with_capacity(groups.len());
+    for (name, members) in groups {
+        if !GeoGroups::is_valid_name(name) {
+            return Err(format!(
+                "[geo_groups]: invalid group name '{}', use at least three letters, digits or '_'",
+                name
+            )
+            .into());
+        }
+        let name = name.to_lowercase();
+        let mut keys: Vec<LocationKey> = Vec::with_capacity(members.len());
+        for member in members {
+            if groups
+                .keys()
+                .any(|group| group.eq_ignore_ascii_case(member.trim()))
+            {
+                return Err(format!(
+                    "[geo_groups]: group '{}' contains group '{}', groups cannot be nested",
+                    name, member
+                )
+                .into());
+            }
+            let key: LocationKey = member
+                .parse()
+                .map_err(|e| format!("[geo_groups]: {} in group '{}'", e, name))?;
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        if keys.is_empty() {
+            return Err(format!("[geo_groups]: group '{}' is empty", name).into());
+        }
+        if compiled.insert(name.clone(), keys).is_some() {
+            return Err(format!("[geo_groups]: group '{}' is listed twice", name).into());
+        }
+    }
+
+    let mut membership: HashMap<&LocationKey, Vec<&str>> = HashMap::new();
+    for (name, keys) in &compiled {
+        for key in keys {
+            membership.entry(key).or_default().push(name);
+        }
+    }
+    let mut overlaps: Vec<_> = membership
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .collect();
+    overlaps.sort_by_key(|(key, _)| key.to_string());
+    for (key, mut names) in overlaps {
+        names.sort();
+        warn!(
+            "[geo_groups]: {} is in groups {}; zones using several of them take it from the smallest",
+            key,
+            names.join(", ")
+        );
+    }
+
+    if !compiled.is_empty() {
+        info!("Loaded {} GeoIP group(s)", compiled.len());
+    }
+    Ok(GeoGroups::new(compiled))
+}
+
+/// The config directory: `CONFIG_PATH`, or `~/lazy-dns` when unset.
+pub fn base_path_from_env() -> PathBuf {
+    env::var("CONFIG_PATH")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            dirs::home_dir()
+                .expect("Could not find home directory")
+                .join("lazy-dns")
+        })
+}
+
+/// Reads only the `[control]` section of config.toml, falling back to the
+/// defaults when the file does not exist.
+#[cfg(unix)]
+pub fn load_control_config(base_path: &Path) -> Result<ControlConfig, Box<dyn std::error::Error>> {
+    let main_config_path = base_path.join("config.toml");
+    if !main_config_path.exists() {
+        return Ok(ControlConfig::default());
+    }
+    let parsed: ControlOnly = toml::from_str(&fs::read_to_string(main_config_path)?)?;
+    Ok(parsed.control)
+}
+
+/// Reads and compiles one zone file. The SOA serial never goes below the one
+/// last recorded for the zone by `save_serial`, so it does not go backwards
+/// across restarts.
+pub fn load_zone(
+    domain: &str,
+    path: &Path,
+    default_ttl: u32,
+    geo_groups: &GeoGroups,
+) -> Result<CompiledZone, Box<dyn std::error::Error>> {
+    let (zone_config, content_hash) = load_zone_file(path)?;
+    let mut zone = CompiledZone::compile(domain, &zone_config, default_ttl, geo_groups)?;
+
+    // The default TTL and the groups are part of what gets served, so they
+    // are part of the fingerprint too.
+    let mut hasher = DefaultHasher::new();
+    (content_hash, default_ttl, geo_groups.fingerprint).hash(&mut hasher);
+    zone.fingerprint = hasher.finish();
+
+    if let Some(serial) = zone.serial() {
+        let saved = read_saved_serial(path);
+        let serial = match saved {
+            Some((saved, fingerprint)) if fingerprint == zone.fingerprint => serial.max(saved),
+            // Changed while the server was down, within the same hour.
+            Some((saved, _)) if saved >= serial => saved.wrapping_add(1),
+            _ => serial,
+        };
+        zone.set_serial(serial);
+        if saved != Some((serial, zone.fingerprint)) {
+            save_serial(path, &zone);
+        }
+    }
+    Ok(zone)
+}
+
+/// Where the last served serial of a zone file is kept: next to it, with a
+/// `.serial` suffix.
+fn serial_path(zone_path: &Path) -> PathBuf {
+    let mut path = zone_path.as_os_str().to_os_string();
+    path.push(".serial");
+    PathBuf::from(path)
+}
+
+/// The serial and fingerprint last saved for a zone file, if any.
+fn read_saved_serial(zone_path: &Path) -> Option<(u32, u64)> {
+    let content = fs::read_to_string(serial_path(zone_path)).ok()?;
+    let (serial, fingerprint) = content.trim().split_once(' ')?;
+    Some((
+        serial.parse().ok()?,
+        u64::from_str_radix(fingerprint, 16).ok()?,
+    ))
+}
+
+/// Records the serial a zone is served with. Failing to write it only costs
+/// the guarantee across restarts, so it is logged and otherwise ignored.
+pub fn save_serial(zone_path: &Path, zone: &CompiledZone) {
+    let Some(serial) = zone.serial() else {
+        return;
+    };
+    let path = serial_path(zone_path);
+    if let Err(e) = fs::write(&path, format!("{} {:016x}\n", serial, zone.fingerprint)) {
+        warn!("Failed to save zone serial to {:?}: {}", path, e);
+    }
+}
+
+/// Reads a zone file, returning it with a hash of its content.
+fn load_zone_file(path: &Path) -> Result<(ZoneConfig, u64), Box<dyn std::error::Error>> {
     let metadata = fs::metadata(path)?;
     let modified_time = metadata.modified()?;
     let serial = generate_serial(modified_time);
@@ -187,7 +882,9 @@ fn load_zone_file(path: &Path) -> Result<ZoneConfig, Box<dyn std::error::Error>>
         soa.serial = serial;
     }
 
-    Ok(zone)
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    Ok((zone, hasher.finish()))
 }
 
 fn generate_serial(mod_time: SystemTime) -> u32 {
@@ -0,0 +1,477 @@
+/* src/control.rs */
+
+use crate::config::ControlConfig;
+use crate::geoip_cache::read_prewarm_file;
+use crate::logging;
+use crate::reload::{self, ReloadReport, fmt_serial};
+use crate::resolver::DnsResolver;
+use crate::stats::{self, SERVER};
+use chrono::{DateTime, Utc};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time::{Duration, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// How long a client may take to send its command line.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest command line accepted.
+const MAX_COMMAND_LEN: u64 = 1024;
+
+pub const COMMANDS: &str = "\
+reload [zone]          reload all zones, or just one
+zonestatus [zone]      serial, record count and load time of each zone
+geoip                  GeoIP service and cache status
+geoip prewarm [file]   look up the addresses in a file and cache them
+geoip flush            drop every cached GeoIP result
+loglevel [filter]      show or set the log filter (e.g. info,lazy_dns::geoip=debug)
+flush                  drop every cached response
+stats                  server, cache and zone counters";
+
+/// Binds the admin socket and serves commands on it until `shutdown` is
+/// cancelled, then removes the socket file.
+///
+/// Each connection carries one command line; the reply starts with `OK` or
+/// `ERR <reason>` and is followed by free-form text, then the connection is closed.
+pub fn spawn_server(
+    control: &ControlConfig,
+    resolver: Arc<DnsResolver>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let path = control.socket_path(&resolver.config().base_path);
+    let listener = bind(&path, control.mode)?;
+    info!(
+        "Control socket listening on {:?} (mode {:o})",
+        path, control.mode
+    );
+
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    let resolver = resolver.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &resolver).await {
+                            warn!("Control connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Control socket accept failed: {}", e),
+            }
+        }
+        let _ = fs::remove_file(&path);
+    });
+    Ok(())
+}
+
+/// Binds `path`, replacing a stale socket file left by a previous run but
+/// refusing to take over one that a live server still answers on.
+///
+/// The socket is created in a private directory next to `path` and moved into
+/// place once its mode is set, so it is never reachable with the looser
+/// permissions the umask would give it.
+fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another server is already listening on it",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent)?;
+
+    let staging = parent.join(format!(".ctl-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&staging);
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("sock");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    listener
+}
+
+async fn handle_connection(stream: UnixStream, resolver: &DnsResolver) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    let mut reader = BufReader::new(read.take(MAX_COMMAND_LEN));
+    match timeout(READ_TIMEOUT, reader.read_line(&mut line)).await {
+        Ok(result) => result?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out")),
+    };
+
+    let reply = match execute(line.trim(), resolver).await {
+        Ok(body) => format!("OK\n{}", body),
+        Err(reason) => format!("ERR {}\n", reason),
+    };
+    write.write_all(reply.as_bytes()).await?;
+    write.shutdown().await
+}
+
+async fn execute(line: &str, resolver: &DnsResolver) -> Result<String, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+    info!("Control command: {}", line);
+
+    match (command, args.as_slice()) {
+        ("reload", []) => {
+            let report = reload::reload(resolver).await?;
+            report.log();
+            Ok(format_report(&report))
+        }
+        ("reload", [zone]) => {
+            let report = reload::reload_zone(resolver, zone).await?;
+            report.log();
+            Ok(format_report(&report))
+        }
+        ("zonestatus", []) => Ok(zone_status(resolver, None)),
+        ("zonestatus", [zone]) => {
+            if !resolver.config().zone_files.contains_key(*zone) {
+                return Err(format!("zone '{}' is not configured", zone));
+            }
+            Ok(zone_status(resolver, Some(zone)))
+        }
+        ("geoip", []) => {
+            let geoip = resolver.geoip();
+            let mut out = String::new();
+            for provider in geoip.providers() {
+                let state = if provider.available {
+                    "available"
+                } else {
+                    "unavailable"
+                };
+                let detail = provider
+                    .detail
+                    .map(|detail| format!(" ({})", detail))
+                    .unwrap_or_default();
+                out.push_str(&format!(
+                    "{} at {}: {}{}\n",
+                    provider.name, provider.source, state, detail
+                ));
+            }
+            let config = resolver.config();
+            out.push_str(&format!(
+                "overrides: {} network(s)\n",
+                config.geo_overrides.len()
+            ));
+            out.push_str(&format!("groups: {}\n", config.geo_groups.len()));
+            let cache = geoip.cache_stats();
+            out.push_str(&format!(
+                "cache: {} entries, {} hits, {} misses, hit rate {:.1}%\n",
+                cache.entries,
+                cache.hits,
+                cache.misses,
+                cache.hit_rate() * 100.0
+            ));
+            Ok(out)
+        }
+        ("geoip", ["prewarm", rest @ ..]) if rest.len() <= 1 => {
+            let config = resolver.config();
+            let path = match rest.first() {
+                Some(path) => config.base_path.join(path),
+                None => config
+                    .geoip_cache
+                    .prewarm_file
+                    .as_ref()
+                    .map(|path| config.base_path.join(path))
+                    .ok_or("no file given and no prewarm_file configured")?,
+            };
+            let (addrs, invalid) =
+                read_prewarm_file(&path).map_err(|e| format!("{:?}: {}", path, e))?;
+            let geoip = resolver.geoip();
+            if !geoip.is_available() {
+                return Err("no GeoIP provider is available".to_string());
+            }
+            let report = geoip.prewarm(&addrs).await;
+            Ok(format!(
+                "cached {} address(es), {} with a country, {} failed, {} skipped, {} invalid\n",
+                report.cached,
+                report.found,
+                report.failed,
+                report.skipped,
+                invalid.len()
+            ))
+        }
+        ("geoip", ["flush"]) => {
+            let removed = resolver.geoip().clear_cache();
+            Ok(format!("flushed {} cached GeoIP results\n", removed))
+        }
+        ("loglevel", []) => Ok(format!("log filter is {}\n", logging::current_filter())),
+        ("loglevel", [filter]) => {
+            logging::set_filter(filter)
+                .map_err(|e| format!("invalid log filter '{}': {}", filter, e))?;
+            warn!(filter = %filter, "Log filter changed");
+            Ok(format!("log filter set to {}\n", filter))
+        }
+        ("flush", []) => {
+            let removed = resolver.cache().clear();
+            Ok(format!("flushed {} cached responses\n", removed))
+        }
+        ("stats", []) => Ok(stats_dump(resolver)),
+        ("", _) => Err("empty command".to_string()),
+        ("reload" | "zonestatus" | "geoip" | "loglevel" | "flush" | "stats", _) => {
+            Err(format!("wrong arguments for '{}'", command))
+        }
+        _ => Err(format!("unknown command '{}'", command)),
+    }
+}
+
+fn format_report(report: &ReloadReport) -> String {
+    let mut out = format!("{}\n", report);
+    for change in &report.changed {
+        let _ = writeln!(
+            out,
+            "changed {}: serial {} -> {}",
+            change.zone,
+            fmt_serial(change.old_serial),
+            fmt_serial(change.new_serial)
+        );
+    }
+    for zone in &report.added {
+        let _ = writeln!(out, "added {}", zone);
+    }
+    for zone in &report.removed {
+        let _ = writeln!(out, "removed {}", zone);
+    }
+    for failure in &report.failed {
+        let outcome = if failure.kept_previous {
+            "keeping previous version"
+        } else {
+            "not served"
+        };
+        let _ = writeln!(
+            out,
+            "failed {} ({}): {}",
+            failure.zone, outcome, failure.error
+        );
+    }
+    out
+}
+
+fn zone_status(resolver: &DnsResolver, only: Option<&str>) -> String {
+    let config = resolver.config();
+    let mut names: Vec<&String> = config
+        .zone_files
+        .keys()
+        .filter(|name| only.is_none_or(|only| only == name.as_str()))
+        .collect();
+    names.sort();
+
+    let mut out = String::new();
+    for name in names {
+        match config.zones.get(name) {
+            Some(zone) => {
+                let loaded: DateTime<Utc> = zone.loaded_at.into();
+                let _ = writeln!(
+                    out,
+                    "{}: serial {}, {} records, loaded {}",
+                    name,
+                    fmt_serial(zone.serial()),
+                    zone.record_count,
+                    loaded.format("%Y-%m-%d %H:%M:%S UTC")
+                );
+            }
+            None => {
+                let _ = writeln!(out, "{}: not loaded", name);
+            }
+        }
+        if let Some(error) = config.zone_errors.get(name) {
+            let _ = writeln!(out, "  last load failed: {}", error);
+        }
+        if let Some(path) = config.zone_files.get(name) {
+            let _ = writeln!(out, "  file {}", path.display());
+        }
+    }
+    out
+}
+
+fn stats_dump(resolver: &DnsResolver) -> String {
+    let config = resolver.config();
+    let cache = resolver.cache().stats();
+    let geoip_cache = resolver.geoip().cache_stats();
+    let counters = [
+        ("queries", stats::get(&SERVER.queries)),
+        ("inflight_queries", stats::get(&SERVER.inflight_queries)),
+        ("shed_queries", stats::get(&SERVER.shed_queries)),
+        ("tcp_connections", stats::get(&SERVER.tcp_connections)),
+        ("tcp_rejected", stats::get(&SERVER.tcp_rejected)),
+        ("tcp_timeouts", stats::get(&SERVER.tcp_timeouts)),
+        ("cache_entries", cache.entries as u64),
+        ("cache_bytes", cache.bytes as u64),
+        ("cache_hits", cache.hits),
+        ("cache_misses", cache.misses),
+        ("geoip_cache_entries", geoip_cache.entries as u64),
+        ("geoip_cache_hits", geoip_cache.hits),
+        ("geoip_cache_misses", geoip_cache.misses),
+        ("zones_loaded", config.zones.len() as u64),
+        ("zones_failed", config.zone_errors.len() as u64),
+    ];
+    counters
+        .iter()
+        .map(|(name, value)| format!("{} {}\n", name, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, GeoIpCacheConfig};
+    use crate::geo_provider::NoopProvider;
+    use crate::geoip::GeoIpClient;
+    use std::path::PathBuf;
+
+    const ZONE: &str = r#"
+        [soa]
+        mname = "ns1.example.com."
+        rname = "admin.example.com."
+
+        [apex]
+        a = ["192.0.2.1"]
+    "#;
+
+    /// A resolver serving `ZONE` as example.com from a fresh directory.
+    fn resolver(name: &str) -> (Arc<DnsResolver>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("lazy-dns-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("config.toml"),
+            "default_ttl = 5\n[zones]\n\"example.com\" = \"example.com.toml\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("example.com.toml"), ZONE).unwrap();
+
+        let config = AppConfig::load(&dir).unwrap();
+        let resolver = DnsResolver::new(
+            Arc::new(config),
+            Arc::new(GeoIpClient::new(
+                Arc::new(NoopProvider),
+                &GeoIpCacheConfig::default(),
+            )),
+            None,
+            None,
+        );
+        (Arc::new(resolver), dir)
+    }
+
+    #[tokio::test]
+    async fn execute_rejects_malformed_commands() {
+        let (resolver, dir) = resolver("control-parse");
+        let cases = [
+            ("", "empty command"),
+            ("   ", "empty command"),
+            ("restart", "unknown command 'restart'"),
+            ("reload a b", "wrong arguments for 'reload'"),
+            ("loglevel a b", "wrong arguments for 'loglevel'"),
+            ("flush now", "wrong arguments for 'flush'"),
+            ("stats -v", "wrong arguments for 'stats'"),
+            (
+                "zonestatus example.org",
+                "zone 'example.org' is not configured",
+            ),
+            ("reload example.org", "zone 'example.org' is not configured"),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(
+                execute(line, &resolver).await.unwrap_err(),
+                expected,
+                "{:?}",
+                line
+            );
+        }
+        let error = execute("loglevel lazy_dns=loud", &resolver)
+            .await
+            .unwrap_err();
+        assert!(error.starts_with("invalid log filter 'lazy_dns=loud': "));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn execute_runs_commands_with_extra_whitespace() {
+        let (resolver, dir) = resolver("control-run");
+
+        let status = execute("  zonestatus   example.com ", &resolver)
+            .await
+            .unwrap();
+        assert!(status.starts_with("example.com: serial "), "{}", status);
+
+        let report = execute("reload example.com", &resolver).await.unwrap();
+        assert!(report.contains("1 unchanged"), "{}", report);
+
+        let flushed = execute("flush", &resolver).await.unwrap();
+        assert_eq!(flushed, "flushed 0 cached responses\n");
+
+        let stats = execute("stats", &resolver).await.unwrap();
+        assert!(stats.contains("zones_loaded 1\n"), "{}", stats);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_sets_mode_and_refuses_a_live_socket() {
+        let dir =
+            std::env::temp_dir().join(format!("lazy-dns-control-bind-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("run").join("control.sock");
+
+        let listener = bind(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let error = bind(&path, 0o600).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        // Once nobody listens, the stale socket file is replaced.
+        drop(listener);
+        let _listener = bind(&path, 0o660).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        // No staging directory is left behind.
+        let entries: Vec<_> = fs::read_dir(dir.join("run")).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn server_answers_one_command_per_connection() {
+        let (resolver, dir) = resolver("control-server");
+        let shutdown = CancellationToken::new();
+        spawn_server(&ControlConfig::default(), resolver, shutdown.clone()).unwrap();
+
+        let mut stream = UnixStream::connect(dir.join("control.sock")).await.unwrap();
+        stream.write_all(b"zonestatus\n").await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("OK\nexample.com: serial "), "{}", reply);
+
+        let mut stream = UnixStream::connect(dir.join("control.sock")).await.unwrap();
+        stream.write_all(b"bogus\n").await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "ERR unknown command 'bogus'\n");
+
+        shutdown.cancel();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
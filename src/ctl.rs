@@ -0,0 +1,92 @@
+/* src/ctl.rs */
+
+use crate::config;
+use crate::control::COMMANDS;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// Runs `lazy-dns ctl [--socket PATH] <command> [args...]` and returns the
+/// process exit code: 0 on success, 1 if the server reported an error or
+/// could not be reached, 2 on a usage error.
+pub async fn run(args: &[String]) -> i32 {
+    let mut socket: Option<PathBuf> = None;
+    let mut command = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" | "-s" => match args.next() {
+                Some(path) => socket = Some(PathBuf::from(path)),
+                None => return usage("--socket needs a path"),
+            },
+            "-h" | "--help" => {
+                usage("");
+                return 0;
+            }
+            _ => command.push(arg.as_str()),
+        }
+    }
+    if command.is_empty() {
+        return usage("no command given");
+    }
+
+    let socket = match socket {
+        Some(path) => path,
+        None => {
+            let base_path = config::base_path_from_env();
+            match config::load_control_config(&base_path) {
+                Ok(control) => control.socket_path(&base_path),
+                Err(e) => {
+                    eprintln!("Failed to read config.toml in {:?}: {}", base_path, e);
+                    return 1;
+                }
+            }
+        }
+    };
+
+    match send(&socket, &command.join(" ")).await {
+        Ok(reply) => print_reply(&reply),
+        Err(e) => {
+            eprintln!("Cannot reach control socket {:?}: {}", socket, e);
+            1
+        }
+    }
+}
+
+async fn send(socket: &Path, command: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(socket).await?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    stream.shutdown().await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    Ok(reply)
+}
+
+fn print_reply(reply: &str) -> i32 {
+    let (status, body) = reply.split_once('\n').unwrap_or((reply, ""));
+    print!("{}", body);
+    match status.strip_prefix("ERR") {
+        Some(reason) => {
+            eprintln!("error: {}", reason.trim());
+            1
+        }
+        None if status == "OK" => 0,
+        None => {
+            eprintln!("error: unexpected reply from server: {}", status);
+            1
+        }
+    }
+}
+
+fn usage(problem: &str) -> i32 {
+    if !problem.is_empty() {
+        eprintln!("error: {}\n", problem);
+    }
+    eprintln!("Usage: lazy-dns ctl [--socket PATH] <command> [args...]\n\nCommands:");
+    for line in COMMANDS.lines() {
+        eprintln!("  {}", line);
+    }
+    2
+}
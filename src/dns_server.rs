@@ -1,63 +1,99 @@
 /* src/dns_server.rs */
 
-use crate::config::UnconfiguredPolicy;
+use crate::cache::{self, CacheKey, CachedResponse};
+use crate::config::{OverloadPolicy, UnconfiguredPolicy};
+use crate::dnstap::Exchange;
+use crate::limits::Limits;
+use crate::metrics;
+use crate::privacy;
+use crate::query_log::{LoggedAnswer, QueryLogEntry};
 use crate::resolver::DnsResolver;
-use fancy_log::{LogLevel, log};
-use hickory_proto::op::{Message, MessageType, OpCode, ResponseCode};
-use hickory_proto::rr::{RData, Record, RecordType};
+use crate::stats::{self, SERVER};
+use crate::udp;
+use hickory_proto::op::{Edns, Header, Message, MessageType, OpCode, ResponseCode};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
 use hickory_proto::serialize::binary::{BinDecodable, BinEncodable};
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Instant, timeout};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, Span, debug, debug_span, field, info, warn};
+
+/// UDP payload size advertised in EDNS responses.
+const EDNS_MAX_PAYLOAD: u16 = 1232;
+
+/// Identifies the `request` span of each query in the logs.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
 /// Runs both the UDP and TCP DNS servers concurrently.
+///
+/// Returns once `shutdown` is cancelled and the listeners are closed; every
+/// connection and query still being handled is registered in `tasks`.
 pub async fn run_server(
     bind_addr: &str,
     resolver: Arc<DnsResolver>,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let addr: SocketAddr = bind_addr.parse()?;
+    let server_config = resolver.config().server.clone();
+
     // Bind both UDP and TCP listeners to the same address
-    let udp_socket = Arc::new(UdpSocket::bind(bind_addr).await?);
-    let tcp_listener = TcpListener::bind(bind_addr).await?;
+    let udp_sockets = udp::bind_sockets(addr, server_config.udp_workers)?;
+    let tcp_listener = TcpListener::bind(addr).await?;
 
-    log(
-        LogLevel::Info,
-        &format!("DNS server listening for UDP and TCP on {}", bind_addr),
-    );
+    info!("DNS server listening for UDP and TCP on {}", bind_addr);
 
-    // Buffer for incoming UDP packets
-    let mut udp_buf = [0; 512];
+    SERVER.listening.store(true, Ordering::Relaxed);
+
+    let limits = Arc::new(Limits::new(resolver.config().limits.clone()));
+    udp::spawn_workers(
+        udp_sockets,
+        resolver.clone(),
+        limits.clone(),
+        &server_config,
+        shutdown.clone(),
+        tasks.clone(),
+    );
 
     loop {
-        // Use tokio::select! to handle the first available event from either socket
-        tokio::select! {
-            // Handle incoming UDP packets
-            Ok((len, addr)) = udp_socket.recv_from(&mut udp_buf) => {
-                let data = udp_buf[..len].to_vec();
-                let resolver_clone = resolver.clone();
-                let udp_socket_clone = udp_socket.clone();
-
-                tokio::spawn(async move {
-                    if let Some(response_bytes) = handle_request(data, addr, resolver_clone).await {
-                        if let Err(e) = udp_socket_clone.send_to(&response_bytes, addr).await {
-                            log(LogLevel::Error, &format!("Failed to send UDP response to {}: {}", addr, e));
-                        }
-                    }
-                });
-            },
+        let accepted = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = tcp_listener.accept() => accepted,
+        };
 
-            // Handle incoming TCP connections
-            Ok((stream, addr)) = tcp_listener.accept() => {
-                let resolver_clone = resolver.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = handle_tcp_connection(stream, addr, resolver_clone).await {
-                        log(LogLevel::Warn, &format!("TCP connection error from {}: {}", addr, e));
-                    }
-                });
-            },
+        // Handle incoming TCP connections
+        if let Ok((stream, addr)) = accepted {
+            // Over the limit: dropping the stream closes it right away.
+            let Ok(guard) = limits.try_accept_tcp(addr.ip()) else {
+                continue;
+            };
+            let resolver_clone = resolver.clone();
+            let limits_clone = limits.clone();
+            tasks.spawn(async move {
+                if let Err(e) =
+                    handle_tcp_connection(stream, addr, resolver_clone, &limits_clone).await
+                {
+                    warn!(
+                        "TCP connection error from {}: {}",
+                        privacy::display(addr),
+                        e
+                    );
+                }
+                drop(guard);
+            });
         }
     }
+
+    SERVER.listening.store(false, Ordering::Relaxed);
+    info!("Stopped accepting new queries.");
+    Ok(())
 }
 
 /// Handles a single TCP connection, including message framing.
@@ -65,7 +101,46 @@ async fn handle_tcp_connection(
     mut stream: TcpStream,
     addr: SocketAddr,
     resolver: Arc<DnsResolver>,
+    limits: &Limits,
 ) -> io::Result<()> {
+    // The whole message must arrive within the read timeout, so a client
+    // trickling bytes cannot hold the connection open.
+    let req_buf = match timeout(limits.read_timeout(), read_tcp_message(&mut stream)).await {
+        Ok(result) => result?,
+        Err(_) => {
+            stats::incr(&SERVER.tcp_timeouts);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+        }
+    };
+
+    // Process the request using the same shared handler
+    let response = match limits.try_begin_query() {
+        Some(guard) => {
+            let response = handle_request(&req_buf, addr, Transport::Tcp, resolver).await;
+            drop(guard);
+            response
+        }
+        None => shed_response(&req_buf, limits.overload_policy()),
+    };
+
+    if let Some(res_buf) = response {
+        // Prepend the response with its 2-byte length and send it back
+        let res_len = res_buf.len() as u16;
+        let write = async {
+            stream.write_all(&res_len.to_be_bytes()).await?;
+            stream.write_all(&res_buf).await
+        };
+        if timeout(limits.write_timeout(), write).await.is_err() {
+            stats::incr(&SERVER.tcp_timeouts);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out"));
+        }
+    }
+    // The stream is dropped here, closing the connection.
+    Ok(())
+}
+
+/// Reads one length-prefixed DNS message from a TCP stream.
+async fn read_tcp_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
     // DNS over TCP messages are prefixed with a 2-byte length field
     let mut len_buf = [0u8; 2];
     stream.read_exact(&mut len_buf).await?;
@@ -74,54 +149,241 @@ async fn handle_tcp_connection(
     // Read the full DNS query message
     let mut req_buf = vec![0u8; len];
     stream.read_exact(&mut req_buf).await?;
+    Ok(req_buf)
+}
 
-    // Process the request using the same shared handler
-    if let Some(res_buf) = handle_request(req_buf, addr, resolver).await {
-        // Prepend the response with its 2-byte length and send it back
-        let res_len = res_buf.len() as u16;
-        stream.write_all(&res_len.to_be_bytes()).await?;
-        stream.write_all(&res_buf).await?;
+/// Answers a query that was shed under load, according to the overload policy.
+pub fn shed_response(data: &[u8], policy: OverloadPolicy) -> Option<Vec<u8>> {
+    if policy == OverloadPolicy::Drop {
+        return None;
     }
-    // The stream is dropped here, closing the connection.
-    Ok(())
+    let request = Message::from_bytes(data).ok()?;
+    if request.message_type() != MessageType::Query {
+        return None;
+    }
+    let mut response = Message::new();
+    response.set_header(Header::response_from_request(request.header()));
+    response.add_queries(request.queries().iter().cloned());
+    response.set_response_code(ResponseCode::Refused);
+    response.to_bytes().ok()
+}
+
+/// The listener a query arrived on.
+#[derive(Debug, Clone, Copy)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+/// What happened to a query, for metrics and the query log.
+#[derive(Default)]
+struct Outcome {
+    /// Set once the message is known to be a query.
+    is_query: bool,
+    qname: Option<String>,
+    qtype: Option<RecordType>,
+    zone: Option<String>,
+    country: Option<String>,
+    /// `None` when no response was sent.
+    rcode: Option<ResponseCode>,
+    answers: Option<Arc<[LoggedAnswer]>>,
+    cached: bool,
 }
 
 /// The core request handler, protocol-agnostic.
-async fn handle_request(
-    data: Vec<u8>,
+pub async fn handle_request(
+    data: &[u8],
     addr: SocketAddr,
+    transport: Transport,
     resolver: Arc<DnsResolver>,
 ) -> Option<Vec<u8>> {
-    let request = match Message::from_bytes(&data) {
-        Ok(req) => req,
-        Err(e) => {
-            log(
-                LogLevel::Warn,
-                &format!("Failed to parse request from {}: {}", addr, e),
+    let started = Instant::now();
+    let received_at = SystemTime::now();
+    let mut outcome = Outcome::default();
+    let response = match Message::from_bytes(data) {
+        Ok(request) => {
+            // `zone` and `country` are recorded once the query is routed.
+            let query = request.queries().first();
+            let span = debug_span!(
+                "request",
+                id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+                client = %privacy::display_ip(addr.ip()),
+                transport = transport.as_str(),
+                qname = query.map(|query| field::display(query.name())),
+                qtype = query.map(|query| field::display(query.query_type())),
+                zone = field::Empty,
+                country = field::Empty,
             );
-            return None;
+            answer(&request, data, addr, &resolver, &mut outcome)
+                .instrument(span)
+                .await
+        }
+        Err(e) => {
+            warn!(client = %privacy::display(addr), error = %e, "Failed to parse request");
+            None
         }
     };
 
+    if let Some(dnstap) = resolver.dnstap() {
+        let zone = outcome
+            .zone
+            .as_deref()
+            .and_then(|zone| Name::from_ascii(zone).ok())
+            .and_then(|zone| zone.to_bytes().ok());
+        dnstap.record(&Exchange {
+            client: addr,
+            transport,
+            query: data,
+            query_time: received_at,
+            response: response.as_deref(),
+            response_time: SystemTime::now(),
+            zone: zone.as_deref(),
+        });
+    }
+
+    if outcome.is_query {
+        let elapsed = started.elapsed();
+        metrics::REQUEST_DURATION.observe(elapsed);
+        let rcode = outcome.rcode.map_or("DROPPED", rcode_label);
+        metrics::QUERIES.inc(&[
+            outcome.zone.as_deref().unwrap_or(""),
+            outcome.qtype.map_or("none", qtype_label),
+            rcode,
+            transport.as_str(),
+        ]);
+
+        if let Some(query_log) = resolver.query_log()
+            && query_log.wants(&resolver.config().query_log, outcome.zone.as_deref())
+        {
+            query_log.record(&QueryLogEntry {
+                client: privacy::client(addr.ip()),
+                transport: transport.as_str(),
+                qname: outcome.qname.as_deref().unwrap_or(""),
+                qtype: outcome
+                    .qtype
+                    .map_or_else(String::new, |qtype| qtype.to_string()),
+                rcode,
+                answers: outcome.answers.as_deref().unwrap_or_default(),
+                zone: outcome.zone.as_deref(),
+                country: outcome.country.as_deref(),
+                latency_us: elapsed.as_micros() as u64,
+                cached: outcome.cached,
+            });
+        }
+    }
+    response
+}
+
+/// Query types reported as themselves in metrics; anything else is "other",
+/// so clients cannot create unbounded label values.
+fn qtype_label(qtype: RecordType) -> &'static str {
+    match qtype {
+        RecordType::A => "A",
+        RecordType::AAAA => "AAAA",
+        RecordType::CNAME => "CNAME",
+        RecordType::MX => "MX",
+        RecordType::TXT => "TXT",
+        RecordType::NS => "NS",
+        RecordType::SOA => "SOA",
+        RecordType::ANY => "ANY",
+        RecordType::PTR => "PTR",
+        RecordType::SRV => "SRV",
+        RecordType::CAA => "CAA",
+        RecordType::HTTPS => "HTTPS",
+        RecordType::SVCB => "SVCB",
+        RecordType::DS => "DS",
+        RecordType::DNSKEY => "DNSKEY",
+        _ => "other",
+    }
+}
+
+fn rcode_label(rcode: ResponseCode) -> &'static str {
+    match rcode {
+        ResponseCode::NoError => "NOERROR",
+        ResponseCode::FormErr => "FORMERR",
+        ResponseCode::ServFail => "SERVFAIL",
+        ResponseCode::NXDomain => "NXDOMAIN",
+        ResponseCode::NotImp => "NOTIMP",
+        ResponseCode::Refused => "REFUSED",
+        _ => "other",
+    }
+}
+
+async fn answer(
+    request: &Message,
+    data: &[u8],
+    addr: SocketAddr,
+    resolver: &DnsResolver,
+    outcome: &mut Outcome,
+) -> Option<Vec<u8>> {
     if request.message_type() != MessageType::Query || request.op_code() != OpCode::Query {
         return None;
     }
+    stats::incr(&SERVER.queries);
+    outcome.is_query = true;
 
-    let mut response = Message::from(request.clone());
-    response.set_message_type(MessageType::Response);
+    let mut response = Message::new();
+    response.set_header(Header::response_from_request(request.header()));
     response.set_authoritative(true);
+    if let Some(edns) = request.extensions() {
+        let mut response_edns = Edns::new();
+        response_edns.set_max_payload(EDNS_MAX_PAYLOAD);
+        response_edns.set_dnssec_ok(edns.flags().dnssec_ok);
+        response.set_edns(response_edns);
+    }
 
     let query = match request.queries().first() {
         Some(q) => q,
         None => {
             response.set_response_code(ResponseCode::FormErr);
+            outcome.rcode = Some(ResponseCode::FormErr);
             return response.to_bytes().ok();
         }
     };
+    response.add_query(query.clone());
+    outcome.qname = Some(query.name().to_string());
+    outcome.qtype = Some(query.query_type());
+
+    // Answers for names without GeoIP data are the same for every client, so
+    // the cache is checked before routing, which for other names needs the
+    // client's location.
+    let edns = request.extensions().as_ref();
+    let geo_dependent = resolver.is_geo_dependent(query);
+    if !geo_dependent && let Some(cached) = resolver.cache().get(&CacheKey::new(query, edns, None))
+    {
+        let _entered = record_route(outcome, cached.zone.as_deref(), None);
+        return Some(served_from_cache(&cached, data, outcome));
+    }
+
+    let cache_generation = resolver.cache().generation();
+    let route = resolver.route(query, addr.ip()).await;
+    let _entered = record_route(outcome, route.zone(), route.geo_bucket());
+    if route.is_servfail() {
+        response.set_response_code(ResponseCode::ServFail);
+        outcome.rcode = Some(ResponseCode::ServFail);
+        debug!(cached = false, answer = "-> SERVFAIL", "inquiry");
+        return response.to_bytes().ok();
+    }
 
-    let answers = resolver.resolve(query, addr.ip()).await;
+    let cache_key = CacheKey::new(query, edns, route.geo_bucket());
 
-    if answers.is_empty() {
+    if geo_dependent && let Some(cached) = resolver.cache().get(&cache_key) {
+        return Some(served_from_cache(&cached, data, outcome));
+    }
+
+    let answers = route.answers(query.query_type());
+    let logged: Arc<[LoggedAnswer]> = answers.iter().map(LoggedAnswer::from).collect();
+
+    let summary = if answers.is_empty() {
         match resolver.config().unconfigured_policy {
             UnconfiguredPolicy::Drop => {
                 return None;
@@ -137,29 +399,63 @@ async fn handle_request(
                 }
             }
         }
-        log(
-            LogLevel::Info,
-            &format!(
-                "{} inquiry {} -> {}",
-                addr.ip(),
-                query.name(),
-                response.response_code()
-            ),
-        );
+        format!("-> {}", response.response_code())
     } else {
-        let records_str = format_records(&answers);
-        log(
-            LogLevel::Info,
-            &format!("{} inquiry {} get {}", addr.ip(), query.name(), records_str),
+        let summary = format!("get {}", format_records(&answers));
+        response.add_answers(answers);
+        response.set_response_code(ResponseCode::NoError);
+        summary
+    };
+
+    debug!(cached = false, answer = %summary, "inquiry");
+
+    let bytes = response.to_bytes().ok()?;
+    outcome.rcode = Some(response.response_code());
+    outcome.answers = Some(logged.clone());
+    if route.is_cacheable(query.query_type()) {
+        resolver.cache().insert(
+            cache_key,
+            CachedResponse {
+                bytes: bytes.clone(),
+                zone: outcome.zone.clone(),
+                summary,
+                rcode: response.response_code(),
+                answers: logged,
+            },
+            cache_generation,
         );
+    }
+    Some(bytes)
+}
 
-        for answer in answers {
-            response.add_answer(answer);
-        }
-        response.set_response_code(ResponseCode::NoError);
+/// Sets the zone and GeoIP bucket of the outcome and records them on the
+/// request span.
+fn record_route(
+    outcome: &mut Outcome,
+    zone: Option<&str>,
+    country: Option<&str>,
+) -> tracing::span::EnteredSpan {
+    outcome.zone = zone.map(str::to_string);
+    outcome.country = country.map(str::to_string);
+    let span = Span::current();
+    if let Some(zone) = zone {
+        span.record("zone", zone);
     }
+    if let Some(country) = country {
+        span.record("country", country);
+    }
+    // Log filters look at span fields when the span is entered, so enter it
+    // again for filters such as `[request{zone=example.com}]` to see the
+    // fields just recorded. Nothing is awaited while it is entered.
+    span.entered()
+}
 
-    response.to_bytes().ok()
+fn served_from_cache(cached: &CachedResponse, request: &[u8], outcome: &mut Outcome) -> Vec<u8> {
+    outcome.rcode = Some(cached.rcode);
+    outcome.answers = Some(cached.answers.clone());
+    outcome.cached = true;
+    debug!(cached = true, answer = %cached.summary, "inquiry");
+    cache::patch_response(&cached.bytes, request)
 }
 
 /// Helper function to format DNS records into a concise string for logging.
@@ -198,3 +494,103 @@ fn format_records(records: &[Record]) -> String {
         .collect::<Vec<_>>()
         .join(" ")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, GeoIpCacheConfig};
+    use crate::geo_provider::FakeProvider;
+    use crate::geoip::GeoIpClient;
+    use crate::location::GeoGroups;
+    use crate::records::ZoneConfig;
+    use crate::zone::CompiledZone;
+    use hickory_proto::op::Query;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    const ZONE: &str = r#"
+        [www]
+        a = ["192.0.2.1"]
+
+        [geo]
+        a = ["192.0.2.2"]
+
+        [geo.country]
+        US = { a = ["192.0.2.3"] }
+    "#;
+
+    fn resolver(geoip: Arc<GeoIpClient>) -> Arc<DnsResolver> {
+        let zone: ZoneConfig = toml::from_str(ZONE).unwrap();
+        let zone = CompiledZone::compile("example.com", &zone, 5, &GeoGroups::default()).unwrap();
+        let config =
+            AppConfig::with_zones(HashMap::from([("example.com".to_string(), Arc::new(zone))]));
+        Arc::new(DnsResolver::new(Arc::new(config), geoip, None, None))
+    }
+
+    fn request(id: u16, name: &str) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_id(id)
+            .add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        message.to_vec().unwrap()
+    }
+
+    async fn resolve(resolver: &Arc<DnsResolver>, id: u16, name: &str) -> Message {
+        let client: SocketAddr = "203.0.113.1:5353".parse().unwrap();
+        let response = handle_request(&request(id, name), client, Transport::Udp, resolver.clone())
+            .await
+            .unwrap();
+        Message::from_vec(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn cache_hits_for_names_without_geoip_data_skip_geoip() {
+        let provider = Arc::new(FakeProvider::new([]));
+        // Without a GeoIP cache, every lookup reaches the provider.
+        let cache = GeoIpCacheConfig {
+            enabled: false,
+            ..GeoIpCacheConfig::default()
+        };
+        let geoip = Arc::new(GeoIpClient::new(provider.clone(), &cache));
+        let resolver = resolver(geoip.clone());
+
+        for id in 1..=3 {
+            let response = resolve(&resolver, id, "www.example.com.").await;
+            assert_eq!(response.id(), id);
+            assert_eq!(
+                response.answers()[0].data(),
+                &RData::A("192.0.2.1".parse().unwrap())
+            );
+        }
+        let key = CacheKey::new(
+            &Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A),
+            None,
+            None,
+        );
+        assert!(resolver.cache().get(&key).is_some());
+        assert_eq!(provider.lookups(), 0);
+
+        // Names with GeoIP data are looked up on every query, cached or not.
+        for id in 1..=2 {
+            let response = resolve(&resolver, id, "geo.example.com.").await;
+            assert_eq!(
+                response.answers()[0].data(),
+                &RData::A("192.0.2.2".parse().unwrap())
+            );
+        }
+        assert_eq!(provider.lookups(), 2);
+    }
+
+    #[test]
+    fn shed_queries_are_refused_or_dropped_by_policy() {
+        let data = request(7, "www.example.com.");
+        assert!(shed_response(&data, OverloadPolicy::Drop).is_none());
+
+        let response = shed_response(&data, OverloadPolicy::Refused).unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 7);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert_eq!(response.queries().len(), 1);
+    }
+}
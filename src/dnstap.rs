@@ -0,0 +1,680 @@
+/* src/dnstap.rs */
+
+use crate::config::DnstapConfig;
+use crate::dns_server::Transport;
+use crate::privacy;
+use crate::stats::{self, SERVER};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Frames buffered for the writer before new ones are dropped.
+const QUEUE_CAPACITY: usize = 65_536;
+/// Frame Streams content type for dnstap.
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+/// Reconnect delays for socket outputs grow up to this.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long the collector has to answer a handshake or STOP.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Frame Streams control frame types and fields.
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+// dnstap.proto enum values.
+const DNSTAP_MESSAGE: u64 = 1;
+const AUTH_QUERY: u64 = 1;
+const AUTH_RESPONSE: u64 = 2;
+const FAMILY_INET: u64 = 1;
+const FAMILY_INET6: u64 = 2;
+const PROTOCOL_UDP: u64 = 1;
+const PROTOCOL_TCP: u64 = 2;
+
+/// Where dnstap frames go.
+#[derive(Debug, Clone)]
+enum Output {
+    Unix(PathBuf),
+    Tcp(String),
+    File(PathBuf),
+}
+
+impl Output {
+    fn parse(output: &str) -> Result<Self, String> {
+        match output.split_once(':') {
+            Some(("unix", path)) => Ok(Output::Unix(PathBuf::from(path))),
+            Some(("tcp", addr)) => Ok(Output::Tcp(addr.to_string())),
+            Some(("file", path)) => Ok(Output::File(PathBuf::from(path))),
+            _ => Err(format!(
+                "invalid dnstap output '{}', expected unix:<path>, tcp:<host:port> or file:<path>",
+                output
+            )),
+        }
+    }
+}
+
+enum Command {
+    Frame(Vec<u8>),
+    Stop,
+}
+
+/// Handle to the dnstap writer. Messages are encoded on the calling task and
+/// handed to a dedicated thread, which owns the connection and reconnects as
+/// needed. When the queue is full, frames are dropped and counted.
+pub struct Dnstap {
+    sender: SyncSender<Command>,
+    done: parking_lot::Mutex<Option<Receiver<()>>>,
+    identity: Vec<u8>,
+    version: Vec<u8>,
+    queries: bool,
+    responses: bool,
+}
+
+/// One query and its response, as seen by `handle_request`.
+pub struct Exchange<'a> {
+    pub client: SocketAddr,
+    pub transport: Transport,
+    pub query: &'a [u8],
+    pub query_time: SystemTime,
+    pub response: Option<&'a [u8]>,
+    pub response_time: SystemTime,
+    /// The zone in wire format, if the query fell in one.
+    pub zone: Option<&'a [u8]>,
+}
+
+impl Dnstap {
+    pub fn start(config: &DnstapConfig) -> Result<Self, String> {
+        let output = Output::parse(&config.output)?;
+        // A file must be writable up front; sockets may come up later.
+        let initial = match &output {
+            Output::File(path) => {
+                Some(Connection::connect(&output).map_err(|e| format!("{:?}: {}", path, e))?)
+            }
+            _ => None,
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        let description = format!("{:?}", output);
+        std::thread::Builder::new()
+            .name("dnstap".to_string())
+            .spawn(move || {
+                write_loop(output, initial, receiver);
+                let _ = done_tx.send(());
+            })
+            .map_err(|e| e.to_string())?;
+        info!("dnstap enabled, writing to {}", description);
+
+        Ok(Self {
+            sender,
+            done: parking_lot::Mutex::new(Some(done_rx)),
+            identity: config
+                .identity
+                .clone()
+                .unwrap_or_else(hostname)
+                .into_bytes(),
+            version: config.version.clone().into_bytes(),
+            queries: config.queries,
+            responses: config.responses,
+        })
+    }
+
+    /// Emits AUTH_QUERY and AUTH_RESPONSE messages for one exchange.
+    pub fn record(&self, exchange: &Exchange<'_>) {
+        if self.queries {
+            self.send(self.encode(AUTH_QUERY, exchange));
+        }
+        if self.responses && exchange.response.is_some() {
+            self.send(self.encode(AUTH_RESPONSE, exchange));
+        }
+    }
+
+    /// Writes the STOP frame and waits briefly for the writer to finish, so
+    /// files end cleanly and collectors see an orderly close.
+    pub fn close(&self, wait: Duration) {
+        let deadline = Instant::now() + wait;
+        // A full queue must not swallow the STOP, so retry until the writer
+        // has made room or the wait is over.
+        let mut stop = Command::Stop;
+        loop {
+            match self.sender.try_send(stop) {
+                Err(TrySendError::Full(command)) if Instant::now() < deadline => {
+                    stop = command;
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(TrySendError::Full(_)) => return,
+                Ok(()) | Err(TrySendError::Disconnected(_)) => break,
+            }
+        }
+        if let Some(done) = self.done.lock().take() {
+            let _ = done.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    fn send(&self, frame: Vec<u8>) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(Command::Frame(frame)) {
+            stats::incr(&SERVER.dnstap_dropped);
+        }
+    }
+
+    fn encode(&self, kind: u64, exchange: &Exchange<'_>) -> Vec<u8> {
+        let mut message = Vec::with_capacity(64 + exchange.query.len());
+        put_varint_field(&mut message, 1, kind);
+        let family = match exchange.client {
+            SocketAddr::V4(_) => FAMILY_INET,
+            SocketAddr::V6(_) => FAMILY_INET6,
+        };
+        put_varint_field(&mut message, 2, family);
+        let protocol = match exchange.transport {
+            Transport::Udp => PROTOCOL_UDP,
+            Transport::Tcp => PROTOCOL_TCP,
+        };
+        put_varint_field(&mut message, 3, protocol);
+        match privacy::export_address(exchange.client.ip()) {
+            Some(IpAddr::V4(ip)) => put_bytes_field(&mut message, 4, &ip.octets()),
+            Some(IpAddr::V6(ip)) => put_bytes_field(&mut message, 4, &ip.octets()),
+            None => {}
+        }
+        if privacy::exports_port() {
+            put_varint_field(&mut message, 6, u64::from(exchange.client.port()));
+        }
+        let (secs, nanos) = unix_time(exchange.query_time);
+        put_varint_field(&mut message, 8, secs);
+        put_fixed32_field(&mut message, 9, nanos);
+        if kind == AUTH_QUERY {
+            put_bytes_field(&mut message, 10, exchange.query);
+        }
+        if let Some(zone) = exchange.zone {
+            put_bytes_field(&mut message, 11, zone);
+        }
+        if kind == AUTH_RESPONSE {
+            let (secs, nanos) = unix_time(exchange.response_time);
+            put_varint_field(&mut message, 12, secs);
+            put_fixed32_field(&mut message, 13, nanos);
+            if let Some(response) = exchange.response {
+                put_bytes_field(&mut message, 14, response);
+            }
+        }
+
+        let mut frame = Vec::with_capacity(message.len() + 64);
+        put_bytes_field(&mut frame, 1, &self.identity);
+        put_bytes_field(&mut frame, 2, &self.version);
+        put_bytes_field(&mut frame, 14, &message);
+        put_varint_field(&mut frame, 15, DNSTAP_MESSAGE);
+        frame
+    }
+}
+
+enum Connection {
+    File(BufWriter<File>),
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    /// Connects to a collector and completes the bidirectional handshake.
+    fn connect(output: &Output) -> io::Result<Self> {
+        let mut connection = match output {
+            Output::Unix(path) => Connection::Unix(UnixStream::connect(path)?),
+            Output::Tcp(addr) => Connection::Tcp(TcpStream::connect(addr)?),
+            Output::File(path) => Connection::File(BufWriter::new(File::create(path)?)),
+        };
+        connection.handshake()?;
+        Ok(connection)
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        if !matches!(self, Connection::File(_)) {
+            self.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            self.write_all(&control_frame(CONTROL_READY, true))?;
+            self.expect_control(CONTROL_ACCEPT)?;
+            self.set_read_timeout(None)?;
+        }
+        self.write_all(&control_frame(CONTROL_START, true))?;
+        self.flush()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.write_all(&control_frame(CONTROL_STOP, false))?;
+        self.flush()?;
+        if !matches!(self, Connection::File(_)) {
+            self.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            self.expect_control(CONTROL_FINISH)?;
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.write_all(&(frame.len() as u32).to_be_bytes())?;
+        self.write_all(frame)
+    }
+
+    fn expect_control(&mut self, expected: u32) -> io::Result<()> {
+        let mut header = [0u8; 8];
+        self.read_exact(&mut header)?;
+        let escape = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if escape != 0 || !(4..=512).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a Frame Streams control frame",
+            ));
+        }
+        let mut body = vec![0u8; len];
+        self.read_exact(&mut body)?;
+        let control = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+        if control != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected control frame {}, got {}", expected, control),
+            ));
+        }
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::File(_) => Ok(()),
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Connection::File(file) => file.write_all(bytes),
+            Connection::Unix(stream) => stream.write_all(bytes),
+            Connection::Tcp(stream) => stream.write_all(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::File(file) => file.flush(),
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Tcp(stream) => stream.flush(),
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            Connection::File(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "files are write-only",
+            )),
+            Connection::Unix(stream) => stream.read_exact(buf),
+            Connection::Tcp(stream) => stream.read_exact(buf),
+        }
+    }
+}
+
+fn write_loop(output: Output, initial: Option<Connection>, receiver: Receiver<Command>) {
+    let mut connection = initial;
+    // Only the first failure of an outage is logged.
+    let mut reported = false;
+    let mut backoff = Duration::from_secs(1);
+
+    loop {
+        if connection.is_none() {
+            match Connection::connect(&output) {
+                Ok(connected) => {
+                    info!("dnstap connected to {:?}", output);
+                    connection = Some(connected);
+                    reported = false;
+                    backoff = Duration::from_secs(1);
+                }
+                Err(e) => {
+                    if !reported {
+                        warn!("dnstap cannot reach {:?}: {}", output, e);
+                        reported = true;
+                    }
+                    // Keep draining so the queue does not hold stale frames.
+                    match receiver.recv_timeout(backoff) {
+                        Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                        Ok(Command::Frame(_)) => {
+                            stats::incr(&SERVER.dnstap_dropped);
+                            while let Ok(Command::Frame(_)) = receiver.try_recv() {
+                                stats::incr(&SERVER.dnstap_dropped);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            }
+        }
+        let Some(conn) = connection.as_mut() else {
+            continue;
+        };
+
+        let first = match receiver.recv() {
+            Ok(command) => command,
+            Err(_) => return,
+        };
+        let mut stop = false;
+        let mut result = Ok(());
+        for command in std::iter::once(first).chain(receiver.try_iter()) {
+            match command {
+                Command::Frame(frame) => {
+                    if result.is_ok() {
+                        result = conn.write_frame(&frame);
+                    } else {
+                        stats::incr(&SERVER.dnstap_dropped);
+                    }
+                }
+                Command::Stop => {
+                    stop = true;
+                    break;
+                }
+            }
+        }
+        let result = result.and_then(|()| conn.flush());
+        if stop {
+            if result.is_ok() {
+                let _ = conn.stop();
+            }
+            return;
+        }
+        if let Err(e) = result {
+            warn!("dnstap write to {:?} failed: {}", output, e);
+            connection = None;
+        }
+    }
+}
+
+/// A Frame Streams control frame, with the dnstap content type if asked for.
+fn control_frame(control: u32, with_content_type: bool) -> Vec<u8> {
+    let mut body = control.to_be_bytes().to_vec();
+    if with_content_type {
+        body.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend_from_slice(CONTENT_TYPE);
+    }
+    let mut frame = vec![0u8; 4];
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+fn unix_time(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, and gethostname
+    // writes at most that many bytes.
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if rc != 0 {
+        return "lazy-dns".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// Minimal protobuf encoding, enough for the dnstap schema.
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, value);
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, (field << 3) | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_fixed32_field(buf: &mut Vec<u8>, field: u64, value: u32) {
+    put_varint(buf, (field << 3) | 5);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lazy-dns-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(output: String) -> DnstapConfig {
+        DnstapConfig {
+            enabled: true,
+            output,
+            identity: Some("ns1".to_string()),
+            ..DnstapConfig::default()
+        }
+    }
+
+    fn exchange<'a>(query: &'a [u8], response: Option<&'a [u8]>) -> Exchange<'a> {
+        Exchange {
+            client: "192.0.2.7:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            query,
+            query_time: UNIX_EPOCH + Duration::new(1_700_000_000, 5),
+            response,
+            response_time: UNIX_EPOCH + Duration::new(1_700_000_001, 0),
+            zone: Some(b"\x07example\x03com\x00"),
+        }
+    }
+
+    /// One Frame Streams frame: a control frame type, or a data frame.
+    #[derive(Debug, PartialEq)]
+    enum Frame {
+        Control(u32, Vec<u8>),
+        Data(Vec<u8>),
+    }
+
+    fn read_frame(reader: &mut impl Read) -> Frame {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).unwrap();
+        let len = u32::from_be_bytes(len) as usize;
+        if len > 0 {
+            let mut data = vec![0u8; len];
+            reader.read_exact(&mut data).unwrap();
+            return Frame::Data(data);
+        }
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).unwrap();
+        let mut body = vec![0u8; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut body).unwrap();
+        let control = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+        Frame::Control(control, body[4..].to_vec())
+    }
+
+    fn content_type_field() -> Vec<u8> {
+        let mut field = FIELD_CONTENT_TYPE.to_be_bytes().to_vec();
+        field.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        field.extend_from_slice(CONTENT_TYPE);
+        field
+    }
+
+    /// Protobuf fields in order, as (number, varint or bytes).
+    fn fields(mut buf: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        fn varint(buf: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            for shift in (0..).step_by(7) {
+                let byte = buf[0];
+                *buf = &buf[1..];
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            value
+        }
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = varint(&mut buf);
+            let value = match key & 7 {
+                0 => varint(&mut buf).to_le_bytes().to_vec(),
+                2 => {
+                    let len = varint(&mut buf) as usize;
+                    let (value, rest) = buf.split_at(len);
+                    buf = rest;
+                    value.to_vec()
+                }
+                5 => {
+                    let (value, rest) = buf.split_at(4);
+                    buf = rest;
+                    value.to_vec()
+                }
+                wire => panic!("unexpected wire type {}", wire),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    fn field(fields: &[(u64, Vec<u8>)], number: u64) -> Option<&[u8]> {
+        fields
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn varint_field(fields: &[(u64, Vec<u8>)], number: u64) -> Option<u64> {
+        field(fields, number).map(|value| u64::from_le_bytes(value.try_into().unwrap()))
+    }
+
+    #[test]
+    fn varints_use_seven_bits_per_byte() {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 1);
+        put_varint(&mut buf, 300);
+        put_varint(&mut buf, u64::from(u32::MAX));
+        assert_eq!(buf, [0x01, 0xac, 0x02, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+    }
+
+    #[test]
+    fn encodes_query_and_response_messages() {
+        let dir = temp_dir("dnstap-encode");
+        let path = dir.join("out.fstrm");
+        let dnstap = Dnstap::start(&config(format!("file:{}", path.display()))).unwrap();
+        let exchange = exchange(b"query", Some(b"response"));
+
+        let frame = fields(&dnstap.encode(AUTH_QUERY, &exchange));
+        assert_eq!(field(&frame, 1), Some(&b"ns1"[..]));
+        assert_eq!(varint_field(&frame, 15), Some(DNSTAP_MESSAGE));
+        let message = fields(field(&frame, 14).unwrap());
+        assert_eq!(varint_field(&message, 1), Some(AUTH_QUERY));
+        assert_eq!(varint_field(&message, 2), Some(FAMILY_INET));
+        assert_eq!(varint_field(&message, 3), Some(PROTOCOL_UDP));
+        assert_eq!(field(&message, 4), Some(&[192, 0, 2, 7][..]));
+        assert_eq!(varint_field(&message, 6), Some(5353));
+        assert_eq!(varint_field(&message, 8), Some(1_700_000_000));
+        assert_eq!(field(&message, 9), Some(&5u32.to_le_bytes()[..]));
+        assert_eq!(field(&message, 10), Some(&b"query"[..]));
+        assert_eq!(field(&message, 11), Some(&b"\x07example\x03com\x00"[..]));
+        assert_eq!(field(&message, 14), None);
+
+        let frame = fields(&dnstap.encode(AUTH_RESPONSE, &exchange));
+        let message = fields(field(&frame, 14).unwrap());
+        assert_eq!(varint_field(&message, 1), Some(AUTH_RESPONSE));
+        assert_eq!(field(&message, 10), None);
+        assert_eq!(varint_field(&message, 12), Some(1_700_000_001));
+        assert_eq!(field(&message, 14), Some(&b"response"[..]));
+
+        dnstap.close(Duration::from_secs(2));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn file_output_is_framed_by_start_and_stop() {
+        let dir = temp_dir("dnstap-file");
+        let path = dir.join("out.fstrm");
+        let dnstap = Dnstap::start(&config(format!("file:{}", path.display()))).unwrap();
+        dnstap.record(&exchange(b"query", Some(b"response")));
+        // A dropped query has no response message.
+        dnstap.record(&exchange(b"dropped", None));
+        dnstap.close(Duration::from_secs(2));
+
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(
+            read_frame(&mut file),
+            Frame::Control(CONTROL_START, content_type_field())
+        );
+        for expected in [AUTH_QUERY, AUTH_RESPONSE, AUTH_QUERY] {
+            let Frame::Data(data) = read_frame(&mut file) else {
+                panic!("expected a data frame");
+            };
+            let message = fields(field(&fields(&data), 14).unwrap());
+            assert_eq!(varint_field(&message, 1), Some(expected));
+        }
+        assert_eq!(
+            read_frame(&mut file),
+            Frame::Control(CONTROL_STOP, Vec::new())
+        );
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn socket_output_completes_the_bidirectional_handshake() {
+        let dir = temp_dir("dnstap-socket");
+        let path = dir.join("dnstap.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let collector = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(
+                read_frame(&mut stream),
+                Frame::Control(CONTROL_READY, content_type_field())
+            );
+            stream
+                .write_all(&control_frame(CONTROL_ACCEPT, true))
+                .unwrap();
+            assert_eq!(
+                read_frame(&mut stream),
+                Frame::Control(CONTROL_START, content_type_field())
+            );
+            let mut data = 0;
+            loop {
+                match read_frame(&mut stream) {
+                    Frame::Data(_) => data += 1,
+                    Frame::Control(control, _) => {
+                        assert_eq!(control, CONTROL_STOP);
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(&control_frame(CONTROL_FINISH, false))
+                .unwrap();
+            data
+        });
+
+        let dnstap = Dnstap::start(&config(format!("unix:{}", path.display()))).unwrap();
+        // The listener is already bound, so the writer connects at once and
+        // queued frames are written once the handshake completes.
+        dnstap.record(&exchange(b"query", Some(b"response")));
+        dnstap.close(Duration::from_secs(5));
+
+        assert_eq!(collector.join().unwrap(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
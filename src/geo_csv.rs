@@ -0,0 +1,265 @@
+/* src/geo_csv.rs */
+
+use crate::cidr::{Cidr, CidrTable};
+use crate::geo_provider::{GeoProvider, Lookup, ProviderStatus, ReloadHook, spawn_file_watcher};
+use crate::location::{Location, LocationKey};
+use async_trait::async_trait;
+use parking_lot::RwLock;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// A static table of `network,location` lines, e.g. `203.0.113.0/24,JP` or
+/// `198.51.100.0/24,US-CA`, matched by longest prefix and read again when the
+/// file changes.
+pub struct CsvProvider {
+    path: PathBuf,
+    table: RwLock<Option<Arc<CidrTable<Location>>>>,
+}
+
+impl CsvProvider {
+    /// Reads `path`. If that fails, the error is logged and the provider is
+    /// unavailable until the file can be read.
+    pub fn open(path: PathBuf) -> Self {
+        let provider = Self {
+            path,
+            table: RwLock::new(None),
+        };
+        provider.reload();
+        provider
+    }
+
+    /// Reads the file again, keeping the current table if that fails.
+    /// Returns whether a table was read.
+    fn reload(&self) -> bool {
+        match read_table(&self.path) {
+            Ok((table, invalid)) => {
+                if !invalid.is_empty() {
+                    warn!(
+                        "Ignoring {} invalid line(s) in {:?}, e.g. '{}'",
+                        invalid.len(),
+                        self.path,
+                        invalid[0]
+                    );
+                }
+                info!(
+                    "Loaded GeoIP table {:?} ({} networks)",
+                    self.path,
+                    table.len()
+                );
+                *self.table.write() = Some(Arc::new(table));
+                true
+            }
+            Err(e) => {
+                if self.is_available() {
+                    warn!(
+                        "Failed to read GeoIP table {:?}, keeping the previous one: {}",
+                        self.path, e
+                    );
+                } else {
+                    warn!(
+                        "Failed to read GeoIP table {:?}, provider unavailable: {}",
+                        self.path, e
+                    );
+                }
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl GeoProvider for CsvProvider {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    fn is_available(&self) -> bool {
+        self.table.read().is_some()
+    }
+
+    async fn lookup(&self, ip: IpAddr) -> Lookup {
+        let Some(table) = self.table.read().clone() else {
+            return Lookup::Unavailable;
+        };
+        match table.lookup(ip) {
+            Some((_, location)) => Lookup::Hit(location.clone()),
+            None => Lookup::Miss,
+        }
+    }
+
+    fn status(&self) -> Vec<ProviderStatus> {
+        let table = self.table.read().clone();
+        vec![ProviderStatus {
+            name: self.name(),
+            source: self.path.display().to_string(),
+            available: table.is_some(),
+            detail: table.map(|table| format!("{} networks", table.len())),
+        }]
+    }
+
+    fn start(self: Arc<Self>, shutdown: CancellationToken, reloaded: ReloadHook) {
+        let path = self.path.clone();
+        let provider = self.clone();
+        let reload = move || {
+            if provider.reload() {
+                reloaded();
+            }
+        };
+        if let Err(e) = spawn_file_watcher(path, shutdown, reload) {
+            warn!(
+                "Failed to watch {:?} for changes, GeoIP table will not be reloaded: {}",
+                self.path, e
+            );
+        }
+    }
+}
+
+/// Parses the table: one `network,location` pair per line, where the location
+/// is written like a zone's `country` key, `#` starts a comment, and a
+/// `network,...` header is allowed. Returns the table and
+/// the lines that could not be parsed.
+fn read_table(path: &Path) -> io::Result<(CidrTable<Location>, Vec<String>)> {
+    let content = fs::read_to_string(path)?;
+    let mut table = CidrTable::default();
+    let mut invalid = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split(',').map(str::trim);
+        let (network, location) = (fields.next().unwrap_or_default(), fields.next());
+        if network.eq_ignore_ascii_case("network") {
+            continue;
+        }
+        match (
+            network.parse::<Cidr>(),
+            location.map(str::parse::<LocationKey>),
+        ) {
+            (Ok(network), Some(Ok(key))) => {
+                table.insert(network, Location::from(&key));
+            }
+            _ => invalid.push(line.to_string()),
+        }
+    }
+    Ok((table, invalid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lazy-dns-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes `data` next to `path` and renames it over `path`.
+    fn replace(path: &Path, data: &str) {
+        let staged = path.with_extension("tmp");
+        fs::write(&staged, data).unwrap();
+        fs::rename(&staged, path).unwrap();
+    }
+
+    async fn lookup(provider: &CsvProvider, ip: &str) -> Lookup {
+        provider.lookup(ip.parse().unwrap()).await
+    }
+
+    fn hit(key: &str) -> Lookup {
+        Lookup::Hit(Location::from(&key.parse::<LocationKey>().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn reads_networks_by_longest_prefix() {
+        let dir = temp_dir("csv-parse");
+        let path = dir.join("geo.csv");
+        fs::write(
+            &path,
+            "network,country\n\
+             # documentation ranges\n\
+             203.0.113.0/24, jp\n\
+             203.0.113.128/25,KR  # more specific\n\
+             2001:db8::/32,DE\n\
+             198.18.0.0/15,us-ca\n\
+             198.19.0.0/16,city:US/Los Angeles\n\
+             \n\
+             198.51.100.0/24\n\
+             not-a-network,US\n\
+             192.0.2.0/24,\n",
+        )
+        .unwrap();
+
+        let (table, invalid) = read_table(&path).unwrap();
+        assert_eq!(table.len(), 5);
+        assert_eq!(
+            invalid,
+            ["198.51.100.0/24", "not-a-network,US", "192.0.2.0/24,"]
+        );
+
+        let provider = CsvProvider::open(path);
+        assert!(provider.is_available());
+        assert_eq!(provider.status()[0].detail.as_deref(), Some("5 networks"));
+        assert_eq!(lookup(&provider, "203.0.113.7").await, hit("JP"));
+        assert_eq!(lookup(&provider, "203.0.113.200").await, hit("KR"));
+        assert_eq!(lookup(&provider, "2001:db8::1").await, hit("DE"));
+        assert_eq!(lookup(&provider, "198.18.0.1").await, hit("US-CA"));
+        assert_eq!(
+            lookup(&provider, "198.19.0.1").await,
+            hit("city:US/Los Angeles")
+        );
+        assert_eq!(lookup(&provider, "198.51.100.1").await, Lookup::Miss);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn missing_files_leave_the_provider_unavailable() {
+        let dir = temp_dir("csv-missing");
+        let provider = CsvProvider::open(dir.join("geo.csv"));
+        assert!(!provider.is_available());
+        assert_eq!(lookup(&provider, "203.0.113.7").await, Lookup::Unavailable);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn changes_are_read_again_and_reported() {
+        let dir = temp_dir("csv-watch");
+        let path = dir.join("geo.csv");
+        fs::write(&path, "203.0.113.0/24,JP\n").unwrap();
+        let provider = Arc::new(CsvProvider::open(path.clone()));
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counted = reloads.clone();
+        let shutdown = CancellationToken::new();
+        provider.clone().start(
+            shutdown.clone(),
+            Arc::new(move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+
+        replace(&path, "203.0.113.0/24,FR\n");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reloads.load(Ordering::SeqCst) == 0 {
+            assert!(Instant::now() < deadline, "table not read again");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(lookup(&provider, "203.0.113.7").await, hit("FR"));
+
+        // Removing the file keeps the table and is not a reload.
+        fs::remove_file(&path).unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+        assert_eq!(lookup(&provider, "203.0.113.7").await, hit("FR"));
+        assert!(!provider.reload());
+        shutdown.cancel();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
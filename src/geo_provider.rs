@@ -0,0 +1,404 @@
+/* src/geo_provider.rs */
+
+use crate::config::{AsnProviderKind, GeoIpConfig, GeoProviderKind};
+use crate::geo_csv::CsvProvider;
+use crate::lazy_mmdb::LazyMmdbProvider;
+use crate::location::Location;
+use crate::mmdb::MmdbProvider;
+use async_trait::async_trait;
+use notify::{Event, RecursiveMode, Watcher};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+/// Quiet period after the last event on a watched file before it is read
+/// again, so a copy still being written is not picked up halfway.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Outcome of one lookup with a provider.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup {
+    /// Where the address is.
+    Hit(Location),
+    /// Answered, but without a location for the address.
+    Miss,
+    /// The provider cannot answer at all right now.
+    Unavailable,
+    /// No complete answer within the lookup deadline.
+    Timeout,
+    /// A server error or an unreadable answer.
+    Error,
+}
+
+/// Called by a provider after it loaded new data, so that results cached from
+/// the old data can be dropped.
+pub type ReloadHook = Arc<dyn Fn() + Send + Sync>;
+
+/// State of one provider, for `ctl geoip` and the readiness check.
+#[derive(Debug, Clone)]
+pub struct ProviderStatus {
+    pub name: &'static str,
+    /// The socket or file it reads from.
+    pub source: String,
+    pub available: bool,
+    /// E.g. the type and build date of a database.
+    pub detail: Option<String>,
+}
+
+/// A source of client locations.
+#[async_trait]
+pub trait GeoProvider: Send + Sync {
+    /// The provider's name in `[geoip] providers`.
+    fn name(&self) -> &'static str;
+
+    fn is_available(&self) -> bool;
+
+    async fn lookup(&self, ip: IpAddr) -> Lookup;
+
+    /// One entry per provider; a chain lists each of its members.
+    fn status(&self) -> Vec<ProviderStatus>;
+
+    /// Starts whatever keeps the provider current (probing a service,
+    /// watching a file) until `shutdown` is cancelled. `reloaded` is called
+    /// whenever new data replaces what was loaded.
+    fn start(self: Arc<Self>, _shutdown: CancellationToken, _reloaded: ReloadHook) {}
+}
+
+/// Builds the providers listed in `[geoip] providers`, chained in that order,
+/// and those in `asn_providers` alongside them. Files are opened right away,
+/// relative to `base_path`.
+pub fn from_config(config: &GeoIpConfig, base_path: &Path) -> Arc<dyn GeoProvider> {
+    let mut providers: Vec<Arc<dyn GeoProvider>> = Vec::new();
+    for kind in &config.providers {
+        let provider: Arc<dyn GeoProvider> = match kind {
+            GeoProviderKind::LazyMmdb => {
+                Arc::new(LazyMmdbProvider::new(config, &config.lookup_path))
+            }
+            GeoProviderKind::Mmdb => {
+                Arc::new(MmdbProvider::open(base_path.join(&config.mmdb_path)))
+            }
+            GeoProviderKind::Csv => Arc::new(CsvProvider::open(base_path.join(&config.csv_path))),
+            GeoProviderKind::None => continue,
+        };
+        providers.push(provider);
+    }
+    let location = chain(providers);
+
+    let asn_providers: Vec<Arc<dyn GeoProvider>> = config
+        .asn_providers
+        .iter()
+        .map(|kind| -> Arc<dyn GeoProvider> {
+            match kind {
+                AsnProviderKind::LazyMmdb => {
+                    Arc::new(LazyMmdbProvider::new(config, &config.asn_lookup_path))
+                }
+                AsnProviderKind::Mmdb => {
+                    Arc::new(MmdbProvider::open(base_path.join(&config.asn_mmdb_path)))
+                }
+            }
+        })
+        .collect();
+    if asn_providers.is_empty() {
+        location
+    } else {
+        Arc::new(AsnProvider {
+            location,
+            asn: chain(asn_providers),
+        })
+    }
+}
+
+fn chain(mut providers: Vec<Arc<dyn GeoProvider>>) -> Arc<dyn GeoProvider> {
+    match providers.len() {
+        0 => Arc::new(NoopProvider),
+        1 => providers.remove(0),
+        _ => Arc::new(ChainProvider::new(providers)),
+    }
+}
+
+/// Asks the location providers and the ASN providers at the same time and
+/// merges their answers, so `asn` tables work with any location source. The
+/// ASN is only added to a definite answer: when the location lookup is
+/// unavailable, times out or fails, that failure is the result.
+pub struct AsnProvider {
+    location: Arc<dyn GeoProvider>,
+    asn: Arc<dyn GeoProvider>,
+}
+
+#[async_trait]
+impl GeoProvider for AsnProvider {
+    fn name(&self) -> &'static str {
+        "asn"
+    }
+
+    fn is_available(&self) -> bool {
+        self.location.is_available() || self.asn.is_available()
+    }
+
+    async fn lookup(&self, ip: IpAddr) -> Lookup {
+        let (location, asn) = tokio::join!(self.location.lookup(ip), self.asn.lookup(ip));
+        let asn = match asn {
+            Lookup::Hit(found) => found.asn,
+            _ => None,
+        };
+        match (location, asn) {
+            (Lookup::Hit(mut location), Some(asn)) => {
+                location.asn.get_or_insert(asn);
+                Lookup::Hit(location)
+            }
+            (Lookup::Miss, Some(asn)) => Lookup::Hit(Location {
+                asn: Some(asn),
+                ..Location::default()
+            }),
+            (location, _) => location,
+        }
+    }
+
+    fn status(&self) -> Vec<ProviderStatus> {
+        let mut status = self.location.status();
+        status.extend(self.asn.status());
+        status
+    }
+
+    fn start(self: Arc<Self>, shutdown: CancellationToken, reloaded: ReloadHook) {
+        self.location
+            .clone()
+            .start(shutdown.clone(), reloaded.clone());
+        self.asn.clone().start(shutdown, reloaded);
+    }
+}
+
+/// Tries providers in order until one knows the location. If none does, the
+/// result is `Miss` when any of them answered, else the last failure.
+pub struct ChainProvider {
+    providers: Vec<Arc<dyn GeoProvider>>,
+}
+
+impl ChainProvider {
+    pub fn new(providers: Vec<Arc<dyn GeoProvider>>) -> Self {
+        Self { providers }
+    }
+}
+
+#[async_trait]
+impl GeoProvider for ChainProvider {
+    fn name(&self) -> &'static str {
+        "chain"
+    }
+
+    fn is_available(&self) -> bool {
+        self.providers
+            .iter()
+            .any(|provider| provider.is_available())
+    }
+
+    async fn lookup(&self, ip: IpAddr) -> Lookup {
+        let mut result = Lookup::Unavailable;
+        for provider in &self.providers {
+            if !provider.is_available() {
+                continue;
+            }
+            match provider.lookup(ip).await {
+                Lookup::Hit(location) => return Lookup::Hit(location),
+                Lookup::Miss => result = Lookup::Miss,
+                failure if result != Lookup::Miss => {
+                    debug!(
+                        provider = provider.name(),
+                        result = ?failure,
+                        "Trying the next GeoIP provider"
+                    );
+                    result = failure;
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+
+    fn status(&self) -> Vec<ProviderStatus> {
+        self.providers
+            .iter()
+            .flat_map(|provider| provider.status())
+            .collect()
+    }
+
+    fn start(self: Arc<Self>, shutdown: CancellationToken, reloaded: ReloadHook) {
+        for provider in &self.providers {
+            provider.clone().start(shutdown.clone(), reloaded.clone());
+        }
+    }
+}
+
+/// Knows no locations, so every query gets the default records.
+pub struct NoopProvider;
+
+#[async_trait]
+impl GeoProvider for NoopProvider {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    async fn lookup(&self, _ip: IpAddr) -> Lookup {
+        Lookup::Miss
+    }
+
+    fn status(&self) -> Vec<ProviderStatus> {
+        vec![ProviderStatus {
+            name: self.name(),
+            source: "-".to_string(),
+            available: true,
+            detail: None,
+        }]
+    }
+}
+
+/// Answers lookups from a script of per-address results, and `Miss` for any
+/// other address, counting every lookup it gets.
+#[cfg(test)]
+pub struct FakeProvider {
+    answers: std::collections::HashMap<IpAddr, Lookup>,
+    lookups: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl FakeProvider {
+    pub fn new(answers: impl IntoIterator<Item = (IpAddr, Lookup)>) -> Self {
+        Self {
+            answers: answers.into_iter().collect(),
+            lookups: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    pub fn lookups(&self) -> usize {
+        self.lookups.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl GeoProvider for FakeProvider {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    async fn lookup(&self, ip: IpAddr) -> Lookup {
+        self.lookups
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.answers.get(&ip).cloned().unwrap_or(Lookup::Miss)
+    }
+
+    fn status(&self) -> Vec<ProviderStatus> {
+        vec![ProviderStatus {
+            name: self.name(),
+            source: "-".to_string(),
+            available: true,
+            detail: None,
+        }]
+    }
+}
+
+/// Calls `reload` after `path` changes, until `shutdown` is cancelled. The
+/// directory is watched so atomic replaces are noticed; removing the file
+/// does not trigger a reload.
+pub fn spawn_file_watcher<F>(
+    path: PathBuf,
+    shutdown: CancellationToken,
+    reload: F,
+) -> notify::Result<()>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let name = path.file_name().map(|name| name.to_os_string());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event
+            && !event.kind.is_access()
+            && event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == name.as_deref())
+        {
+            let _ = tx.send(());
+        }
+    })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    let reload = Arc::new(reload);
+    tokio::spawn(async move {
+        // Events stop once the watcher is dropped.
+        let _watcher = watcher;
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                event = rx.recv() => if event.is_none() { break },
+            }
+            // Wait until events stop arriving for a full debounce period.
+            while let Ok(event) = timeout(DEBOUNCE, rx.recv()).await {
+                if event.is_none() {
+                    return;
+                }
+            }
+
+            if !path.exists() {
+                debug!(path = ?path, "Watched file removed, keeping what was loaded");
+                continue;
+            }
+            info!("{:?} changed, reloading it...", path);
+            let reload = reload.clone();
+            let _ = tokio::task::spawn_blocking(move || reload()).await;
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn asn_is_only_added_to_definite_answers() {
+        let ip: IpAddr = "203.0.113.1".parse().unwrap();
+        let with_asn = Location {
+            asn: Some(64500),
+            ..Location::default()
+        };
+        let located = Location::from_parts(Some("EU"), Some("DE"), None, None);
+        let cases = [
+            (
+                Lookup::Hit(located.clone()),
+                Lookup::Hit(Location {
+                    asn: Some(64500),
+                    ..located
+                }),
+            ),
+            (Lookup::Miss, Lookup::Hit(with_asn.clone())),
+            (Lookup::Unavailable, Lookup::Unavailable),
+            (Lookup::Timeout, Lookup::Timeout),
+            (Lookup::Error, Lookup::Error),
+        ];
+        for (location, expected) in cases {
+            let provider = AsnProvider {
+                location: Arc::new(FakeProvider::new([(ip, location.clone())])),
+                asn: Arc::new(FakeProvider::new([(ip, Lookup::Hit(with_asn.clone()))])),
+            };
+            assert_eq!(provider.lookup(ip).await, expected, "{:?}", location);
+        }
+    }
+}
@@ -1,130 +1,238 @@
 /* src/geoip.rs */
 
-use fancy_log::{LogLevel, log};
-use serde::Deserialize;
-use std::env;
+use crate::config::GeoIpCacheConfig;
+use crate::geo_provider::{GeoProvider, Lookup, ProviderStatus, ReloadHook};
+use crate::geoip_cache::{GeoIpCache, GeoIpCacheStats, read_prewarm_file};
+use crate::metrics;
+use crate::privacy;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixStream;
-use tokio::sync::Mutex;
-use tokio::time::{Duration, sleep};
+use tokio::time::{Duration, Instant, sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span, debug, debug_span, field, info, warn};
 
-fn get_socket_path() -> String {
-    env::var("GEOIP_SOCKET_PATH").unwrap_or_else(|_| "/tmp/lazy-mmdb/lazy-mmdb.sock".to_string())
-}
-
-#[derive(Debug, Deserialize)]
-struct CountryInfo {
-    #[serde(rename = "iso_code")]
-    iso_code: String,
-}
+/// How often a pending prewarm checks whether a provider is up.
+const PREWARM_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-#[derive(Debug, Deserialize)]
-struct GeoIpResponse {
-    country: CountryInfo,
+/// Front of the GeoIP providers, adding the result cache, metrics and a
+/// tracing span to every lookup.
+pub struct GeoIpClient {
+    provider: Arc<dyn GeoProvider>,
+    cache: Arc<GeoIpCache>,
 }
 
-pub struct GeoIpClient {
-    is_available: Arc<Mutex<bool>>,
+/// Outcome of `GeoIpClient::prewarm`.
+pub struct PrewarmReport {
+    /// Addresses a provider answered for, with or without a location.
+    pub cached: usize,
+    /// Addresses among them that have a location.
+    pub found: usize,
+    /// Addresses skipped because every provider became unavailable.
+    pub skipped: usize,
+    /// Addresses whose lookup timed out or failed.
+    pub failed: usize,
 }
 
 impl GeoIpClient {
-    pub fn new() -> Self {
+    pub fn new(provider: Arc<dyn GeoProvider>, cache: &GeoIpCacheConfig) -> Self {
         Self {
-            is_available: Arc::new(Mutex::new(false)),
+            provider,
+            cache: Arc::new(GeoIpCache::new(cache)),
         }
     }
 
-    pub fn start_reconnect_task(&self) {
-        let is_available = self.is_available.clone();
-        tokio::spawn(async move {
-            let reconnect_secs: u64 = env::var("GEOIP_RECONNECT_SECONDS")
-                .unwrap_or_else(|_| "300".to_string())
-                .parse()
-                .unwrap_or(300);
-            let check_interval = Duration::from_secs(reconnect_secs);
-            let socket_path = get_socket_path();
-
-            loop {
-                let mut current_status = is_available.lock().await;
-                match UnixStream::connect(&socket_path).await {
-                    Ok(_) => {
-                        if !*current_status {
-                            log(
-                                LogLevel::Info,
-                                "GeoIP service is available (connected to lazy-mmdb successfully).",
-                            );
-                            *current_status = true;
-                        }
-                    }
-                    Err(e) => {
-                        if *current_status {
-                            log(
-                                LogLevel::Warn,
-                                "GeoIP service has become unavailable (connection lost).",
-                            );
-                            *current_status = false;
-                        } else {
-                            log(
-                                LogLevel::Warn,
-                                &format!(
-                                    "GeoIP service is unavailable (failed to connect: {}). Retrying in {:?}...",
-                                    e, check_interval
-                                ),
-                            );
-                        }
-                    }
-                }
-
-                drop(current_status);
-                sleep(check_interval).await;
-            }
+    /// Starts the providers' background work until `shutdown` is cancelled.
+    /// The cache is flushed whenever a provider loads new data, so no answer
+    /// from a replaced database outlives it.
+    pub fn start(&self, shutdown: CancellationToken) {
+        let cache = self.cache.clone();
+        let reloaded: ReloadHook = Arc::new(move || {
+            let dropped = cache.clear();
+            info!("GeoIP data reloaded, dropped {} cached result(s)", dropped);
         });
+        self.provider.clone().start(shutdown, reloaded);
+    }
+
+    /// Whether any provider can answer.
+    pub fn is_available(&self) -> bool {
+        self.provider.is_available()
+    }
+
+    /// One entry per configured provider, in lookup order.
+    pub fn providers(&self) -> Vec<ProviderStatus> {
+        self.provider.status()
+    }
+
+    pub fn cache_stats(&self) -> GeoIpCacheStats {
+        self.cache.stats()
+    }
+
+    /// Drops every cached result and returns how many there were.
+    pub fn clear_cache(&self) -> usize {
+        self.cache.clear()
     }
 
-    pub async fn lookup(&self, ip: IpAddr) -> Option<String> {
-        if !*self.is_available.lock().await {
-            return None;
+    /// Looks up `addrs` with the providers and caches the results, without touching
+    /// the lookup metrics. Addresses already cached are looked up again.
+    pub async fn prewarm(&self, addrs: &[IpAddr]) -> PrewarmReport {
+        let mut report = PrewarmReport {
+            cached: 0,
+            found: 0,
+            skipped: 0,
+            failed: 0,
+        };
+        for (i, ip) in addrs.iter().enumerate() {
+            if !self.is_available() {
+                report.skipped = addrs.len() - i;
+                break;
+            }
+            match self.provider.lookup(*ip).await {
+                Lookup::Hit(location) => {
+                    self.cache.insert(*ip, Some(location));
+                    report.cached += 1;
+                    report.found += 1;
+                }
+                Lookup::Miss => {
+                    self.cache.insert(*ip, None);
+                    report.cached += 1;
+                }
+                Lookup::Unavailable => {
+                    report.skipped = addrs.len() - i;
+                    break;
+                }
+                Lookup::Timeout | Lookup::Error => report.failed += 1,
+            }
         }
+        report
+    }
 
-        let socket_path = get_socket_path();
-        let mut stream = match UnixStream::connect(&socket_path).await {
-            Ok(s) => s,
-            Err(_) => {
-                let mut avail = self.is_available.lock().await;
-                if *avail {
-                    log(
-                        LogLevel::Warn,
-                        "Failed a lookup connection to lazy-mmdb. Marking as unavailable.",
-                    );
-                    *avail = false;
+    /// Prewarms the cache from `path` once a provider is available, unless
+    /// `shutdown` is cancelled first.
+    pub fn spawn_prewarm(self: &Arc<Self>, path: PathBuf, shutdown: CancellationToken) {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let addrs = match read_prewarm_file(&path) {
+                Ok((addrs, invalid)) => {
+                    if !invalid.is_empty() {
+                        warn!(
+                            "Ignoring {} invalid address(es) in {:?}, e.g. '{}'",
+                            invalid.len(),
+                            path,
+                            invalid[0]
+                        );
+                    }
+                    addrs
+                }
+                Err(e) => {
+                    warn!("Failed to read GeoIP prewarm file {:?}: {}", path, e);
+                    return;
+                }
+            };
+            while !client.is_available() {
+                tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    _ = sleep(PREWARM_POLL_INTERVAL) => {}
                 }
-                return None;
             }
-        };
+            let report = client.prewarm(&addrs).await;
+            info!(
+                "GeoIP cache prewarmed from {:?}: {} address(es) cached, {} with a location, {} failed, {} skipped",
+                path, report.cached, report.found, report.failed, report.skipped
+            );
+        });
+    }
 
-        let request = format!(
-            "GET /lookup/country?ip={} HTTP/1.1\r\nHost: localhost\r\n\r\n",
-            ip
+    /// Looks up where `ip` is, recording the result in metrics and in a
+    /// `geoip_lookup` span. `Unavailable`, `Timeout` and `Error` all mean no
+    /// provider could answer.
+    pub async fn lookup(&self, ip: IpAddr) -> Lookup {
+        let span = debug_span!(
+            "geoip_lookup",
+            client = %privacy::display_ip(ip),
+            result = field::Empty,
+            country = field::Empty,
+            cached = field::Empty,
         );
+        self.lookup_inner(ip).instrument(span).await
+    }
 
-        if stream.write_all(request.as_bytes()).await.is_err() || stream.flush().await.is_err() {
-            return None;
+    async fn lookup_inner(&self, ip: IpAddr) -> Lookup {
+        let span = Span::current();
+        if let Some(location) = self.cache.get(ip) {
+            let label = if location.is_some() { "hit" } else { "miss" };
+            let country = location.as_ref().and_then(|l| l.country.as_deref());
+            metrics::GEOIP_LOOKUPS.inc(&[label, country.unwrap_or(""), "true"]);
+            span.record("result", label);
+            if let Some(country) = country {
+                span.record("country", country);
+            }
+            span.record("cached", true);
+            debug!("GeoIP lookup answered from cache");
+            return location.map_or(Lookup::Miss, Lookup::Hit);
+        }
+        span.record("cached", false);
+        if !self.is_available() {
+            metrics::GEOIP_LOOKUPS.inc(&["unavailable", "", "false"]);
+            span.record("result", "unavailable");
+            debug!("GeoIP service unavailable, skipping lookup");
+            return Lookup::Unavailable;
         }
 
-        let mut response_buf = [0; 1024];
-        if let Ok(n) = stream.read(&mut response_buf).await {
-            if let Some(body) = String::from_utf8_lossy(&response_buf[..n])
-                .split("\r\n\r\n")
-                .nth(1)
-            {
-                if let Ok(data) = serde_json::from_str::<GeoIpResponse>(body.trim_end_matches('\0'))
-                {
-                    return Some(data.country.iso_code);
-                }
+        let started = Instant::now();
+        let result = self.provider.lookup(ip).await;
+        let elapsed = started.elapsed();
+        if !matches!(result, Lookup::Unavailable) {
+            metrics::GEOIP_DURATION.observe(elapsed);
+        }
+        let label = match &result {
+            Lookup::Hit(location) => {
+                self.cache.insert(ip, Some(location.clone()));
+                "hit"
+            }
+            Lookup::Miss => {
+                self.cache.insert(ip, None);
+                "miss"
             }
+            Lookup::Unavailable => "unavailable",
+            Lookup::Timeout => "timeout",
+            Lookup::Error => "error",
+        };
+        let country = match &result {
+            Lookup::Hit(location) => location.country.as_deref(),
+            _ => None,
+        };
+        metrics::GEOIP_LOOKUPS.inc(&[label, country.unwrap_or(""), "false"]);
+        span.record("result", label);
+        if let Some(country) = country {
+            span.record("country", country);
         }
-        None
+        debug!(
+            elapsed_us = elapsed.as_micros() as u64,
+            "GeoIP lookup finished"
+        );
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo_provider::NoopProvider;
+    use crate::location::Location;
+
+    #[tokio::test]
+    async fn cached_results_are_counted_with_the_cached_label() {
+        let client = GeoIpClient::new(Arc::new(NoopProvider), &GeoIpCacheConfig::default());
+        // Documentation addresses and a user-assigned code no other test uses.
+        let ip: IpAddr = "203.0.113.41".parse().unwrap();
+        let location = Location::from_parts(None, Some("XG"), None, None);
+        client.cache.insert(ip, Some(location.clone()));
+
+        assert_eq!(client.lookup(ip).await, Lookup::Hit(location.clone()));
+        assert_eq!(client.lookup(ip).await, Lookup::Hit(location));
+        assert_eq!(metrics::GEOIP_LOOKUPS.get(&["hit", "XG", "true"]), 2);
+        assert_eq!(metrics::GEOIP_LOOKUPS.get(&["hit", "XG", "false"]), 0);
+        assert_eq!(client.cache_stats().hits, 2);
     }
 }
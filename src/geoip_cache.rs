@@ -0,0 +1,302 @@
+/* src/geoip_cache.rs */
+
+use crate::config::GeoIpCacheConfig;
+use crate::location::Location;
+use crate::privacy;
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const SHARD_COUNT: usize = 16;
+
+struct Entry {
+    /// `None` for an address no provider has a location for.
+    location: Option<Location>,
+    expires: Instant,
+}
+
+/// Sharded LRU of GeoIP results keyed by client prefix, so most queries are
+/// answered without asking a provider.
+pub struct GeoIpCache {
+    enabled: bool,
+    max_entries: usize,
+    ttl: Duration,
+    negative_ttl: Duration,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    shards: Vec<Mutex<LruCache<IpAddr, Entry>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Point-in-time cache figures for metrics and the control socket.
+pub struct GeoIpCacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl GeoIpCacheStats {
+    /// Fraction of lookups answered from the cache, 0 before any lookup.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+impl GeoIpCache {
+    pub fn new(config: &GeoIpCacheConfig) -> Self {
+        Self {
+            enabled: config.enabled && config.max_entries > 0,
+            max_entries: (config.max_entries / SHARD_COUNT).max(1),
+            ttl: config.ttl(),
+            negative_ttl: config.negative_ttl(),
+            ipv4_prefix: config.ipv4_prefix,
+            ipv6_prefix: config.ipv6_prefix,
+            shards: (0..SHARD_COUNT)
+                .map(|_| Mutex::new(LruCache::unbounded()))
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The cached result for `ip`: `Some(None)` is a cached "no location".
+    pub fn get(&self, ip: IpAddr) -> Option<Option<Location>> {
+        if !self.enabled {
+            return None;
+        }
+        let key = self.key(ip);
+        let mut shard = self.shards[shard(&key)].lock();
+        let found = match shard.get(&key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.location.clone()),
+            Some(_) => {
+                shard.pop(&key);
+                None
+            }
+            None => None,
+        };
+        drop(shard);
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Stores a provider's answer; `None` means it had no location.
+    pub fn insert(&self, ip: IpAddr, location: Option<Location>) {
+        if !self.enabled {
+            return;
+        }
+        let ttl = if location.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let key = self.key(ip);
+        let mut shard = self.shards[shard(&key)].lock();
+        shard.pop(&key);
+        while shard.len() >= self.max_entries {
+            if shard.pop_lru().is_none() {
+                break;
+            }
+        }
+        shard.put(
+            key,
+            Entry {
+                location,
+                expires: Instant::now() + ttl,
+            },
+        );
+    }
+
+    /// Drops every entry and returns how many there were.
+    pub fn clear(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let mut shard = shard.lock();
+                let removed = shard.len();
+                shard.clear();
+                removed
+            })
+            .sum()
+    }
+
+    pub fn stats(&self) -> GeoIpCacheStats {
+        GeoIpCacheStats {
+            entries: self.shards.iter().map(|shard| shard.lock().len()).sum(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        privacy::truncate(ip, self.ipv4_prefix, self.ipv6_prefix)
+    }
+}
+
+fn shard(key: &IpAddr) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARD_COUNT
+}
+
+/// Reads a prewarm list: one address per line, `#` starts a comment.
+/// Returns the addresses and the lines that could not be parsed.
+pub fn read_prewarm_file(path: &Path) -> io::Result<(Vec<IpAddr>, Vec<String>)> {
+    let content = fs::read_to_string(path)?;
+    let mut addrs = Vec::new();
+    let mut invalid = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(ip) => addrs.push(ip),
+            Err(_) => invalid.push(line.to_string()),
+        }
+    }
+    Ok((addrs, invalid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(config: GeoIpCacheConfig) -> GeoIpCache {
+        GeoIpCache::new(&config)
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn country(code: &str) -> Location {
+        Location::from_parts(None, Some(code), None, None)
+    }
+
+    #[test]
+    fn entries_expire_after_their_ttl() {
+        let mut cache = cache(GeoIpCacheConfig::default());
+        cache.ttl = Duration::from_millis(100);
+        cache.negative_ttl = Duration::from_millis(20);
+        cache.insert(ip("192.0.2.1"), Some(country("US")));
+        cache.insert(ip("192.0.2.2"), None);
+        assert_eq!(cache.get(ip("192.0.2.1")), Some(Some(country("US"))));
+        assert_eq!(cache.get(ip("192.0.2.2")), Some(None));
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(cache.get(ip("192.0.2.1")), Some(Some(country("US"))));
+        assert_eq!(cache.get(ip("192.0.2.2")), None);
+
+        std::thread::sleep(Duration::from_millis(80));
+        assert_eq!(cache.get(ip("192.0.2.1")), None);
+        // Expired entries are removed when found.
+        assert_eq!(cache.stats().entries, 0);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 2));
+    }
+
+    #[test]
+    fn zero_ttls_and_disabled_caches_store_nothing() {
+        let no_negative = cache(GeoIpCacheConfig {
+            negative_ttl_secs: 0,
+            ..GeoIpCacheConfig::default()
+        });
+        no_negative.insert(ip("192.0.2.1"), None);
+        assert_eq!(no_negative.get(ip("192.0.2.1")), None);
+
+        let disabled = cache(GeoIpCacheConfig {
+            enabled: false,
+            ..GeoIpCacheConfig::default()
+        });
+        disabled.insert(ip("192.0.2.1"), Some(country("US")));
+        assert_eq!(disabled.get(ip("192.0.2.1")), None);
+        assert_eq!(disabled.stats().misses, 0);
+    }
+
+    #[test]
+    fn addresses_in_one_prefix_share_an_entry() {
+        let cache = cache(GeoIpCacheConfig {
+            ipv4_prefix: 24,
+            ipv6_prefix: 48,
+            ..GeoIpCacheConfig::default()
+        });
+        cache.insert(ip("192.0.2.1"), Some(country("US")));
+        cache.insert(ip("2001:db8:1::1"), Some(country("DE")));
+
+        assert_eq!(cache.get(ip("192.0.2.254")), Some(Some(country("US"))));
+        assert_eq!(cache.get(ip("192.0.3.1")), None);
+        assert_eq!(
+            cache.get(ip("2001:db8:1:ffff::9")),
+            Some(Some(country("DE")))
+        );
+        assert_eq!(cache.get(ip("2001:db8:2::1")), None);
+
+        // A later answer for the prefix replaces the entry.
+        cache.insert(ip("192.0.2.77"), Some(country("CA")));
+        assert_eq!(cache.get(ip("192.0.2.1")), Some(Some(country("CA"))));
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn entries_are_bounded_by_max_entries() {
+        let cache = cache(GeoIpCacheConfig {
+            max_entries: SHARD_COUNT * 2,
+            ..GeoIpCacheConfig::default()
+        });
+        for i in 0..1000u32 {
+            cache.insert(IpAddr::from((0x0a00_0000 + i).to_be_bytes()), None);
+        }
+        assert_eq!(cache.stats().entries, SHARD_COUNT * 2);
+
+        // The most recently inserted address of each shard is kept.
+        assert_eq!(cache.get(ip("10.0.3.231")), Some(None));
+    }
+
+    #[test]
+    fn clear_drops_every_entry() {
+        let cache = cache(GeoIpCacheConfig::default());
+        for i in 0..50u8 {
+            cache.insert(IpAddr::from([192, 0, 2, i]), Some(country("US")));
+        }
+        assert_eq!(cache.clear(), 50);
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.get(ip("192.0.2.1")), None);
+        assert_eq!(cache.clear(), 0);
+    }
+
+    #[test]
+    fn prewarm_files_skip_comments_and_collect_invalid_lines() {
+        let path =
+            std::env::temp_dir().join(format!("lazy-dns-prewarm-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# resolvers\n192.0.2.1\n\n  2001:db8::1  # v6\nnot-an-ip\n",
+        )
+        .unwrap();
+        let (addrs, invalid) = read_prewarm_file(&path).unwrap();
+        assert_eq!(addrs, [ip("192.0.2.1"), ip("2001:db8::1")]);
+        assert_eq!(invalid, ["not-an-ip"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+/* src/health.rs */
+
+use crate::resolver::DnsResolver;
+use crate::stats::SERVER;
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+/// Answer of `/healthz`: the process is up and its listeners are bound.
+#[derive(Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub listening: bool,
+}
+
+/// Answer of `/readyz`, with the state of every check and zone.
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Checks,
+    pub zones: BTreeMap<String, ZoneState>,
+}
+
+#[derive(Serialize)]
+pub struct Checks {
+    pub listening: bool,
+    pub zones_loaded: usize,
+    pub zones_failed: usize,
+    pub geoip: GeoIpState,
+}
+
+#[derive(Serialize)]
+pub struct GeoIpState {
+    pub available: bool,
+    /// Whether readiness depends on it (`[health] require_geoip`).
+    pub required: bool,
+    /// In lookup order (`[geoip] providers`).
+    pub providers: Vec<ProviderState>,
+}
+
+#[derive(Serialize)]
+pub struct ProviderState {
+    pub name: &'static str,
+    /// The lazy-mmdb socket or the file read.
+    pub source: String,
+    pub available: bool,
+}
+
+#[derive(Serialize)]
+pub struct ZoneState {
+    pub loaded: bool,
+    pub serial: Option<u32>,
+    pub records: Option<usize>,
+    pub error: Option<String>,
+}
+
+pub fn health() -> Health {
+    let listening = SERVER.listening.load(Ordering::Relaxed);
+    Health {
+        healthy: listening,
+        listening,
+    }
+}
+
+/// Ready once the listeners are bound and at least one zone is loaded, and,
+/// if configured, while GeoIP is available. Stops being ready during shutdown.
+pub async fn readiness(resolver: &DnsResolver) -> Readiness {
+    let config = resolver.config();
+    let geoip = resolver.geoip();
+    let geoip = GeoIpState {
+        available: geoip.is_available(),
+        required: config.health.require_geoip,
+        providers: geoip
+            .providers()
+            .into_iter()
+            .map(|provider| ProviderState {
+                name: provider.name,
+                source: provider.source,
+                available: provider.available,
+            })
+            .collect(),
+    };
+
+    let zones: BTreeMap<String, ZoneState> = config
+        .zone_files
+        .keys()
+        .map(|name| {
+            let zone = config.zones.get(name);
+            let state = ZoneState {
+                loaded: zone.is_some(),
+                serial: zone.and_then(|zone| zone.serial()),
+                records: zone.map(|zone| zone.record_count),
+                error: config.zone_errors.get(name).cloned(),
+            };
+            (name.clone(), state)
+        })
+        .collect();
+
+    let checks = Checks {
+        listening: SERVER.listening.load(Ordering::Relaxed),
+        zones_loaded: config.zones.len(),
+        zones_failed: config.zone_errors.len(),
+        geoip,
+    };
+    let ready = checks.listening
+        && checks.zones_loaded > 0
+        && (checks.geoip.available || !checks.geoip.required);
+    Readiness {
+        ready,
+        checks,
+        zones,
+    }
+}
+
+/// `GET /healthz`: 200 while healthy, 503 otherwise.
+pub async fn healthz() -> Response {
+    let health = health();
+    (status_for(health.healthy), Json(health)).into_response()
+}
+
+/// `GET /readyz`: 200 while ready, 503 otherwise.
+pub async fn readyz(State(resolver): State<Arc<DnsResolver>>) -> Response {
+    let readiness = readiness(&resolver).await;
+    (status_for(readiness.ready), Json(readiness)).into_response()
+}
+
+fn status_for(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, GeoIpCacheConfig, GeoIpConfig};
+    use crate::geoip::GeoIpClient;
+    use crate::lazy_mmdb::LazyMmdbProvider;
+    use crate::location::GeoGroups;
+    use crate::records::ZoneConfig;
+    use crate::zone::CompiledZone;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn resolver(config: AppConfig) -> Arc<DnsResolver> {
+        Arc::new(DnsResolver::new(
+            Arc::new(config),
+            Arc::new(GeoIpClient::new(
+                Arc::new(LazyMmdbProvider::new(
+                    &GeoIpConfig::default(),
+                    "/lookup/country",
+                )),
+                &GeoIpCacheConfig::default(),
+            )),
+            None,
+            None,
+        ))
+    }
+
+    #[tokio::test]
+    async fn not_ready_before_any_zone_loads() {
+        SERVER.listening.store(true, Ordering::Relaxed);
+
+        // Zone files are known, but none has compiled yet.
+        let mut config = AppConfig::with_zones(HashMap::new());
+        config
+            .zone_files
+            .insert("example.com".to_string(), PathBuf::from("example.com.toml"));
+        let readiness = readiness(&resolver(config)).await;
+        assert!(!readiness.ready);
+        assert_eq!(readiness.checks.zones_loaded, 0);
+        assert!(!readiness.zones["example.com"].loaded);
+
+        let response = readyz(State(resolver(AppConfig::with_zones(HashMap::new())))).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn ready_once_a_zone_loads_unless_geoip_is_required() {
+        SERVER.listening.store(true, Ordering::Relaxed);
+
+        let zone: ZoneConfig = toml::from_str("[www]\na = [\"192.0.2.1\"]\n").unwrap();
+        let zone = CompiledZone::compile("example.com", &zone, 5, &GeoGroups::default()).unwrap();
+        let mut config =
+            AppConfig::with_zones(HashMap::from([("example.com".to_string(), Arc::new(zone))]));
+        let response = readyz(State(resolver(config.clone()))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // lazy-mmdb is never reachable in tests.
+        config.health.require_geoip = true;
+        let readiness = readiness(&resolver(config)).await;
+        assert!(!readiness.ready);
+        assert!(readiness.checks.geoip.required);
+    }
+}
@@ -0,0 +1,295 @@
+/* src/lazy_mmdb.rs */
+
+use crate::config::GeoIpConfig;
+use crate::geo_provider::{GeoProvider, Lookup, ProviderStatus, ReloadHook};
+use crate::location::Location;
+use crate::unix_http::{HttpError, UnixHttpPool};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::net::UnixStream;
+use tokio::time::{Duration, sleep, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// How long the availability probe may take to connect.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn get_socket_path() -> String {
+    env::var("GEOIP_SOCKET_PATH").unwrap_or_else(|_| "/tmp/lazy-mmdb/lazy-mmdb.sock".to_string())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CodeInfo {
+    code: Option<String>,
+    iso_code: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CityInfo {
+    names: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LocationInfo {
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TraitsInfo {
+    autonomous_system_number: Option<u32>,
+}
+
+/// The parts of a lazy-mmdb answer used for routing. `/lookup/country`
+/// only has `continent` and `country`, `/lookup/asn` only
+/// `autonomous_system_number`; `/lookup/city` adds `location` coordinates.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GeoIpResponse {
+    continent: Option<CodeInfo>,
+    country: Option<CodeInfo>,
+    subdivisions: Vec<CodeInfo>,
+    city: Option<CityInfo>,
+    location: Option<LocationInfo>,
+    autonomous_system_number: Option<u32>,
+    traits: Option<TraitsInfo>,
+}
+
+impl GeoIpResponse {
+    fn into_location(self) -> Location {
+        let mut location = Location::from_parts(
+            self.continent.as_ref().and_then(|c| c.code.as_deref()),
+            self.country.as_ref().and_then(|c| c.iso_code.as_deref()),
+            self.subdivisions
+                .first()
+                .and_then(|s| s.iso_code.as_deref()),
+            self.city
+                .as_ref()
+                .and_then(|city| city.names.get("en"))
+                .map(String::as_str),
+        );
+        location.asn = self.autonomous_system_number.or(self
+            .traits
+            .and_then(|traits| traits.autonomous_system_number));
+        location.coordinates = self
+            .location
+            .and_then(|coords| Some((coords.latitude?, coords.longitude?)));
+        location
+    }
+}
+
+/// The lazy-mmdb service, reached over HTTP on its Unix socket.
+pub struct LazyMmdbProvider {
+    is_available: AtomicBool,
+    pool: UnixHttpPool,
+    lookup_path: String,
+    lookup_timeout: Duration,
+}
+
+impl LazyMmdbProvider {
+    /// Looks addresses up at `lookup_path`, e.g. `/lookup/country`.
+    pub fn new(config: &GeoIpConfig, lookup_path: &str) -> Self {
+        Self {
+            is_available: AtomicBool::new(false),
+            pool: UnixHttpPool::new(get_socket_path(), config.pool_size, config.idle_timeout()),
+            lookup_path: lookup_path.to_string(),
+            lookup_timeout: config.lookup_timeout(),
+        }
+    }
+}
+
+#[async_trait]
+impl GeoProvider for LazyMmdbProvider {
+    fn name(&self) -> &'static str {
+        "lazy-mmdb"
+    }
+
+    fn is_available(&self) -> bool {
+        self.is_available.load(Ordering::Relaxed)
+    }
+
+    async fn lookup(&self, ip: IpAddr) -> Lookup {
+        let target = format!("{}?ip={}", self.lookup_path, ip);
+        let response = match self.pool.get(&target, self.lookup_timeout).await {
+            Ok(response) => response,
+            Err(HttpError::Connect(e)) => {
+                if self.is_available.swap(false, Ordering::Relaxed) {
+                    warn!(
+                        "Failed a lookup connection to lazy-mmdb ({}). Marking as unavailable.",
+                        e
+                    );
+                    self.pool.clear();
+                }
+                return Lookup::Unavailable;
+            }
+            Err(HttpError::Timeout) => {
+                debug!(timeout = ?self.lookup_timeout, "GeoIP lookup timed out");
+                return Lookup::Timeout;
+            }
+            Err(e) => {
+                debug!(error = %e, "GeoIP lookup failed");
+                return Lookup::Error;
+            }
+        };
+
+        match response.status {
+            200 => match serde_json::from_slice::<GeoIpResponse>(&response.body) {
+                Ok(data) => {
+                    let location = data.into_location();
+                    if location.is_empty() {
+                        Lookup::Miss
+                    } else {
+                        Lookup::Hit(location)
+                    }
+                }
+                Err(e) => {
+                    debug!(error = %e, "Unreadable GeoIP lookup response");
+                    Lookup::Error
+                }
+            },
+            400..=499 => Lookup::Miss,
+            status => {
+                debug!(status, "GeoIP lookup failed");
+                Lookup::Error
+            }
+        }
+    }
+
+    fn status(&self) -> Vec<ProviderStatus> {
+        vec![ProviderStatus {
+            name: self.name(),
+            source: self.pool.socket_path().to_string(),
+            available: self.is_available(),
+            detail: Some(self.lookup_path.clone()),
+        }]
+    }
+
+    /// Probes lazy-mmdb every `GEOIP_RECONNECT_SECONDS`.
+    fn start(self: Arc<Self>, shutdown: CancellationToken, _reloaded: ReloadHook) {
+        tokio::spawn(async move {
+            let reconnect_secs: u64 = env::var("GEOIP_RECONNECT_SECONDS")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .unwrap_or(300);
+            let check_interval = Duration::from_secs(reconnect_secs);
+
+            loop {
+                let probe =
+                    timeout(PROBE_TIMEOUT, UnixStream::connect(self.pool.socket_path())).await;
+                match probe.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())) {
+                    Ok(_) => {
+                        if !self.is_available.swap(true, Ordering::Relaxed) {
+                            info!(
+                                "GeoIP service is available (connected to lazy-mmdb successfully)."
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        if self.is_available.swap(false, Ordering::Relaxed) {
+                            warn!("GeoIP service has become unavailable (connection lost).");
+                            self.pool.clear();
+                        } else {
+                            warn!(
+                                "GeoIP service is unavailable (failed to connect: {}). Retrying in {:?}...",
+                                e, check_interval
+                            );
+                        }
+                    }
+                }
+
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = sleep(check_interval) => {}
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves every connection on a fresh socket with `response`.
+    fn serve(name: &str, response: &'static [u8]) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path =
+            std::env::temp_dir().join(format!("lazy-dns-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(response).await;
+            }
+        });
+        path.display().to_string()
+    }
+
+    async fn lookup(socket_path: String, ip: IpAddr) -> Lookup {
+        let config = GeoIpConfig::default();
+        let provider = LazyMmdbProvider {
+            is_available: AtomicBool::new(true),
+            pool: UnixHttpPool::new(socket_path, config.pool_size, config.idle_timeout()),
+            lookup_timeout: config.lookup_timeout(),
+            lookup_path: config.lookup_path.clone(),
+        };
+        provider.lookup(ip).await
+    }
+
+    #[tokio::test]
+    async fn unreadable_success_bodies_are_errors_not_misses() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let garbled = serve(
+            "lazy-mmdb-garbled",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nnot json!",
+        );
+        assert_eq!(lookup(garbled, ip).await, Lookup::Error);
+
+        let not_found = serve(
+            "lazy-mmdb-not-found",
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert_eq!(lookup(not_found, ip).await, Lookup::Miss);
+
+        let found = serve(
+            "lazy-mmdb-found",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 29\r\n\r\n{\"country\":{\"iso_code\":\"DE\"}}",
+        );
+        assert_eq!(
+            lookup(found, ip).await,
+            Lookup::Hit(Location::from_parts(None, Some("DE"), None, None))
+        );
+    }
+
+    #[test]
+    fn city_answers_carry_every_part_of_the_location() {
+        let body = r#"{
+            "continent": {"code": "NA", "names": {"en": "North America"}},
+            "country": {"iso_code": "US"},
+            "subdivisions": [{"iso_code": "CA"}, {"iso_code": "LA"}],
+            "city": {"names": {"de": "Los Angeles", "en": "Los Angeles"}},
+            "location": {"latitude": 34.05}
+        }"#;
+        let response: GeoIpResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            response.into_location().keys(),
+            ["city:US/Los Angeles", "US-CA", "US", "continent:NA"]
+        );
+
+        let response: GeoIpResponse = serde_json::from_str("{}").unwrap();
+        assert!(response.into_location().is_empty());
+    }
+}
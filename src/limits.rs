@@ -0,0 +1,231 @@
+/* src/limits.rs */
+
+use crate::config::{LimitsConfig, OverloadPolicy};
+use crate::privacy;
+use crate::stats::{self, SERVER};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Minimum gap between two "limit reached" warnings of the same kind.
+const WARN_INTERVAL_SECS: u64 = 10;
+
+/// Why a TCP connection was turned away.
+#[derive(Debug, Clone, Copy)]
+pub enum TcpRejection {
+    Global,
+    PerIp,
+}
+
+/// Enforces the `[limits]` section: concurrent TCP clients and in-flight queries.
+pub struct Limits {
+    config: LimitsConfig,
+    tcp_per_ip: Mutex<HashMap<IpAddr, usize>>,
+    last_shed_warn: AtomicU64,
+    last_tcp_warn: AtomicU64,
+}
+
+impl Limits {
+    pub fn new(config: LimitsConfig) -> Self {
+        info!(
+            "Limits: {} TCP clients ({} per IP), read timeout {:?}, write timeout {:?}, {} queries in flight, overload policy {:?}",
+            config.max_tcp_clients,
+            config.max_tcp_clients_per_ip,
+            config.tcp_read_timeout(),
+            config.tcp_write_timeout(),
+            config.max_inflight_queries,
+            config.overload_policy
+        );
+        Self {
+            config,
+            tcp_per_ip: Mutex::new(HashMap::new()),
+            last_shed_warn: AtomicU64::new(0),
+            last_tcp_warn: AtomicU64::new(0),
+        }
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        self.config.tcp_read_timeout()
+    }
+
+    pub fn write_timeout(&self) -> Duration {
+        self.config.tcp_write_timeout()
+    }
+
+    pub fn overload_policy(&self) -> OverloadPolicy {
+        self.config.overload_policy
+    }
+
+    /// Reserves an in-flight query slot, or records a shed query and returns `None`.
+    pub fn try_begin_query(&self) -> Option<QueryGuard> {
+        let previous = SERVER.inflight_queries.fetch_add(1, Ordering::AcqRel);
+        if previous as usize >= self.config.max_inflight_queries {
+            stats::decr(&SERVER.inflight_queries);
+            stats::incr(&SERVER.shed_queries);
+            if should_warn(&self.last_shed_warn) {
+                warn!(
+                    "Load shedding: {} queries in flight, applying {:?} policy ({} shed so far)",
+                    self.config.max_inflight_queries,
+                    self.config.overload_policy,
+                    stats::get(&SERVER.shed_queries)
+                );
+            }
+            return None;
+        }
+        Some(QueryGuard)
+    }
+
+    /// Registers a new TCP client, unless a global or per-IP limit is reached.
+    pub fn try_accept_tcp(self: &Arc<Self>, ip: IpAddr) -> Result<TcpGuard, TcpRejection> {
+        let rejection = {
+            let mut per_ip = self.tcp_per_ip.lock();
+            let current = per_ip.get(&ip).copied().unwrap_or(0);
+            if stats::get(&SERVER.tcp_connections) as usize >= self.config.max_tcp_clients {
+                Some(TcpRejection::Global)
+            } else if current >= self.config.max_tcp_clients_per_ip {
+                Some(TcpRejection::PerIp)
+            } else {
+                per_ip.insert(ip, current + 1);
+                stats::incr(&SERVER.tcp_connections);
+                None
+            }
+        };
+
+        match rejection {
+            None => Ok(TcpGuard {
+                limits: self.clone(),
+                ip,
+            }),
+            Some(reason) => {
+                stats::incr(&SERVER.tcp_rejected);
+                if should_warn(&self.last_tcp_warn) {
+                    warn!(
+                        "Rejecting TCP client {}: {:?} connection limit reached ({} rejected so far)",
+                        privacy::display_ip(ip),
+                        reason,
+                        stats::get(&SERVER.tcp_rejected)
+                    );
+                }
+                Err(reason)
+            }
+        }
+    }
+}
+
+/// Releases an in-flight query slot when dropped.
+pub struct QueryGuard;
+
+impl Drop for QueryGuard {
+    fn drop(&mut self) {
+        stats::decr(&SERVER.inflight_queries);
+    }
+}
+
+/// Releases a TCP client slot when dropped.
+pub struct TcpGuard {
+    limits: Arc<Limits>,
+    ip: IpAddr,
+}
+
+impl Drop for TcpGuard {
+    fn drop(&mut self) {
+        let mut per_ip = self.limits.tcp_per_ip.lock();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+        stats::decr(&SERVER.tcp_connections);
+    }
+}
+
+/// Rate-limits warnings so a flood does not also flood the log.
+fn should_warn(last: &AtomicU64) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let previous = last.load(Ordering::Relaxed);
+    now.saturating_sub(previous) >= WARN_INTERVAL_SECS
+        && last
+            .compare_exchange(previous, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(config: LimitsConfig) -> Arc<Limits> {
+        Arc::new(Limits::new(config))
+    }
+
+    // The TCP counters are process-wide, so every TCP case lives in this one test.
+    #[test]
+    fn tcp_clients_are_limited_globally_and_per_ip() {
+        let limits = limits(LimitsConfig {
+            max_tcp_clients: 3,
+            max_tcp_clients_per_ip: 2,
+            ..LimitsConfig::default()
+        });
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let rejected = stats::get(&SERVER.tcp_rejected);
+
+        let a1 = limits.try_accept_tcp(a).unwrap();
+        let a2 = limits.try_accept_tcp(a).unwrap();
+        assert!(matches!(limits.try_accept_tcp(a), Err(TcpRejection::PerIp)));
+
+        let b1 = limits.try_accept_tcp(b).unwrap();
+        assert!(matches!(
+            limits.try_accept_tcp(b),
+            Err(TcpRejection::Global)
+        ));
+        assert_eq!(stats::get(&SERVER.tcp_connections), 3);
+        assert_eq!(stats::get(&SERVER.tcp_rejected), rejected + 2);
+
+        // Dropping a guard frees both the global and the per-IP slot.
+        drop(a1);
+        let a3 = limits.try_accept_tcp(a).unwrap();
+        assert!(matches!(
+            limits.try_accept_tcp(a),
+            Err(TcpRejection::Global)
+        ));
+
+        drop((a2, a3, b1));
+        assert_eq!(stats::get(&SERVER.tcp_connections), 0);
+        assert!(limits.tcp_per_ip.lock().is_empty());
+    }
+
+    #[test]
+    fn queries_are_shed_once_the_inflight_limit_is_reached() {
+        let shedding = limits(LimitsConfig {
+            max_inflight_queries: 0,
+            overload_policy: OverloadPolicy::Refused,
+            ..LimitsConfig::default()
+        });
+        let shed = stats::get(&SERVER.shed_queries);
+        assert!(shedding.try_begin_query().is_none());
+        assert!(stats::get(&SERVER.shed_queries) > shed);
+        assert_eq!(shedding.overload_policy(), OverloadPolicy::Refused);
+
+        let open = limits(LimitsConfig {
+            max_inflight_queries: usize::MAX,
+            ..LimitsConfig::default()
+        });
+        let guard = open.try_begin_query();
+        assert!(guard.is_some());
+    }
+
+    #[test]
+    fn warnings_are_rate_limited() {
+        let last = AtomicU64::new(0);
+        assert!(should_warn(&last));
+        assert!(!should_warn(&last));
+    }
+}
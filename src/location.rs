@@ -0,0 +1,380 @@
+/* src/location.rs */
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+/// Where a client is, as far as GeoIP knows. Any part may be missing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Location {
+    /// Continent code, e.g. `EU`.
+    pub continent: Option<String>,
+    /// ISO 3166-1 country code, e.g. `US`.
+    pub country: Option<String>,
+    /// ISO 3166-2 subdivision code including the country, e.g. `US-CA`.
+    pub subdivision: Option<String>,
+    /// City name in English, e.g. `Los Angeles`.
+    pub city: Option<String>,
+    /// Autonomous system number of the client's network, e.g. 4134.
+    pub asn: Option<u32>,
+    /// Latitude and longitude in degrees.
+    pub coordinates: Option<(f64, f64)>,
+}
+
+impl Location {
+    /// Builds a location from the raw codes of a GeoIP answer, normalizing
+    /// them and dropping empty ones. `subdivision` may be given with or
+    /// without its country prefix.
+    pub fn from_parts(
+        continent: Option<&str>,
+        country: Option<&str>,
+        subdivision: Option<&str>,
+        city: Option<&str>,
+    ) -> Self {
+        let code = |value: Option<&str>| {
+            value
+                .map(|value| value.trim().to_uppercase())
+                .filter(|value| !value.is_empty())
+        };
+        let country = code(country);
+        let subdivision = match (code(subdivision), &country) {
+            (Some(sub), Some(country)) if !sub.contains('-') => {
+                Some(format!("{}-{}", country, sub))
+            }
+            (sub, _) => sub,
+        };
+        Self {
+            continent: code(continent),
+            country,
+            subdivision,
+            city: city
+                .map(|city| city.trim().to_string())
+                .filter(|city| !city.is_empty()),
+            asn: None,
+            coordinates: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.continent.is_none()
+            && self.country.is_none()
+            && self.subdivision.is_none()
+            && self.city.is_none()
+            && self.asn.is_none()
+            && self.coordinates.is_none()
+    }
+
+    /// The zone keys that match this location, most specific first: city,
+    /// subdivision, country, continent.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::with_capacity(4);
+        if let (Some(country), Some(city)) = (&self.country, &self.city) {
+            keys.push(
+                LocationKey::City {
+                    country: country.clone(),
+                    name: city.clone(),
+                }
+                .to_string(),
+            );
+        }
+        keys.extend(self.subdivision.clone());
+        keys.extend(self.country.clone());
+        if let Some(continent) = &self.continent {
+            keys.push(LocationKey::Continent(continent.clone()).to_string());
+        }
+        keys
+    }
+}
+
+/// Mean Earth radius used for great-circle distances.
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Great-circle distance between two `(latitude, longitude)` points in
+/// degrees, by the haversine formula.
+pub fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let h = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().min(1.0).asin()
+}
+
+/// Parses a key of a zone's `asn` table, e.g. `AS4134`.
+pub fn parse_asn(key: &str) -> Result<u32, String> {
+    let key = key.trim();
+    key.get(..2)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("AS"))
+        .and_then(|_| key[2..].parse().ok())
+        .ok_or_else(|| format!("invalid ASN '{}', expected e.g. AS4134", key))
+}
+
+/// A key of a zone's `country` table, or a location in `[geo_overrides]`.
+///
+/// Written as `DE` (country), `US-CA` (subdivision), `continent:EU` or
+/// `city:US/Los Angeles`. Countries are two letters, as in ISO 3166-1.
+/// Codes are case-insensitive; city names are not.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LocationKey {
+    Continent(String),
+    Country(String),
+    /// Including the country, e.g. `US-CA`.
+    Subdivision(String),
+    City {
+        country: String,
+        name: String,
+    },
+}
+
+impl FromStr for LocationKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = s.trim();
+        let is_code =
+            |code: &str| !code.is_empty() && code.chars().all(|c| c.is_ascii_alphanumeric());
+        let is_country =
+            |code: &str| code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic());
+        let invalid = || {
+            format!(
+                "invalid location '{}', expected e.g. DE, US-CA, continent:EU or city:US/Los Angeles",
+                s
+            )
+        };
+
+        let (kind, value) = match key.split_once(':') {
+            Some((kind, value)) => (Some(kind.trim().to_lowercase()), value.trim()),
+            None => (None, key),
+        };
+        match kind.as_deref() {
+            Some("continent") if is_code(value) => Ok(LocationKey::Continent(value.to_uppercase())),
+            Some("city") => match value.split_once('/') {
+                Some((country, name)) if is_country(country.trim()) && !name.trim().is_empty() => {
+                    Ok(LocationKey::City {
+                        country: country.trim().to_uppercase(),
+                        name: name.trim().to_string(),
+                    })
+                }
+                _ => Err(invalid()),
+            },
+            Some(_) => Err(invalid()),
+            None => match value.split_once('-') {
+                Some((country, sub)) if is_country(country) && is_code(sub) => Ok(
+                    LocationKey::Subdivision(format!("{}-{}", country, sub).to_uppercase()),
+                ),
+                Some(_) => Err(invalid()),
+                None if is_country(value) => Ok(LocationKey::Country(value.to_uppercase())),
+                None => Err(invalid()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for LocationKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocationKey::Continent(code) => write!(f, "continent:{}", code),
+            LocationKey::Country(code) | LocationKey::Subdivision(code) => write!(f, "{}", code),
+            LocationKey::City { country, name } => write!(f, "city:{}/{}", country, name),
+        }
+    }
+}
+
+/// The location a key stands for, with the country implied by a subdivision
+/// or city key filled in.
+impl From<&LocationKey> for Location {
+    fn from(key: &LocationKey) -> Self {
+        match key {
+            LocationKey::Continent(code) => Location {
+                continent: Some(code.clone()),
+                ..Location::default()
+            },
+            LocationKey::Country(code) => Location {
+                country: Some(code.clone()),
+                ..Location::default()
+            },
+            LocationKey::Subdivision(code) => Location {
+                country: code.split('-').next().map(str::to_string),
+                subdivision: Some(code.clone()),
+                ..Location::default()
+            },
+            LocationKey::City { country, name } => Location {
+                country: Some(country.clone()),
+                city: Some(name.clone()),
+                ..Location::default()
+            },
+        }
+    }
+}
+
+/// Named sets of locations from `[geo_groups]`, usable as keys of any zone's
+/// `country` table, e.g. `apac = ["JP", "KR", "SG", "AU"]`.
+#[derive(Debug, Default)]
+pub struct GeoGroups {
+    /// Lower-cased group name to its members.
+    groups: HashMap<String, Vec<LocationKey>>,
+    /// Hash of every group, so zones can tell when their expansion changed.
+    pub fingerprint: u64,
+}
+
+impl GeoGroups {
+    pub fn new(groups: HashMap<String, Vec<LocationKey>>) -> Self {
+        let mut sorted: Vec<_> = groups.iter().collect();
+        sorted.sort_by_key(|(name, _)| name.as_str());
+        let mut hasher = DefaultHasher::new();
+        for (name, members) in sorted {
+            name.hash(&mut hasher);
+            members.hash(&mut hasher);
+        }
+        Self {
+            groups,
+            fingerprint: hasher.finish(),
+        }
+    }
+
+    /// The members of the group called `name`, compared case-insensitively.
+    pub fn get(&self, name: &str) -> Option<&[LocationKey]> {
+        self.groups
+            .get(&name.trim().to_lowercase())
+            .map(Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Group names may use letters, digits and `_`, must start with a letter
+    /// and be at least three characters long. Countries are two letters and
+    /// other keys have a `-` or `:`, so a group never shadows a key.
+    pub fn is_valid_name(name: &str) -> bool {
+        name.len() >= 3
+            && name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(s: &str) -> LocationKey {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_every_kind_of_key() {
+        assert_eq!(key("de"), LocationKey::Country("DE".to_string()));
+        assert_eq!(
+            key(" us-ca "),
+            LocationKey::Subdivision("US-CA".to_string())
+        );
+        assert_eq!(
+            key("Continent:eu"),
+            LocationKey::Continent("EU".to_string())
+        );
+        assert_eq!(
+            key("continent: as"),
+            LocationKey::Continent("AS".to_string())
+        );
+        assert_eq!(
+            key("city:us/Los Angeles"),
+            LocationKey::City {
+                country: "US".to_string(),
+                name: "Los Angeles".to_string(),
+            }
+        );
+        assert_eq!(
+            key("CITY: gb / St Albans "),
+            LocationKey::City {
+                country: "GB".to_string(),
+                name: "St Albans".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for (input, written) in [
+            ("de", "DE"),
+            ("us-ca", "US-CA"),
+            ("continent:eu", "continent:EU"),
+            ("city:us/Los Angeles", "city:US/Los Angeles"),
+            ("city:US/los angeles", "city:US/los angeles"),
+        ] {
+            assert_eq!(key(input).to_string(), written, "{}", input);
+            assert_eq!(key(written), key(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        for input in [
+            "",
+            "  ",
+            "d e",
+            "US-",
+            "-CA",
+            "US-CA-X",
+            "USA",
+            "apac",
+            "D1",
+            "USA-CA",
+            "city:USA/New York",
+            "U.S.",
+            "continent:",
+            "continent:E U",
+            "region:EU",
+            "city:US",
+            "city:US/",
+            "city:/Paris",
+            "city:U S/Paris",
+        ] {
+            assert!(input.parse::<LocationKey>().is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn group_names_never_parse_as_keys() {
+        for name in ["apac", "dach", "usa", "eu_west", "nam", "a1b"] {
+            assert!(GeoGroups::is_valid_name(name), "{}", name);
+            assert!(name.parse::<LocationKey>().is_err(), "{}", name);
+        }
+        for name in ["us", "US-CA", "continent:EU", "1ab", "ab-c", "_ab"] {
+            assert!(!GeoGroups::is_valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn keys_go_from_most_to_least_specific() {
+        let location =
+            Location::from_parts(Some("na"), Some("us"), Some("ca"), Some("Los Angeles"));
+        assert_eq!(
+            location.keys(),
+            ["city:US/Los Angeles", "US-CA", "US", "continent:NA"]
+        );
+        let location = Location::from_parts(Some("EU"), None, None, Some("Paris"));
+        assert_eq!(location.keys(), ["continent:EU"]);
+        assert!(Location::default().keys().is_empty());
+    }
+
+    #[test]
+    fn keys_locate_as_themselves() {
+        for input in ["DE", "US-CA", "continent:EU", "city:US/Los Angeles"] {
+            let location = Location::from(&key(input));
+            assert!(location.keys().contains(&input.to_string()), "{}", input);
+        }
+        assert_eq!(Location::from(&key("US-CA")).keys(), ["US-CA", "US"]);
+    }
+
+    #[test]
+    fn measures_great_circle_distances() {
+        let (london, paris) = ((51.5074, -0.1278), (48.8566, 2.3522));
+        assert!((distance_km(london, paris) - 343.6).abs() < 0.5);
+        assert_eq!(distance_km(london, paris), distance_km(paris, london));
+        assert_eq!(distance_km(london, london), 0.0);
+        let half_way_round = std::f64::consts::PI * EARTH_RADIUS_KM;
+        assert!((distance_km((0.0, 0.0), (0.0, 180.0)) - half_way_round).abs() < 1e-6);
+        assert!((distance_km((90.0, 0.0), (-90.0, 0.0)) - half_way_round).abs() < 1e-6);
+    }
+}
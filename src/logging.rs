@@ -0,0 +1,118 @@
+/* src/logging.rs */
+
+use once_cell::sync::OnceCell;
+use std::io::{self, IsTerminal};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry, reload};
+
+/// Filter used when `LOG_LEVEL` is unset or invalid.
+const DEFAULT_FILTER: &str = "info";
+
+/// Handle to swap the filter at runtime, for `lazy-dns ctl loglevel`.
+static FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
+/// How log lines are written (`LOG_FORMAT`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One line per event, with the fields of its spans.
+    Text,
+    /// Multi-line, for reading by eye.
+    Pretty,
+    /// One JSON object per event, with its span and the spans it is in.
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "text" => Some(LogFormat::Text),
+            "pretty" => Some(LogFormat::Pretty),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Installs the global subscriber. `filter` takes env-filter directives,
+/// e.g. `info,lazy_dns::geoip=debug`. Returns an error message if they were
+/// invalid and the default was used instead.
+pub fn init(filter: Option<&str>, format: LogFormat) -> Result<(), String> {
+    let (env_filter, error) = match filter.map(EnvFilter::try_new) {
+        None => (EnvFilter::new(DEFAULT_FILTER), None),
+        Some(Ok(env_filter)) => (env_filter, None),
+        Some(Err(e)) => (EnvFilter::new(DEFAULT_FILTER), Some(e.to_string())),
+    };
+    let (filter_layer, handle) = reload::Layer::new(env_filter);
+
+    let output = tracing_subscriber::fmt::layer()
+        .with_target(true)
+        .with_ansi(io::stdout().is_terminal());
+    let output = match format {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Pretty => output.pretty().boxed(),
+        LogFormat::Json => output
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(output)
+        .init();
+    let _ = FILTER.set(handle);
+
+    match error {
+        Some(e) => Err(format!(
+            "invalid LOG_LEVEL, using '{}': {}",
+            DEFAULT_FILTER, e
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Replaces the filter directives of the running process.
+pub fn set_filter(directives: &str) -> Result<(), String> {
+    let env_filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+    let handle = FILTER.get().ok_or("logging is not initialized")?;
+    handle.reload(env_filter).map_err(|e| e.to_string())
+}
+
+/// The filter directives in force.
+pub fn current_filter() -> String {
+    FILTER
+        .get()
+        .and_then(|handle| handle.with_current(|filter| filter.to_string()).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_filter_swaps_valid_directives_and_rejects_bad_ones() {
+        // A subscriber of its own, so the test does not install a global one.
+        let (filter_layer, handle) = reload::Layer::new(EnvFilter::new(DEFAULT_FILTER));
+        let _subscriber = tracing_subscriber::registry().with(filter_layer);
+        assert!(FILTER.set(handle).is_ok());
+
+        set_filter("warn,lazy_dns::geoip=debug").unwrap();
+        assert_eq!(current_filter(), "lazy_dns::geoip=debug,warn");
+
+        for bad in ["lazy_dns=loud", "lazy_dns[{x", "info,lazy_dns=9"] {
+            assert!(set_filter(bad).is_err(), "{:?}", bad);
+        }
+        assert_eq!(current_filter(), "lazy_dns::geoip=debug,warn");
+    }
+
+    #[test]
+    fn log_formats_parse_case_insensitively() {
+        assert_eq!(LogFormat::parse("JSON"), Some(LogFormat::Json));
+        assert_eq!(LogFormat::parse("pretty"), Some(LogFormat::Pretty));
+        assert_eq!(LogFormat::parse("Text"), Some(LogFormat::Text));
+        assert_eq!(LogFormat::parse("xml"), None);
+    }
+}
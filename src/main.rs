@@ -1,61 +1,358 @@
 /* src/main.rs */
 
+mod api;
+mod cache;
+mod cidr;
 mod config;
+#[cfg(unix)]
+mod control;
+#[cfg(unix)]
+mod ctl;
 mod dns_server;
+mod dnstap;
+mod geo_csv;
+mod geo_provider;
 mod geoip;
+mod geoip_cache;
+mod health;
+mod lazy_mmdb;
+mod limits;
+mod location;
+mod logging;
+mod metrics;
+mod mmdb;
+mod privacy;
+mod query_log;
 mod records;
+mod reload;
 mod resolver;
+mod stats;
+mod udp;
+mod unix_http;
+mod zone;
 
 use crate::config::AppConfig;
+use crate::dnstap::Dnstap;
 use crate::geoip::GeoIpClient;
+use crate::logging::LogFormat;
+use crate::query_log::QueryLog;
 use crate::resolver::DnsResolver;
 use dotenvy::dotenv;
-use fancy_log::{LogLevel, log, set_log_level};
 use lazy_motd::lazy_motd;
 use std::env;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // --- Initialization ---
     dotenv().ok();
-    let level = env::var("LOG_LEVEL")
-        .unwrap_or_else(|_| "info".to_string())
-        .to_lowercase();
-    let log_level = match level.as_str() {
-        "debug" => LogLevel::Debug,
-        "warn" => LogLevel::Warn,
-        "error" => LogLevel::Error,
-        _ => LogLevel::Info,
-    };
-    set_log_level(log_level);
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("ctl") {
+        std::process::exit(run_ctl(&args[1..]).await);
+    }
+
+    let log_format = env::var("LOG_FORMAT").ok();
+    let format = log_format
+        .as_deref()
+        .and_then(LogFormat::parse)
+        .unwrap_or(LogFormat::Text);
+    let filter = env::var("LOG_LEVEL").ok();
+    let filter_error = logging::init(filter.as_deref(), format).err();
     lazy_motd!();
+    if let Some(e) = filter_error {
+        warn!("{}", e);
+    }
+    if let Some(name) = log_format
+        && LogFormat::parse(&name).is_none()
+    {
+        warn!("Unknown LOG_FORMAT '{}', using text", name);
+    }
 
     // --- Load Config ---
     let config = match AppConfig::load_from_env() {
         Ok(cfg) => Arc::new(cfg),
         Err(e) => {
-            log(LogLevel::Error, &format!("Failed to load config: {}", e));
+            error!("Failed to load config: {}", e);
             return Err(e);
         }
     };
 
+    if config.zone_errors.is_empty() {
+        stats::mark_reload_success();
+    }
+
     // --- Initialize Services ---
-    let geoip_client = Arc::new(GeoIpClient::new());
-    geoip_client.start_reconnect_task(); // Start background reconnection task
+    let shutdown = CancellationToken::new();
+    let tasks = TaskTracker::new();
+
+    let geo_provider = geo_provider::from_config(&config.geoip, &config.base_path);
+    let geoip_client = Arc::new(GeoIpClient::new(geo_provider, &config.geoip_cache));
+    geoip_client.start(shutdown.clone()); // Probe lazy-mmdb, watch GeoIP files
+    if let Some(path) = &config.geoip_cache.prewarm_file {
+        geoip_client.spawn_prewarm(config.base_path.join(path), shutdown.clone());
+    }
+
+    let query_log = if config.query_log.enabled {
+        match QueryLog::start(&config.query_log, &config.base_path) {
+            Ok(query_log) => Some(query_log),
+            Err(e) => {
+                warn!(
+                    "Failed to open the query log, query logging disabled: {}",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let dnstap = if config.dnstap.enabled {
+        match Dnstap::start(&config.dnstap) {
+            Ok(dnstap) => Some(dnstap),
+            Err(e) => {
+                warn!("Failed to start dnstap, dnstap output disabled: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let resolver = Arc::new(DnsResolver::new(
+        config.clone(),
+        geoip_client,
+        query_log,
+        dnstap,
+    ));
 
-    let resolver = Arc::new(DnsResolver::new(config.clone(), geoip_client));
+    if let Err(e) = reload::spawn_watcher(resolver.clone(), shutdown.clone()) {
+        warn!("Failed to watch config files, auto-reload disabled: {}", e);
+    }
+    #[cfg(unix)]
+    if let Err(e) = reload::spawn_sighup_handler(resolver.clone(), shutdown.clone()) {
+        warn!("Failed to install SIGHUP handler: {}", e);
+    }
+
+    #[cfg(unix)]
+    {
+        let control = resolver.config().control.clone();
+        if control.enabled
+            && let Err(e) = control::spawn_server(&control, resolver.clone(), shutdown.clone())
+        {
+            warn!(
+                "Failed to open control socket, ctl commands disabled: {}",
+                e
+            );
+        }
+    }
+
+    let metrics = resolver.config().metrics.clone();
+    if metrics.enabled
+        && let Err(e) = metrics::spawn_server(&metrics, resolver.clone(), shutdown.clone())
+    {
+        warn!(
+            "Failed to start metrics listener on {}: {}",
+            metrics.listen, e
+        );
+    }
+
+    let api = resolver.config().api.clone();
+    if api.enabled
+        && let Err(e) = api::spawn_server(&api, resolver.clone(), shutdown.clone())
+    {
+        warn!("Failed to start HTTP API on {}: {}", api.listen, e);
+    }
 
     // --- Start DNS Server ---
     let port = env::var("BIND_PORT").unwrap_or_else(|_| "53".to_string());
     let bind_addr = format!("0.0.0.0:{}", port);
 
-    log(
-        LogLevel::Info,
-        &format!("Lazy DNS server starting on {}", bind_addr),
-    );
+    info!("Lazy DNS server starting on {}", bind_addr);
+
+    let grace = config.server.shutdown_grace();
+    tokio::spawn(cancel_on_signal(shutdown.clone()));
 
-    dns_server::run_server(&bind_addr, resolver).await?;
+    dns_server::run_server(&bind_addr, resolver.clone(), shutdown, tasks.clone()).await?;
 
+    // --- Drain In-Flight Work ---
+    if !shut_down(&resolver, &tasks, grace).await {
+        std::process::exit(1);
+    }
+
+    info!("Shutdown complete.");
     Ok(())
 }
+
+/// Drains in-flight work, then closes the query log and dnstap so entries for
+/// queries answered during the drain are written out even if the grace period
+/// runs out and the process exits without running destructors. Returns `false`
+/// then.
+async fn shut_down(resolver: &DnsResolver, tasks: &TaskTracker, grace: Duration) -> bool {
+    let drained = drain(tasks, grace).await;
+    resolver.close_sinks();
+    drained
+}
+
+/// Waits up to `grace` for every tracked task to finish. Returns `false` when
+/// the grace period ran out first.
+async fn drain(tasks: &TaskTracker, grace: Duration) -> bool {
+    tasks.close();
+    info!(
+        "Draining {} in-flight task(s), waiting up to {:?}...",
+        tasks.len(),
+        grace
+    );
+    if tokio::time::timeout(grace, tasks.wait()).await.is_err() {
+        error!(
+            "Grace period elapsed with {} task(s) still running; exiting anyway.",
+            tasks.len()
+        );
+        return false;
+    }
+    true
+}
+
+#[cfg(unix)]
+async fn run_ctl(args: &[String]) -> i32 {
+    ctl::run(args).await
+}
+
+/// The control socket is a Unix domain socket.
+#[cfg(not(unix))]
+async fn run_ctl(_args: &[String]) -> i32 {
+    eprintln!("error: lazy-dns ctl is only available on Unix");
+    1
+}
+
+/// Cancels `shutdown` on the first SIGTERM or SIGINT.
+async fn cancel_on_signal(shutdown: CancellationToken) {
+    let name = wait_for_signal().await;
+    info!("Received {}, shutting down gracefully...", name);
+    shutdown.cancel();
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Failed to install SIGTERM handler: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DnstapConfig, GeoIpCacheConfig, QueryLogConfig, QueryLogSink};
+    use crate::geo_provider::NoopProvider;
+    use crate::query_log::QueryLogEntry;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn drain_waits_for_tracked_tasks_within_the_grace_period() {
+        let tasks = TaskTracker::new();
+        let finished = Arc::new(AtomicBool::new(false));
+        let flag = finished.clone();
+        tasks.spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            flag.store(true, Ordering::SeqCst);
+        });
+
+        assert!(drain(&tasks, Duration::from_secs(5)).await);
+        assert!(finished.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_when_the_grace_period_runs_out() {
+        let tasks = TaskTracker::new();
+        tasks.spawn(std::future::pending::<()>());
+
+        assert!(!drain(&tasks, Duration::from_millis(50)).await);
+        assert_eq!(tasks.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn shut_down_closes_the_sinks_after_the_grace_period() {
+        let dir = std::env::temp_dir().join(format!("lazy-dns-shut-down-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let query_log = QueryLog::start(
+            &QueryLogConfig {
+                sink: QueryLogSink::File,
+                ..QueryLogConfig::default()
+            },
+            &dir,
+        )
+        .unwrap();
+        let dnstap = Dnstap::start(&DnstapConfig {
+            output: format!("file:{}", dir.join("dnstap.fstrm").display()),
+            ..DnstapConfig::default()
+        })
+        .unwrap();
+        let resolver = Arc::new(DnsResolver::new(
+            Arc::new(AppConfig::with_zones(HashMap::new())),
+            Arc::new(GeoIpClient::new(
+                Arc::new(NoopProvider),
+                &GeoIpCacheConfig::default(),
+            )),
+            Some(query_log),
+            Some(dnstap),
+        ));
+        let record = |resolver: &DnsResolver, qname: &str| {
+            resolver.query_log().unwrap().record(&QueryLogEntry {
+                client: Some("192.0.2.7".to_string()),
+                transport: "udp",
+                qname,
+                qtype: "A".to_string(),
+                rcode: "NOERROR",
+                answers: &[],
+                zone: None,
+                country: None,
+                latency_us: 1,
+                cached: false,
+            });
+        };
+
+        let tasks = TaskTracker::new();
+        let finishing = resolver.clone();
+        tasks.spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            record(&finishing, "drained.example.com.");
+        });
+        let stuck = resolver.clone();
+        tasks.spawn(async move {
+            record(&stuck, "stuck.example.com.");
+            std::future::pending::<()>().await;
+        });
+
+        assert!(!shut_down(&resolver, &tasks, Duration::from_millis(200)).await);
+        let logged = std::fs::read_to_string(dir.join("queries.log")).unwrap();
+        assert!(logged.contains("\"qname\":\"stuck.example.com.\""));
+        assert!(logged.contains("\"qname\":\"drained.example.com.\""));
+        // dnstap ends with a Frame Streams STOP control frame.
+        let tapped = std::fs::read(dir.join("dnstap.fstrm")).unwrap();
+        assert!(tapped.ends_with(&[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
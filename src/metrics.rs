@@ -0,0 +1,550 @@
+/* src/metrics.rs */
+
+use crate::config::MetricsConfig;
+use crate::health;
+use crate::resolver::DnsResolver;
+use crate::stats::{self, SERVER};
+use axum::Router;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// Latency histogram bucket bounds, in seconds.
+const LATENCY_BUCKETS: [f64; 13] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+pub static QUERIES: Lazy<CounterVec> = Lazy::new(|| {
+    CounterVec::new(
+        "lazydns_queries_total",
+        "DNS queries answered, by zone, query type, response code and transport.",
+        &["zone", "qtype", "rcode", "transport"],
+    )
+});
+
+pub static GEOIP_LOOKUPS: Lazy<CounterVec> = Lazy::new(|| {
+    CounterVec::new(
+        "lazydns_geoip_lookups_total",
+        "GeoIP lookups by result and whether the cache answered; country is set for hits only.",
+        &["result", "country", "cached"],
+    )
+});
+
+pub static GEO_OVERRIDES: Lazy<CounterVec> = Lazy::new(|| {
+    CounterVec::new(
+        "lazydns_geo_override_matches_total",
+        "Clients located by a [geo_overrides] network instead of GeoIP, by location.",
+        &["location"],
+    )
+});
+
+pub static GEO_FALLBACKS: Lazy<CounterVec> = Lazy::new(|| {
+    CounterVec::new(
+        "lazydns_geo_fallbacks_total",
+        "Queries to names with GeoIP data that no location matched, by zone, reason (unknown, unavailable) and action (set, default, servfail).",
+        &["zone", "reason", "action"],
+    )
+});
+
+pub static REQUEST_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    Histogram::new(
+        "lazydns_request_duration_seconds",
+        "Time spent handling a DNS request, GeoIP lookup included.",
+    )
+});
+
+pub static GEOIP_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    Histogram::new(
+        "lazydns_geoip_lookup_duration_seconds",
+        "Time spent on GeoIP lookups that reached a provider.",
+    )
+});
+
+/// A counter with labels, one time series per distinct label set.
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    label_names: &'static [&'static str],
+    series: RwLock<HashMap<Vec<String>, AtomicU64>>,
+}
+
+impl CounterVec {
+    fn new(name: &'static str, help: &'static str, label_names: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            label_names,
+            series: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Adds one to the series with these label values, in `label_names` order.
+    pub fn inc(&self, labels: &[&str]) {
+        let key: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        if let Some(counter) = self.series.read().get(&key) {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.series
+            .write()
+            .entry(key)
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The count of the series with these label values.
+    #[cfg(test)]
+    pub fn get(&self, labels: &[&str]) -> u64 {
+        let key: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        self.series
+            .read()
+            .get(&key)
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        let series = self.series.read();
+        let mut lines: Vec<String> = series
+            .iter()
+            .map(|(labels, value)| {
+                let pairs: Vec<(&str, &str)> = self
+                    .label_names
+                    .iter()
+                    .copied()
+                    .zip(labels.iter().map(String::as_str))
+                    .collect();
+                format!(
+                    "{}{} {}",
+                    self.name,
+                    format_labels(&pairs),
+                    value.load(Ordering::Relaxed)
+                )
+            })
+            .collect();
+        lines.sort();
+        for line in lines {
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+}
+
+/// A latency histogram over fixed buckets.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    /// One per bucket bound plus one for `+Inf`; not cumulative.
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            buckets: (0..=LATENCY_BUCKETS.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = LATENCY_BUCKETS
+                .get(i)
+                .map_or_else(|| "+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                self.name,
+                format_labels(&[("le", &bound)]),
+                cumulative
+            );
+        }
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{}_sum {}", self.name, sum);
+        let _ = writeln!(
+            out,
+            "{}_count {}",
+            self.name,
+            self.count.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Serves `/metrics` and the `/healthz` and `/readyz` probes on their own
+/// listener until `shutdown` is cancelled.
+pub fn spawn_server(
+    metrics: &MetricsConfig,
+    resolver: Arc<DnsResolver>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let listener = std::net::TcpListener::bind(&metrics.listen)?;
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    info!("Metrics and health probes listening on {}", metrics.listen);
+
+    tokio::spawn(async move {
+        let server = axum::serve(listener, router(resolver))
+            .with_graceful_shutdown(shutdown.cancelled_owned());
+        if let Err(e) = server.await {
+            error!("Metrics server failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn router(resolver: Arc<DnsResolver>) -> Router {
+    Router::new()
+        .route("/metrics", get(serve))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .with_state(resolver)
+}
+
+async fn serve(State(resolver): State<Arc<DnsResolver>>) -> Response {
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        render(&resolver).await,
+    )
+        .into_response()
+}
+
+/// Renders every metric in the Prometheus text exposition format.
+pub async fn render(resolver: &DnsResolver) -> String {
+    let mut out = String::new();
+
+    QUERIES.render(&mut out);
+    REQUEST_DURATION.render(&mut out);
+    GEOIP_LOOKUPS.render(&mut out);
+    GEOIP_DURATION.render(&mut out);
+    GEO_OVERRIDES.render(&mut out);
+    GEO_FALLBACKS.render(&mut out);
+
+    let geoip_up = u64::from(resolver.geoip().is_available());
+    single(
+        &mut out,
+        "lazydns_geoip_available",
+        "Whether any GeoIP provider is available.",
+        "gauge",
+        geoip_up,
+    );
+
+    let server = [
+        (
+            "lazydns_inflight_queries",
+            "Queries currently being answered.",
+            "gauge",
+            &SERVER.inflight_queries,
+        ),
+        (
+            "lazydns_tcp_connections",
+            "Open TCP client connections.",
+            "gauge",
+            &SERVER.tcp_connections,
+        ),
+        (
+            "lazydns_shed_queries_total",
+            "Queries dropped or refused under load.",
+            "counter",
+            &SERVER.shed_queries,
+        ),
+        (
+            "lazydns_tcp_rejected_total",
+            "TCP connections refused by a client limit.",
+            "counter",
+            &SERVER.tcp_rejected,
+        ),
+        (
+            "lazydns_tcp_timeouts_total",
+            "TCP connections closed after a read or write timeout.",
+            "counter",
+            &SERVER.tcp_timeouts,
+        ),
+        (
+            "lazydns_query_log_dropped_total",
+            "Query log entries dropped because the writer fell behind.",
+            "counter",
+            &SERVER.query_log_dropped,
+        ),
+        (
+            "lazydns_dnstap_dropped_total",
+            "dnstap frames dropped because the collector was slow or unreachable.",
+            "counter",
+            &SERVER.dnstap_dropped,
+        ),
+    ];
+    for (name, help, kind, value) in server {
+        single(&mut out, name, help, kind, stats::get(value));
+    }
+
+    let cache = resolver.cache().stats();
+    single(
+        &mut out,
+        "lazydns_cache_entries",
+        "Responses in the cache.",
+        "gauge",
+        cache.entries as u64,
+    );
+    single(
+        &mut out,
+        "lazydns_cache_bytes",
+        "Approximate size of the cache.",
+        "gauge",
+        cache.bytes as u64,
+    );
+    single(
+        &mut out,
+        "lazydns_cache_hits_total",
+        "Cache lookups that found a response.",
+        "counter",
+        cache.hits,
+    );
+    single(
+        &mut out,
+        "lazydns_cache_misses_total",
+        "Cache lookups that found nothing.",
+        "counter",
+        cache.misses,
+    );
+
+    let geoip_cache = resolver.geoip().cache_stats();
+    single(
+        &mut out,
+        "lazydns_geoip_cache_entries",
+        "GeoIP results in the cache, negative ones included.",
+        "gauge",
+        geoip_cache.entries as u64,
+    );
+    single(
+        &mut out,
+        "lazydns_geoip_cache_hits_total",
+        "GeoIP lookups answered from the cache.",
+        "counter",
+        geoip_cache.hits,
+    );
+    single(
+        &mut out,
+        "lazydns_geoip_cache_misses_total",
+        "GeoIP lookups not found in the cache.",
+        "counter",
+        geoip_cache.misses,
+    );
+
+    let config = resolver.config();
+    let mut zones: Vec<_> = config.zones.iter().collect();
+    zones.sort_by(|a, b| a.0.cmp(b.0));
+    header(
+        &mut out,
+        "lazydns_zone_serial",
+        "SOA serial being served.",
+        "gauge",
+    );
+    for (name, zone) in &zones {
+        if let Some(serial) = zone.serial() {
+            let _ = writeln!(
+                out,
+                "lazydns_zone_serial{} {}",
+                format_labels(&[("zone", name)]),
+                serial
+            );
+        }
+    }
+    header(
+        &mut out,
+        "lazydns_zone_records",
+        "Records defined in the zone, GeoIP overrides included.",
+        "gauge",
+    );
+    for (name, zone) in &zones {
+        let _ = writeln!(
+            out,
+            "lazydns_zone_records{} {}",
+            format_labels(&[("zone", name)]),
+            zone.record_count
+        );
+    }
+    header(
+        &mut out,
+        "lazydns_zone_load_error",
+        "1 if the last load of the zone failed.",
+        "gauge",
+    );
+    let mut names: Vec<_> = config.zone_files.keys().collect();
+    names.sort();
+    for name in names {
+        let failed = u64::from(config.zone_errors.contains_key(name));
+        let _ = writeln!(
+            out,
+            "lazydns_zone_load_error{} {}",
+            format_labels(&[("zone", name)]),
+            failed
+        );
+    }
+
+    single(
+        &mut out,
+        "lazydns_last_reload_success_timestamp_seconds",
+        "Unix time of the last load or reload in which every zone loaded.",
+        "gauge",
+        stats::get(&SERVER.last_reload_success),
+    );
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn single(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    header(out, name, help, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn format_labels(pairs: &[(&str, &str)]) -> String {
+    let labels: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, GeoIpCacheConfig, GeoIpConfig};
+    use crate::geoip::GeoIpClient;
+    use crate::lazy_mmdb::LazyMmdbProvider;
+    use crate::location::GeoGroups;
+    use crate::records::ZoneConfig;
+    use crate::zone::CompiledZone;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn resolver() -> Arc<DnsResolver> {
+        let zone: ZoneConfig =
+            toml::from_str("[www]\na = [\"192.0.2.1\", \"192.0.2.2\"]\n").unwrap();
+        let zone = CompiledZone::compile("example.com", &zone, 5, &GeoGroups::default()).unwrap();
+        let config =
+            AppConfig::with_zones(HashMap::from([("example.com".to_string(), Arc::new(zone))]));
+        Arc::new(DnsResolver::new(
+            Arc::new(config),
+            Arc::new(GeoIpClient::new(
+                Arc::new(LazyMmdbProvider::new(
+                    &GeoIpConfig::default(),
+                    "/lookup/country",
+                )),
+                &GeoIpCacheConfig::default(),
+            )),
+            None,
+            None,
+        ))
+    }
+
+    #[test]
+    fn counters_render_one_sorted_line_per_label_set() {
+        let counter = CounterVec::new("test_total", "A test counter.", &["zone", "rcode"]);
+        counter.inc(&["b.com", "NOERROR"]);
+        counter.inc(&["a.com", "NXDOMAIN"]);
+        counter.inc(&["b.com", "NOERROR"]);
+        counter.inc(&["a.com", "say \"hi\"\\\n"]);
+
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert_eq!(
+            out,
+            "# HELP test_total A test counter.\n\
+             # TYPE test_total counter\n\
+             test_total{zone=\"a.com\",rcode=\"NXDOMAIN\"} 1\n\
+             test_total{zone=\"a.com\",rcode=\"say \\\"hi\\\"\\\\\\n\"} 1\n\
+             test_total{zone=\"b.com\",rcode=\"NOERROR\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new("test_seconds", "A test histogram.");
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(2));
+
+        let mut out = String::new();
+        histogram.render(&mut out);
+        assert!(out.contains("# TYPE test_seconds histogram\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.0001\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.0025\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.005\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_seconds_sum 2.00305\n"));
+        assert!(out.contains("test_seconds_count 3\n"));
+    }
+
+    #[tokio::test]
+    async fn render_reports_zones_cache_and_geoip_state() {
+        let out = render(&resolver()).await;
+        assert!(out.contains("# TYPE lazydns_queries_total counter\n"));
+        assert!(out.contains("# TYPE lazydns_request_duration_seconds histogram\n"));
+        assert!(out.contains("lazydns_geoip_available 0\n"));
+        assert!(out.contains("lazydns_cache_entries 0\n"));
+        assert!(out.contains("lazydns_zone_records{zone=\"example.com\"} 2\n"));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_over_http() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(resolver())).await });
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("content-type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+        assert!(response.contains("lazydns_zone_records{zone=\"example.com\"} 2\n"));
+    }
+}
@@ -0,0 +1,372 @@
+/* src/mmdb.rs */
+
+use crate::geo_provider::{GeoProvider, Lookup, ProviderStatus, ReloadHook, spawn_file_watcher};
+use crate::location::Location;
+use async_trait::async_trait;
+use chrono::DateTime;
+use maxminddb::{MaxMindDBError, Mmap, Reader, geoip2};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// The fields used from a record. GeoLite2-ASN has the ASN at the top level,
+/// Enterprise databases have it in `traits`.
+#[derive(Deserialize)]
+struct MmdbRecord<'a> {
+    #[serde(borrow)]
+    city: Option<geoip2::city::City<'a>>,
+    continent: Option<geoip2::city::Continent<'a>>,
+    country: Option<geoip2::city::Country<'a>>,
+    subdivisions: Option<Vec<geoip2::city::Subdivision<'a>>>,
+    location: Option<geoip2::city::Location<'a>>,
+    autonomous_system_number: Option<u32>,
+    traits: Option<MmdbTraits>,
+}
+
+#[derive(Deserialize)]
+struct MmdbTraits {
+    autonomous_system_number: Option<u32>,
+}
+
+/// A MaxMind or DB-IP database, memory-mapped and reopened when its file
+/// changes.
+///
+/// Updates should replace the file (write a new one, then rename it over the
+/// old one) rather than rewrite it in place: lookups keep using the old
+/// mapping until the new file is open.
+pub struct MmdbProvider {
+    path: PathBuf,
+    reader: RwLock<Option<Arc<Reader<Mmap>>>>,
+}
+
+impl MmdbProvider {
+    /// Opens `path`. If that fails, the error is logged and the provider is
+    /// unavailable until the file can be opened.
+    pub fn open(path: PathBuf) -> Self {
+        let reader = Self {
+            path,
+            reader: RwLock::new(None),
+        };
+        reader.reload();
+        reader
+    }
+
+    /// Type and build date of the open database, e.g. `GeoLite2-Country,
+    /// built 2026-10-14`.
+    fn describe(&self) -> Option<String> {
+        let reader = self.reader.read().clone()?;
+        let built = DateTime::from_timestamp(reader.metadata.build_epoch as i64, 0)
+            .map(|time| time.format("%Y-%m-%d").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        Some(format!(
+            "{}, built {}",
+            reader.metadata.database_type, built
+        ))
+    }
+
+    /// Opens the file again, keeping the current database if that fails.
+    /// Returns whether a database was opened.
+    fn reload(&self) -> bool {
+        match Reader::open_mmap(&self.path) {
+            Ok(reader) => {
+                info!(
+                    "Opened GeoIP database {:?} ({} nodes, {})",
+                    self.path, reader.metadata.node_count, reader.metadata.database_type
+                );
+                *self.reader.write() = Some(Arc::new(reader));
+                true
+            }
+            Err(e) => {
+                if self.is_available() {
+                    warn!(
+                        "Failed to reopen GeoIP database {:?}, keeping the previous one: {}",
+                        self.path, e
+                    );
+                } else {
+                    warn!(
+                        "Failed to open GeoIP database {:?}, GeoIP unavailable: {}",
+                        self.path, e
+                    );
+                }
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl GeoProvider for MmdbProvider {
+    fn name(&self) -> &'static str {
+        "mmdb"
+    }
+
+    fn is_available(&self) -> bool {
+        self.reader.read().is_some()
+    }
+
+    /// Continent, country, first subdivision, English city name and
+    /// coordinates from a country or city database, or the ASN from an ASN
+    /// database.
+    async fn lookup(&self, ip: IpAddr) -> Lookup {
+        let Some(reader) = self.reader.read().clone() else {
+            return Lookup::Unavailable;
+        };
+        match reader.lookup::<MmdbRecord>(ip) {
+            Ok(record) => {
+                let mut location = Location::from_parts(
+                    record.continent.and_then(|continent| continent.code),
+                    record.country.and_then(|country| country.iso_code),
+                    record
+                        .subdivisions
+                        .as_ref()
+                        .and_then(|subdivisions| subdivisions.first())
+                        .and_then(|subdivision| subdivision.iso_code),
+                    record
+                        .city
+                        .as_ref()
+                        .and_then(|city| city.names.as_ref())
+                        .and_then(|names| names.get("en").copied()),
+                );
+                location.asn = record.autonomous_system_number.or(record
+                    .traits
+                    .and_then(|traits| traits.autonomous_system_number));
+                location.coordinates = record
+                    .location
+                    .and_then(|location| Some((location.latitude?, location.longitude?)));
+                if location.is_empty() {
+                    Lookup::Miss
+                } else {
+                    Lookup::Hit(location)
+                }
+            }
+            Err(MaxMindDBError::AddressNotFoundError(_)) => Lookup::Miss,
+            Err(e) => {
+                debug!(error = %e, "GeoIP database lookup failed");
+                Lookup::Error
+            }
+        }
+    }
+
+    fn status(&self) -> Vec<ProviderStatus> {
+        vec![ProviderStatus {
+            name: self.name(),
+            source: self.path.display().to_string(),
+            available: self.is_available(),
+            detail: self.describe(),
+        }]
+    }
+
+    fn start(self: Arc<Self>, shutdown: CancellationToken, reloaded: ReloadHook) {
+        let path = self.path.clone();
+        let provider = self.clone();
+        let reload = move || {
+            if provider.reload() {
+                reloaded();
+            }
+        };
+        if let Err(e) = spawn_file_watcher(path, shutdown, reload) {
+            warn!(
+                "Failed to watch {:?} for changes, GeoIP database will not be reloaded: {}",
+                self.path, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    /// Control byte for a data field; types past 7 are extended.
+    fn control(out: &mut Vec<u8>, kind: u8, size: usize) {
+        assert!(size < 29);
+        if kind > 7 {
+            out.push(size as u8);
+            out.push(kind - 7);
+        } else {
+            out.push(kind << 5 | size as u8);
+        }
+    }
+
+    fn string(out: &mut Vec<u8>, value: &str) {
+        control(out, 2, value.len());
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    /// An unsigned integer of `kind` 5 (uint16), 6 (uint32) or 9 (uint64).
+    fn uint(out: &mut Vec<u8>, kind: u8, value: u64) {
+        let bytes = value.to_be_bytes();
+        let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+        control(out, kind, bytes.len() - skip);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+
+    /// An IPv4 database with a single network, 192.0.2.0/24, whose record has
+    /// `country.iso_code` set when `iso_code` is given.
+    fn database(iso_code: Option<&str>) -> Vec<u8> {
+        const NODES: u32 = 24;
+        let prefix = u32::from(Ipv4Addr::new(192, 0, 2, 0));
+        let mut out = Vec::new();
+        for depth in 0..NODES {
+            // The data section starts 16 bytes past the tree.
+            let next = if depth + 1 < NODES {
+                depth + 1
+            } else {
+                NODES + 16
+            };
+            let records = if prefix >> (31 - depth) & 1 == 0 {
+                [next, NODES]
+            } else {
+                [NODES, next]
+            };
+            for record in records {
+                out.extend_from_slice(&record.to_be_bytes()[1..]);
+            }
+        }
+        out.extend_from_slice(&[0; 16]);
+
+        match iso_code {
+            Some(iso_code) => {
+                control(&mut out, 7, 1);
+                string(&mut out, "country");
+                control(&mut out, 7, 1);
+                string(&mut out, "iso_code");
+                string(&mut out, iso_code);
+            }
+            None => control(&mut out, 7, 0),
+        }
+
+        out.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        control(&mut out, 7, 9);
+        string(&mut out, "binary_format_major_version");
+        uint(&mut out, 5, 2);
+        string(&mut out, "binary_format_minor_version");
+        uint(&mut out, 5, 0);
+        string(&mut out, "build_epoch");
+        uint(&mut out, 9, 1_791_936_000);
+        string(&mut out, "database_type");
+        string(&mut out, "Test-Country");
+        string(&mut out, "description");
+        control(&mut out, 7, 0);
+        string(&mut out, "ip_version");
+        uint(&mut out, 5, 4);
+        string(&mut out, "languages");
+        control(&mut out, 11, 0);
+        string(&mut out, "node_count");
+        uint(&mut out, 6, NODES as u64);
+        string(&mut out, "record_size");
+        uint(&mut out, 5, 24);
+        out
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lazy-dns-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes `data` next to `path` and renames it over `path`.
+    fn replace(path: &Path, data: &[u8]) {
+        let staged = path.with_extension("tmp");
+        fs::write(&staged, data).unwrap();
+        fs::rename(&staged, path).unwrap();
+    }
+
+    async fn country(provider: &MmdbProvider, ip: &str) -> Option<String> {
+        match provider.lookup(ip.parse().unwrap()).await {
+            Lookup::Hit(location) => location.country,
+            Lookup::Miss => None,
+            other => panic!("lookup failed: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn looks_up_country_codes() {
+        let dir = temp_dir("mmdb-lookup");
+        let path = dir.join("countries.mmdb");
+        fs::write(&path, database(Some("DE"))).unwrap();
+
+        let provider = MmdbProvider::open(path.clone());
+        assert!(provider.is_available());
+        assert_eq!(
+            provider.describe().as_deref(),
+            Some("Test-Country, built 2026-10-14")
+        );
+        assert_eq!(
+            country(&provider, "192.0.2.77").await.as_deref(),
+            Some("DE")
+        );
+        assert_eq!(country(&provider, "192.0.3.1").await, None);
+        assert_eq!(country(&provider, "10.0.0.1").await, None);
+
+        // A record without a country is a miss too.
+        replace(&path, &database(None));
+        assert!(provider.reload());
+        assert_eq!(country(&provider, "192.0.2.77").await, None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn unreadable_files_leave_the_previous_database_in_place() {
+        let dir = temp_dir("mmdb-unreadable");
+        let path = dir.join("countries.mmdb");
+
+        let provider = MmdbProvider::open(path.clone());
+        assert!(!provider.is_available());
+        assert_eq!(
+            provider.lookup("192.0.2.1".parse().unwrap()).await,
+            Lookup::Unavailable
+        );
+        assert_eq!(provider.describe(), None);
+
+        fs::write(&path, database(Some("DE"))).unwrap();
+        assert!(provider.reload());
+        replace(&path, b"not a database");
+        assert!(!provider.reload());
+        assert_eq!(country(&provider, "192.0.2.1").await.as_deref(), Some("DE"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn replacing_the_file_reopens_it_and_reports_the_reload() {
+        let dir = temp_dir("mmdb-watch");
+        let path = dir.join("countries.mmdb");
+        fs::write(&path, database(Some("DE"))).unwrap();
+        let provider = Arc::new(MmdbProvider::open(path.clone()));
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counted = reloads.clone();
+        let shutdown = CancellationToken::new();
+        provider.clone().start(
+            shutdown.clone(),
+            Arc::new(move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+
+        replace(&path, &database(Some("FR")));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reloads.load(Ordering::SeqCst) == 0 {
+            assert!(Instant::now() < deadline, "database not reopened");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(country(&provider, "192.0.2.1").await.as_deref(), Some("FR"));
+
+        // A broken replacement is not reported as a reload.
+        replace(&path, b"not a database");
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+        assert_eq!(country(&provider, "192.0.2.1").await.as_deref(), Some("FR"));
+        shutdown.cancel();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
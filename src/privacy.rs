@@ -0,0 +1,205 @@
+/* src/privacy.rs */
+
+use crate::config::{ClientIpMode, PrivacyConfig};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Written in place of a client address in `remove` mode.
+const REDACTED: &str = "[redacted]";
+/// Bytes of the HMAC kept in `hash` mode, printed as hex.
+const HASH_BYTES: usize = 8;
+
+/// The settings currently in force. Logging happens in places that do not
+/// hold the configuration, so it is mirrored here on load and reload.
+static SETTINGS: Lazy<RwLock<Settings>> =
+    Lazy::new(|| RwLock::new(Settings::new(&PrivacyConfig::default())));
+
+/// Used when `hash_key` is not set; lives as long as the process.
+static RANDOM_KEY: Lazy<[u8; 32]> = Lazy::new(rand::random);
+
+struct Settings {
+    mode: ClientIpMode,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    key: Vec<u8>,
+}
+
+impl Settings {
+    fn new(config: &PrivacyConfig) -> Self {
+        Self {
+            mode: config.client_ip,
+            ipv4_prefix: config.ipv4_prefix.min(32),
+            ipv6_prefix: config.ipv6_prefix.min(128),
+            key: match &config.hash_key {
+                Some(key) => key.as_bytes().to_vec(),
+                None => RANDOM_KEY.to_vec(),
+            },
+        }
+    }
+
+    fn truncate(&self, ip: IpAddr) -> IpAddr {
+        truncate(ip, self.ipv4_prefix, self.ipv6_prefix)
+    }
+
+    fn hash(&self, ip: IpAddr) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        match ip {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.finalize().into_bytes()[..HASH_BYTES]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn client(&self, ip: IpAddr) -> Option<String> {
+        match self.mode {
+            ClientIpMode::Full => Some(ip.to_string()),
+            ClientIpMode::Truncate => Some(self.truncate(ip).to_string()),
+            ClientIpMode::Hash => Some(self.hash(ip)),
+            ClientIpMode::Remove => None,
+        }
+    }
+
+    fn export_address(&self, ip: IpAddr) -> Option<IpAddr> {
+        match self.mode {
+            ClientIpMode::Full => Some(ip),
+            ClientIpMode::Truncate => Some(self.truncate(ip)),
+            ClientIpMode::Hash | ClientIpMode::Remove => None,
+        }
+    }
+}
+
+/// Keeps the first `ipv4_prefix` or `ipv6_prefix` bits of `ip`, zeroing the rest.
+pub fn truncate(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(ipv4_prefix.min(32)));
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask.unwrap_or(0)))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(ipv6_prefix.min(128)));
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask.unwrap_or(0)))
+        }
+    }
+}
+
+/// Applies the `[privacy]` settings of a newly loaded configuration.
+pub fn configure(config: &PrivacyConfig) {
+    *SETTINGS.write() = Settings::new(config);
+}
+
+/// The client address as it may be logged, or `None` in `remove` mode.
+pub fn client(ip: IpAddr) -> Option<String> {
+    SETTINGS.read().client(ip)
+}
+
+/// A client socket address for operational log lines. The port is only
+/// shown in `full` mode.
+pub fn display(addr: SocketAddr) -> String {
+    if exports_port() {
+        return addr.to_string();
+    }
+    display_ip(addr.ip())
+}
+
+/// A client address for operational log lines.
+pub fn display_ip(ip: IpAddr) -> String {
+    client(ip).unwrap_or_else(|| REDACTED.to_string())
+}
+
+/// The client address for binary exports, which need a real address: the
+/// full or truncated one, or `None` when it must be left out.
+pub fn export_address(ip: IpAddr) -> Option<IpAddr> {
+    SETTINGS.read().export_address(ip)
+}
+
+/// Whether client ports may be exported.
+pub fn exports_port() -> bool {
+    SETTINGS.read().mode == ClientIpMode::Full
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The tests build their own settings: the global ones are reset by every
+    // resolver a test creates.
+    fn settings(client_ip: ClientIpMode) -> Settings {
+        Settings::new(&PrivacyConfig {
+            client_ip,
+            hash_key: Some("secret".to_string()),
+            ..PrivacyConfig::default()
+        })
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn full_mode_keeps_the_address() {
+        let full = settings(ClientIpMode::Full);
+        assert_eq!(full.client(ip("192.0.2.77")).as_deref(), Some("192.0.2.77"));
+        assert_eq!(
+            full.export_address(ip("2001:db8::1")),
+            Some(ip("2001:db8::1"))
+        );
+    }
+
+    #[test]
+    fn truncate_mode_keeps_the_configured_prefix() {
+        let truncate = settings(ClientIpMode::Truncate);
+        assert_eq!(
+            truncate.client(ip("192.0.2.77")).as_deref(),
+            Some("192.0.2.0")
+        );
+        assert_eq!(
+            truncate.client(ip("2001:db8:aaaa:bbbb::1")).as_deref(),
+            Some("2001:db8:aaaa::")
+        );
+        assert_eq!(
+            truncate.export_address(ip("198.51.100.200")),
+            Some(ip("198.51.100.0"))
+        );
+
+        let edges = Settings::new(&PrivacyConfig {
+            client_ip: ClientIpMode::Truncate,
+            ipv4_prefix: 0,
+            ipv6_prefix: 200,
+            ..PrivacyConfig::default()
+        });
+        assert_eq!(edges.truncate(ip("192.0.2.77")), ip("0.0.0.0"));
+        assert_eq!(edges.truncate(ip("2001:db8::1")), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn hash_mode_is_keyed_and_stable() {
+        let hash = settings(ClientIpMode::Hash);
+        let first = hash.client(ip("192.0.2.77")).unwrap();
+        assert_eq!(first.len(), HASH_BYTES * 2);
+        assert!(first.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hash.client(ip("192.0.2.77")).unwrap(), first);
+        assert_ne!(hash.client(ip("192.0.2.78")).unwrap(), first);
+        assert_eq!(hash.export_address(ip("192.0.2.77")), None);
+
+        let other_key = Settings::new(&PrivacyConfig {
+            client_ip: ClientIpMode::Hash,
+            hash_key: Some("another".to_string()),
+            ..PrivacyConfig::default()
+        });
+        assert_ne!(other_key.client(ip("192.0.2.77")).unwrap(), first);
+    }
+
+    #[test]
+    fn remove_mode_leaves_the_address_out() {
+        let remove = settings(ClientIpMode::Remove);
+        assert_eq!(remove.client(ip("192.0.2.77")), None);
+        assert_eq!(remove.export_address(ip("192.0.2.77")), None);
+    }
+}
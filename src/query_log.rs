@@ -0,0 +1,411 @@
+/* src/query_log.rs */
+
+use crate::config::{QueryLogConfig, QueryLogSink};
+use crate::stats::{self, SERVER};
+use chrono::{SecondsFormat, Utc};
+use hickory_proto::rr::Record;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Entries buffered for the writer before new ones are dropped.
+const QUEUE_CAPACITY: usize = 65_536;
+/// Minimum gap between two "cannot write the query log" errors.
+const ERROR_INTERVAL: Duration = Duration::from_secs(60);
+/// Syslog priority: facility local0, severity info.
+const SYSLOG_PRIORITY: u8 = 16 * 8 + 6;
+/// How long `close` waits for the writer to catch up.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One answer record, as it appears in the query log.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoggedAnswer {
+    #[serde(rename = "type")]
+    pub rtype: String,
+    pub ttl: u32,
+    pub data: String,
+}
+
+impl From<&Record> for LoggedAnswer {
+    fn from(record: &Record) -> Self {
+        Self {
+            rtype: record.record_type().to_string(),
+            ttl: record.ttl(),
+            data: record.data().to_string(),
+        }
+    }
+}
+
+/// One line of the query log.
+#[derive(Serialize)]
+pub struct QueryLogEntry<'a> {
+    /// Already anonymized; left out in `remove` mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub transport: &'static str,
+    pub qname: &'a str,
+    pub qtype: String,
+    pub rcode: &'static str,
+    pub answers: &'a [LoggedAnswer],
+    pub zone: Option<&'a str>,
+    pub country: Option<&'a str>,
+    pub latency_us: u64,
+    pub cached: bool,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    entry: &'a QueryLogEntry<'a>,
+}
+
+/// Handle to the query log writer. Entries are serialized on the calling task
+/// and handed to a dedicated thread, so a slow sink never delays an answer;
+/// when the queue is full they are dropped and counted instead.
+pub struct QueryLog {
+    sender: SyncSender<Message>,
+}
+
+enum Message {
+    Line(String),
+    /// Sent by `close`; acknowledged once every earlier line is written.
+    Close(SyncSender<()>),
+}
+
+impl QueryLog {
+    /// Opens the configured sink and starts the writer thread.
+    pub fn start(config: &QueryLogConfig, base_path: &Path) -> io::Result<Self> {
+        let sink = Sink::open(config, base_path)?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        std::thread::Builder::new()
+            .name("query-log".to_string())
+            .spawn(move || write_loop(sink, receiver))?;
+        info!("Query log enabled, writing to {:?} sink", config.sink);
+        Ok(Self { sender })
+    }
+
+    /// Whether a query in `zone` should be logged under the current settings.
+    pub fn wants(&self, config: &QueryLogConfig, zone: Option<&str>) -> bool {
+        let enabled = match zone {
+            Some(zone) => config.zones.get(zone).copied().unwrap_or(true),
+            None => config.log_unmatched,
+        };
+        enabled && (config.sample_rate >= 1.0 || rand::random::<f64>() < config.sample_rate)
+    }
+
+    pub fn record(&self, entry: &QueryLogEntry<'_>) {
+        let line = Line {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            entry,
+        };
+        let Ok(line) = serde_json::to_string(&line) else {
+            return;
+        };
+        match self.sender.try_send(Message::Line(line)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => stats::incr(&SERVER.query_log_dropped),
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    /// Writes out every entry recorded so far and stops the writer; entries
+    /// recorded afterwards are discarded. Called on shutdown, since exiting
+    /// the process would lose whatever is still queued.
+    pub fn close(&self) {
+        let (ack, done) = mpsc::sync_channel(1);
+        if self.sender.send(Message::Close(ack)).is_ok()
+            && done.recv_timeout(CLOSE_TIMEOUT).is_err()
+        {
+            warn!("Timed out flushing the query log; recent entries may be lost.");
+        }
+    }
+}
+
+fn write_loop(mut sink: Sink, receiver: Receiver<Message>) {
+    let mut last_error: Option<Instant> = None;
+    while let Ok(message) = receiver.recv() {
+        // Write whatever else is already queued before flushing once.
+        let mut closed = None;
+        let mut result = Ok(());
+        for message in std::iter::once(message).chain(receiver.try_iter()) {
+            match message {
+                Message::Line(line) => result = result.and_then(|()| sink.write_line(&line)),
+                Message::Close(ack) => {
+                    closed = Some(ack);
+                    break;
+                }
+            }
+        }
+        if let Err(e) = result.and_then(|()| sink.flush())
+            && last_error.is_none_or(|at| at.elapsed() >= ERROR_INTERVAL)
+        {
+            error!("Failed to write the query log: {}", e);
+            last_error = Some(Instant::now());
+        }
+        if let Some(ack) = closed {
+            let _ = ack.send(());
+            return;
+        }
+    }
+}
+
+enum Sink {
+    Stdout(io::Stdout),
+    File(RotatingFile),
+    UnixSyslog(UnixDatagram),
+    UdpSyslog(UdpSocket),
+}
+
+impl Sink {
+    fn open(config: &QueryLogConfig, base_path: &Path) -> io::Result<Self> {
+        match config.sink {
+            QueryLogSink::Stdout => Ok(Sink::Stdout(io::stdout())),
+            QueryLogSink::File => Ok(Sink::File(RotatingFile::open(
+                base_path.join(&config.path),
+                config.max_bytes,
+                config.max_files,
+            )?)),
+            QueryLogSink::Syslog => match config.syslog.parse::<SocketAddr>() {
+                Ok(addr) => {
+                    let bind: SocketAddr = if addr.is_ipv4() {
+                        ([0, 0, 0, 0], 0).into()
+                    } else {
+                        ([0u16; 8], 0).into()
+                    };
+                    let socket = UdpSocket::bind(bind)?;
+                    socket.connect(addr)?;
+                    Ok(Sink::UdpSyslog(socket))
+                }
+                Err(_) => {
+                    let socket = UnixDatagram::unbound()?;
+                    socket.connect(&config.syslog)?;
+                    Ok(Sink::UnixSyslog(socket))
+                }
+            },
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stdout(stdout) => writeln!(stdout.lock(), "{}", line),
+            Sink::File(file) => file.write_line(line),
+            Sink::UnixSyslog(socket) => socket.send(syslog_message(line).as_bytes()).map(drop),
+            Sink::UdpSyslog(socket) => socket.send(syslog_message(line).as_bytes()).map(drop),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Stdout(stdout) => stdout.flush(),
+            Sink::File(file) => file.writer.flush(),
+            Sink::UnixSyslog(_) | Sink::UdpSyslog(_) => Ok(()),
+        }
+    }
+}
+
+fn syslog_message(line: &str) -> String {
+    format!(
+        "<{}>lazy-dns[{}]: {}",
+        SYSLOG_PRIORITY,
+        std::process::id(),
+        line
+    )
+}
+
+/// A log file renamed to `<path>.1`, `<path>.2`, ... once it grows past `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            max_files,
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 >= self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.writer, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated(i), self.rotated(i + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lazy-dns-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry<'a>(qname: &'a str, answers: &'a [LoggedAnswer]) -> QueryLogEntry<'a> {
+        QueryLogEntry {
+            client: Some("192.0.2.7".to_string()),
+            transport: "udp",
+            qname,
+            qtype: "A".to_string(),
+            rcode: "NOERROR",
+            answers,
+            zone: Some("example.com"),
+            country: None,
+            latency_us: 42,
+            cached: false,
+        }
+    }
+
+    #[test]
+    fn file_sink_writes_one_json_line_per_entry_until_closed() {
+        let dir = temp_dir("query-log-file");
+        let config = QueryLogConfig {
+            sink: QueryLogSink::File,
+            ..QueryLogConfig::default()
+        };
+        let query_log = QueryLog::start(&config, &dir).unwrap();
+
+        let answers = [LoggedAnswer {
+            rtype: "A".to_string(),
+            ttl: 300,
+            data: "192.0.2.1".to_string(),
+        }];
+        query_log.record(&entry("www.example.com.", &answers));
+        query_log.record(&entry("ftp.example.com.", &[]));
+        query_log.close();
+        query_log.record(&entry("late.example.com.", &[]));
+
+        let contents = fs::read_to_string(dir.join("queries.log")).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["qname"], "www.example.com.");
+        assert_eq!(lines[0]["client"], "192.0.2.7");
+        assert_eq!(lines[0]["zone"], "example.com");
+        assert_eq!(lines[0]["country"], serde_json::Value::Null);
+        assert_eq!(lines[0]["latency_us"], 42);
+        assert_eq!(
+            lines[0]["answers"],
+            serde_json::json!([{"type": "A", "ttl": 300, "data": "192.0.2.1"}])
+        );
+        assert!(lines[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(lines[1]["qname"], "ftp.example.com.");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotates_past_max_bytes_and_keeps_max_files() {
+        let dir = temp_dir("query-log-rotate");
+        let path = dir.join("queries.log");
+        let mut file = RotatingFile::open(path.clone(), 50, 2).unwrap();
+        for i in 0..5 {
+            file.write_line(&format!("{}{}", i, "x".repeat(29)))
+                .unwrap();
+        }
+        file.writer.flush().unwrap();
+
+        let first_char = |path: &Path| fs::read_to_string(path).unwrap().chars().next();
+        assert_eq!(first_char(&path), Some('4'));
+        assert_eq!(first_char(&file.rotated(1)), Some('3'));
+        assert_eq!(first_char(&file.rotated(2)), Some('2'));
+        assert!(!file.rotated(3).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn syslog_messages_carry_the_priority_and_tag() {
+        let dir = temp_dir("query-log-syslog");
+        let socket_path = dir.join("log.sock");
+        let syslog = UnixDatagram::bind(&socket_path).unwrap();
+        let config = QueryLogConfig {
+            sink: QueryLogSink::Syslog,
+            syslog: socket_path.to_string_lossy().into_owned(),
+            ..QueryLogConfig::default()
+        };
+        let query_log = QueryLog::start(&config, &dir).unwrap();
+        query_log.record(&entry("www.example.com.", &[]));
+        query_log.close();
+
+        let mut buf = [0u8; 4096];
+        let len = syslog.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        let prefix = format!("<134>lazy-dns[{}]: ", std::process::id());
+        let line = message.strip_prefix(&prefix).unwrap();
+        let line: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(line["qname"], "www.example.com.");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn wants_follows_zone_switches_and_sampling() {
+        let dir = temp_dir("query-log-wants");
+        let mut config = QueryLogConfig {
+            sink: QueryLogSink::File,
+            zones: HashMap::from([("quiet.com".to_string(), false)]),
+            log_unmatched: false,
+            ..QueryLogConfig::default()
+        };
+        let query_log = QueryLog::start(&config, &dir).unwrap();
+
+        assert!(query_log.wants(&config, Some("example.com")));
+        assert!(!query_log.wants(&config, Some("quiet.com")));
+        assert!(!query_log.wants(&config, None));
+
+        config.sample_rate = 0.0;
+        assert!(!query_log.wants(&config, Some("example.com")));
+        query_log.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,58 +1,118 @@
 /* src/records.rs */
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SOARecord {
     pub mname: String,
     pub rname: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub refresh: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub retry: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub expire: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub minimum: Option<u32>,
-    #[serde(skip)]
+    /// Generated, never read from the zone file.
+    #[serde(skip_deserializing)]
     pub serial: u32,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct MXRecord {
     pub preference: u16,
     pub exchange: String,
 }
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct RecordSet {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub a: Vec<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub aaaa: Vec<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub cname: Vec<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub mx: Vec<MXRecord>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub txt: Vec<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub ns: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ZoneConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ttl: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub soa: Option<SOARecord>,
     #[serde(default)]
     pub apex: RecordSet, // Apex records are now explicitly here
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub country: HashMap<String, RecordSet>, // GeoIP for Apex
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub asn: HashMap<String, RecordSet>, // Client network operator, e.g. `AS4134`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub pops: HashMap<String, PopConfig>, // Nearest-PoP routing for Apex
+    /// Whether an `asn` entry wins over a `country` entry matching the same
+    /// client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn_precedence: Option<AsnPrecedence>,
+    /// What to answer when no GeoIP entry matches a located client and the
+    /// owner has no `_unknown` entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown_policy: Option<FallbackPolicy>,
+    /// What to answer when GeoIP cannot be reached and the owner has no
+    /// `_unavailable` entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unavailable_policy: Option<FallbackPolicy>,
     #[serde(default, flatten)]
     pub subdomains: HashMap<String, Subdomain>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Subdomain {
     #[serde(flatten)]
     pub records: RecordSet,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub country: HashMap<String, RecordSet>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub asn: HashMap<String, RecordSet>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub pops: HashMap<String, PopConfig>,
+}
+
+/// A point of presence: where it is and the records it serves.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PopConfig {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Relative capacity, 1 when unset. Distances to a PoP are divided by its
+    /// weight, so a PoP of weight 2 draws clients from twice as far away.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f64>,
+    #[serde(flatten)]
+    pub records: RecordSet,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AsnPrecedence {
+    /// An `asn` entry wins over any `country` entry.
+    #[default]
+    Asn,
+    /// `asn` entries are only used when no `country` entry matches.
+    Location,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FallbackPolicy {
+    /// Serve the owner's default records.
+    #[default]
+    Default,
+    /// Answer SERVFAIL, so resolvers retry or try another server.
+    Servfail,
 }
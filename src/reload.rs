@@ -0,0 +1,695 @@
+/* src/reload.rs */
+
+use crate::config::{self, AppConfig};
+use crate::resolver::DnsResolver;
+use crate::stats;
+use crate::zone::CompiledZone;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// Quiet period after the last file event before a reload starts, so an
+/// editor saving several files triggers a single reload.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// What a reload did, zone by zone.
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+    pub changed: Vec<SerialChange>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub failed: Vec<ZoneFailure>,
+    pub unchanged: usize,
+}
+
+#[derive(Debug)]
+pub struct SerialChange {
+    pub zone: String,
+    pub old_serial: Option<u32>,
+    pub new_serial: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct ZoneFailure {
+    pub zone: String,
+    pub error: String,
+    /// Whether a previously loaded version is still being served.
+    pub kept_previous: bool,
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} changed, {} added, {} removed, {} failed, {} unchanged",
+            self.changed.len(),
+            self.added.len(),
+            self.removed.len(),
+            self.failed.len(),
+            self.unchanged
+        )
+    }
+}
+
+impl ReloadReport {
+    /// Logs a summary line plus one line per zone that did not stay the same.
+    pub fn log(&self) {
+        if self.failed.is_empty() {
+            info!("Reload finished: {}", self);
+        } else {
+            warn!("Reload finished: {}", self);
+        }
+        for change in &self.changed {
+            info!(
+                "Zone '{}' reloaded, serial {} -> {}",
+                change.zone,
+                fmt_serial(change.old_serial),
+                fmt_serial(change.new_serial)
+            );
+        }
+        for zone in &self.added {
+            info!("Zone '{}' added", zone);
+        }
+        for zone in &self.removed {
+            info!("Zone '{}' removed", zone);
+        }
+        for failure in &self.failed {
+            let outcome = if failure.kept_previous {
+                "keeping previous version"
+            } else {
+                "not served"
+            };
+            error!(
+                "Zone '{}' failed to reload ({}): {}",
+                failure.zone, outcome, failure.error
+            );
+        }
+    }
+}
+
+impl ReloadReport {
+    /// Whether every zone came back exactly as it was being served.
+    fn is_noop(&self) -> bool {
+        self.changed.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.failed.is_empty()
+    }
+}
+
+pub fn fmt_serial(serial: Option<u32>) -> String {
+    serial.map_or_else(|| "-".to_string(), |s| s.to_string())
+}
+
+/// Rebuilds the whole configuration from disk and swaps it in. Zones that
+/// fail to load keep their previous version; if config.toml itself cannot be
+/// read, nothing changes and an error is returned.
+pub async fn reload(resolver: &DnsResolver) -> Result<ReloadReport, String> {
+    let _guard = resolver.lock_reload().await;
+    let current = resolver.config();
+    let base_path = current.base_path.clone();
+    let mut new_config =
+        tokio::task::spawn_blocking(move || AppConfig::load(&base_path).map_err(|e| e.to_string()))
+            .await
+            .map_err(|e| e.to_string())??;
+
+    let mut report = ReloadReport::default();
+
+    let mut failed: Vec<_> = new_config.zone_errors.iter().collect();
+    failed.sort();
+    for (zone, error) in failed {
+        let previous = current.zones.get(zone);
+        if let Some(previous) = previous {
+            new_config.zones.insert(zone.clone(), previous.clone());
+        }
+        report.failed.push(ZoneFailure {
+            zone: zone.clone(),
+            error: error.clone(),
+            kept_previous: previous.is_some(),
+        });
+    }
+
+    let mut names: Vec<String> = new_config.zones.keys().cloned().collect();
+    names.sort();
+    for name in names {
+        if new_config.zone_errors.contains_key(&name) {
+            continue;
+        }
+        let Some(previous) = current.zones.get(&name) else {
+            report.added.push(name);
+            continue;
+        };
+        let zone = new_config.zones.get_mut(&name).expect("zone listed above");
+        match apply_zone_update(previous, zone) {
+            Some(change) => report.changed.push(SerialChange {
+                zone: name,
+                ..change
+            }),
+            None => report.unchanged += 1,
+        }
+    }
+
+    report.removed = current
+        .zones
+        .keys()
+        .filter(|zone| !new_config.zone_files.contains_key(*zone))
+        .cloned()
+        .collect();
+    report.removed.sort();
+
+    // Swapping would flush the response cache for nothing.
+    if report.is_noop() && new_config.fingerprint == current.fingerprint {
+        return Ok(report);
+    }
+    save_serials(&new_config, &report);
+    resolver.swap_config(Arc::new(new_config));
+    if report.failed.is_empty() {
+        stats::mark_reload_success();
+    }
+    Ok(report)
+}
+
+/// Reloads a single zone from its file, leaving every other zone untouched.
+pub async fn reload_zone(resolver: &DnsResolver, zone: &str) -> Result<ReloadReport, String> {
+    let _guard = resolver.lock_reload().await;
+    let current = resolver.config();
+    let Some(path) = current.zone_files.get(zone).cloned() else {
+        return Err(format!("zone '{}' is not configured", zone));
+    };
+
+    let domain = zone.to_string();
+    let default_ttl = current.default_ttl;
+    let geo_groups = current.geo_groups.clone();
+    let loaded = tokio::task::spawn_blocking(move || {
+        config::load_zone(&domain, &path, default_ttl, &geo_groups).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut report = ReloadReport::default();
+    let mut new_config = (*current).clone();
+    match loaded {
+        Ok(compiled) => {
+            let mut compiled = Arc::new(compiled);
+            new_config.zone_errors.remove(zone);
+            match current.zones.get(zone) {
+                Some(previous) => match apply_zone_update(previous, &mut compiled) {
+                    Some(change) => report.changed.push(SerialChange {
+                        zone: zone.to_string(),
+                        ..change
+                    }),
+                    None => report.unchanged += 1,
+                },
+                None => report.added.push(zone.to_string()),
+            }
+            new_config.zones.insert(zone.to_string(), compiled);
+        }
+        Err(error) => {
+            new_config
+                .zone_errors
+                .insert(zone.to_string(), error.clone());
+            report.failed.push(ZoneFailure {
+                zone: zone.to_string(),
+                error,
+                kept_previous: current.zones.contains_key(zone),
+            });
+        }
+    }
+
+    if report.is_noop() && !current.zone_errors.contains_key(zone) {
+        return Ok(report);
+    }
+    save_serials(&new_config, &report);
+    resolver.swap_config(Arc::new(new_config));
+    if report.failed.is_empty() {
+        stats::mark_reload_success();
+    }
+    Ok(report)
+}
+
+/// Records the serials of changed zones, which may have been bumped past the
+/// one their file would give.
+fn save_serials(config: &AppConfig, report: &ReloadReport) {
+    for change in &report.changed {
+        if let (Some(path), Some(zone)) = (
+            config.zone_files.get(&change.zone),
+            config.zones.get(&change.zone),
+        ) {
+            config::save_serial(path, zone);
+        }
+    }
+}
+
+/// Decides between a freshly loaded zone and the one being served.
+///
+/// An unchanged zone is replaced by the previous instance, so serials bumped
+/// earlier survive. A changed zone whose serial did not move forward (the
+/// file-time serial only has hourly resolution) gets the previous serial + 1,
+/// which `save_serials` then records so a restart does not go back on it.
+fn apply_zone_update(
+    previous: &Arc<CompiledZone>,
+    zone: &mut Arc<CompiledZone>,
+) -> Option<SerialChange> {
+    if previous.fingerprint == zone.fingerprint {
+        *zone = previous.clone();
+        return None;
+    }
+
+    let old_serial = previous.serial();
+    if let (Some(old), Some(new)) = (old_serial, zone.serial())
+        && new <= old
+        && let Some(zone) = Arc::get_mut(zone)
+    {
+        zone.set_serial(old.wrapping_add(1));
+    }
+
+    Some(SerialChange {
+        zone: String::new(),
+        old_serial,
+        new_serial: zone.serial(),
+    })
+}
+
+/// Reloads everything on SIGHUP until `shutdown` is cancelled.
+#[cfg(unix)]
+pub fn spawn_sighup_handler(
+    resolver: Arc<DnsResolver>,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                received = hangup.recv() => if received.is_none() { break },
+            }
+            info!("Received SIGHUP, reloading all zones...");
+            match reload(&resolver).await {
+                Ok(report) => report.log(),
+                Err(e) => error!("Reload failed, keeping current config: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Watches config.toml and every zone file, reloading after changes settle.
+pub fn spawn_watcher(
+    resolver: Arc<DnsResolver>,
+    shutdown: CancellationToken,
+) -> notify::Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<PathBuf>>();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event
+            && !event.kind.is_access()
+        {
+            let _ = tx.send(event.paths);
+        }
+    })?;
+
+    let mut watched = WatchSet::default();
+    watched.update(&mut watcher, &resolver.config());
+
+    tokio::spawn(async move {
+        loop {
+            let paths = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                paths = rx.recv() => match paths {
+                    Some(paths) => paths,
+                    None => break,
+                },
+            };
+            let mut changed = Changes::default();
+            watched.collect(&paths, &mut changed);
+
+            // Wait until events stop arriving for a full debounce period.
+            while let Ok(paths) = timeout(DEBOUNCE, rx.recv()).await {
+                match paths {
+                    Some(paths) => watched.collect(&paths, &mut changed),
+                    None => return,
+                }
+            }
+
+            if changed.main_config {
+                info!("config.toml changed, reloading all zones...");
+                match reload(&resolver).await {
+                    Ok(report) => report.log(),
+                    Err(e) => error!("Config reload failed, keeping last good version: {}", e),
+                }
+            } else {
+                let mut zones: Vec<_> = changed.zones.into_iter().collect();
+                zones.sort();
+                for zone in zones {
+                    info!("Zone file for '{}' changed, reloading it...", zone);
+                    match reload_zone(&resolver, &zone).await {
+                        Ok(report) => report.log(),
+                        Err(e) => error!("Reload of zone '{}' failed: {}", zone, e),
+                    }
+                }
+            }
+            // The set of zone files may have changed with config.toml.
+            watched.update(&mut watcher, &resolver.config());
+        }
+    });
+    Ok(())
+}
+
+/// Files that changed during one debounce window.
+#[derive(Default)]
+struct Changes {
+    main_config: bool,
+    zones: HashSet<String>,
+}
+
+/// The files of interest and the directories watched to see them. Directories
+/// are watched rather than files so atomic replaces by editors are noticed.
+#[derive(Default)]
+struct WatchSet {
+    /// File key to the zone it holds; `None` for config.toml.
+    files: HashMap<(PathBuf, OsString), Option<String>>,
+    dirs: HashSet<PathBuf>,
+}
+
+impl WatchSet {
+    fn update(&mut self, watcher: &mut RecommendedWatcher, config: &AppConfig) {
+        self.files = config
+            .zone_files
+            .iter()
+            .filter_map(|(zone, path)| Some((file_key(path)?, Some(zone.clone()))))
+            .collect();
+        if let Some(key) = file_key(&config.base_path.join("config.toml")) {
+            self.files.insert(key, None);
+        }
+        let dirs: HashSet<PathBuf> = self.files.keys().map(|(dir, _)| dir.clone()).collect();
+
+        for dir in self.dirs.difference(&dirs) {
+            let _ = watcher.unwatch(dir);
+        }
+        for dir in dirs.difference(&self.dirs) {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                warn!("Failed to watch {:?} for changes: {}", dir, e);
+            }
+        }
+        self.dirs = dirs;
+    }
+
+    fn collect(&self, paths: &[PathBuf], changes: &mut Changes) {
+        for key in paths.iter().filter_map(|p| file_key(p)) {
+            match self.files.get(&key) {
+                Some(Some(zone)) => {
+                    changes.zones.insert(zone.clone());
+                }
+                Some(None) => changes.main_config = true,
+                None => {}
+            }
+        }
+    }
+}
+
+/// Canonical parent directory and file name; the file itself may not exist.
+fn file_key(path: &Path) -> Option<(PathBuf, OsString)> {
+    let name = path.file_name()?.to_os_string();
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    Some((parent.canonicalize().ok()?, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheKey, CachedResponse};
+    use crate::config::GeoIpCacheConfig;
+    use crate::geo_provider::NoopProvider;
+    use crate::geoip::GeoIpClient;
+    use crate::location::GeoGroups;
+    use crate::records::ZoneConfig;
+    use hickory_proto::op::{Query, ResponseCode};
+    use hickory_proto::rr::{Name, RecordType};
+    use std::fs::{self, File};
+    use std::str::FromStr;
+    use std::time::SystemTime;
+
+    const ZONE: &str = r#"
+        [soa]
+        mname = "ns1.example.com."
+        rname = "admin.example.com."
+
+        [apex]
+        a = ["192.0.2.1"]
+    "#;
+
+    fn zone(fingerprint: u64, serial: u32) -> Arc<CompiledZone> {
+        let config: ZoneConfig = toml::from_str(ZONE).unwrap();
+        let mut zone =
+            CompiledZone::compile("example.com", &config, 5, &GeoGroups::default()).unwrap();
+        zone.fingerprint = fingerprint;
+        zone.set_serial(serial);
+        Arc::new(zone)
+    }
+
+    #[test]
+    fn unchanged_zone_keeps_the_served_instance_and_serial() {
+        let previous = zone(1, 2026101801);
+        let mut loaded = zone(1, 2026101800);
+        assert!(apply_zone_update(&previous, &mut loaded).is_none());
+        assert!(Arc::ptr_eq(&previous, &loaded));
+        assert_eq!(loaded.serial(), Some(2026101801));
+    }
+
+    #[test]
+    fn changed_zone_with_stale_serial_gets_the_next_one() {
+        let previous = zone(1, 2026101800);
+        let mut loaded = zone(2, 2026101800);
+        let change = apply_zone_update(&previous, &mut loaded).unwrap();
+        assert_eq!(change.old_serial, Some(2026101800));
+        assert_eq!(change.new_serial, Some(2026101801));
+        assert_eq!(loaded.serial(), Some(2026101801));
+
+        let mut loaded = zone(3, 2026101700);
+        let change = apply_zone_update(&previous, &mut loaded).unwrap();
+        assert_eq!(change.new_serial, Some(2026101801));
+    }
+
+    #[test]
+    fn changed_zone_with_newer_serial_keeps_it() {
+        let previous = zone(1, 2026101800);
+        let mut loaded = zone(2, 2026101900);
+        let change = apply_zone_update(&previous, &mut loaded).unwrap();
+        assert_eq!(change.new_serial, Some(2026101900));
+    }
+
+    /// A fresh directory serving `zone_source` as example.com.
+    fn config_dir(name: &str, zone_source: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lazy-dns-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("config.toml"),
+            "default_ttl = 5\n[zones]\n\"example.com\" = \"example.com.toml\"\n",
+        )
+        .unwrap();
+        write_zone(&dir, zone_source);
+        dir
+    }
+
+    fn resolver(dir: &Path) -> Arc<DnsResolver> {
+        let config = AppConfig::load(dir).unwrap();
+        Arc::new(DnsResolver::new(
+            Arc::new(config),
+            Arc::new(GeoIpClient::new(
+                Arc::new(NoopProvider),
+                &GeoIpCacheConfig::default(),
+            )),
+            None,
+            None,
+        ))
+    }
+
+    /// Writes the zone file with a fixed modification time, so every version
+    /// gets the same file-time serial.
+    fn write_zone(dir: &Path, source: &str) {
+        let path = dir.join("example.com.toml");
+        fs::write(&path, source).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_790_000_000);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    fn serving(resolver: &DnsResolver) -> Arc<CompiledZone> {
+        resolver.config().zones["example.com"].clone()
+    }
+
+    fn apex_address(resolver: &DnsResolver) -> String {
+        let zone = serving(resolver);
+        zone.node(None).unwrap().default.a[0].data().to_string()
+    }
+
+    #[tokio::test]
+    async fn zone_reload_bumps_serial_and_keeps_previous_on_failure() {
+        let dir = config_dir("reload-zone", ZONE);
+        let resolver = resolver(&dir);
+        let first = serving(&resolver);
+        let serial = first.serial().unwrap();
+
+        let report = reload_zone(&resolver, "example.com").await.unwrap();
+        assert_eq!((report.unchanged, report.changed.len()), (1, 0));
+        assert!(Arc::ptr_eq(&first, &serving(&resolver)));
+
+        write_zone(&dir, &ZONE.replace("192.0.2.1", "192.0.2.2"));
+        let report = reload_zone(&resolver, "example.com").await.unwrap();
+        assert_eq!(report.changed[0].new_serial, Some(serial + 1));
+        let second = serving(&resolver);
+        assert_eq!(second.serial(), Some(serial + 1));
+
+        write_zone(&dir, &ZONE.replace("192.0.2.1", "not an address"));
+        let report = reload_zone(&resolver, "example.com").await.unwrap();
+        assert!(report.failed[0].kept_previous);
+        assert!(Arc::ptr_eq(&second, &serving(&resolver)));
+        assert!(resolver.config().zone_errors.contains_key("example.com"));
+
+        let report = reload(&resolver).await.unwrap();
+        assert!(report.failed[0].kept_previous);
+        assert!(Arc::ptr_eq(&second, &serving(&resolver)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bumped_serial_survives_a_restart() {
+        let dir = config_dir("reload-restart", ZONE);
+        let resolver = resolver(&dir);
+        let serial = serving(&resolver).serial().unwrap();
+
+        write_zone(&dir, &ZONE.replace("192.0.2.1", "192.0.2.2"));
+        reload(&resolver).await.unwrap();
+        assert_eq!(serving(&resolver).serial(), Some(serial + 1));
+
+        // Same files, same hour: a restart must not go back to `serial`.
+        let restarted = AppConfig::load(&dir).unwrap();
+        assert_eq!(restarted.zones["example.com"].serial(), Some(serial + 1));
+
+        // Edited while the server was down, still within the same hour.
+        write_zone(&dir, &ZONE.replace("192.0.2.1", "192.0.2.3"));
+        let restarted = AppConfig::load(&dir).unwrap();
+        assert_eq!(restarted.zones["example.com"].serial(), Some(serial + 2));
+        let restarted = AppConfig::load(&dir).unwrap();
+        assert_eq!(restarted.zones["example.com"].serial(), Some(serial + 2));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unchanged_reload_keeps_the_config_and_cache() {
+        let dir = config_dir("reload-noop", ZONE);
+        let resolver = resolver(&dir);
+        let before = resolver.config();
+        let key = CacheKey::new(
+            &Query::query(Name::from_str("example.com.").unwrap(), RecordType::A),
+            None,
+            None,
+        );
+        let response = CachedResponse {
+            bytes: vec![0; 12],
+            zone: None,
+            summary: String::new(),
+            rcode: ResponseCode::NoError,
+            answers: Arc::from([]),
+        };
+        resolver
+            .cache()
+            .insert(key.clone(), response, resolver.cache().generation());
+
+        let report = reload(&resolver).await.unwrap();
+        assert_eq!(report.unchanged, 1);
+        let report = reload_zone(&resolver, "example.com").await.unwrap();
+        assert_eq!(report.unchanged, 1);
+        assert!(Arc::ptr_eq(&before, &resolver.config()));
+        assert!(resolver.cache().get(&key).is_some());
+
+        // A change outside the zones still swaps the config in.
+        fs::write(
+            dir.join("config.toml"),
+            "default_ttl = 5\n[zones]\n\"example.com\" = \"example.com.toml\"\n[cache]\nenabled = true\n",
+        )
+        .unwrap();
+        reload(&resolver).await.unwrap();
+        assert!(!Arc::ptr_eq(&before, &resolver.config()));
+        assert!(resolver.cache().get(&key).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn watcher_reloads_after_a_zone_file_changes() {
+        let dir = config_dir("reload-watch", ZONE);
+        let resolver = resolver(&dir);
+        let shutdown = CancellationToken::new();
+        spawn_watcher(resolver.clone(), shutdown.clone()).unwrap();
+        assert_eq!(apex_address(&resolver), "192.0.2.1");
+
+        // Files next to the watched ones do not trigger anything.
+        fs::write(dir.join("notes.txt"), "unrelated").unwrap();
+        fs::write(
+            dir.join("example.com.toml"),
+            ZONE.replace("192.0.2.1", "192.0.2.3"),
+        )
+        .unwrap();
+
+        let mut reloaded = false;
+        for _ in 0..50 {
+            if apex_address(&resolver) == "192.0.2.3" {
+                reloaded = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        shutdown.cancel();
+        assert!(reloaded, "zone change was not picked up");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn watch_set_tells_config_and_zone_files_apart() {
+        let dir = std::env::temp_dir();
+        let watched = WatchSet {
+            files: HashMap::from([
+                (file_key(&dir.join("config.toml")).unwrap(), None),
+                (
+                    file_key(&dir.join("example.com.toml")).unwrap(),
+                    Some("example.com".to_string()),
+                ),
+            ]),
+            dirs: HashSet::new(),
+        };
+
+        let mut changes = Changes::default();
+        watched.collect(
+            &[dir.join("example.com.toml.serial"), dir.join("notes.txt")],
+            &mut changes,
+        );
+        assert!(!changes.main_config && changes.zones.is_empty());
+
+        watched.collect(&[dir.join(".").join("example.com.toml")], &mut changes);
+        assert!(!changes.main_config);
+        assert!(changes.zones.contains("example.com"));
+
+        watched.collect(&[dir.join("config.toml")], &mut changes);
+        assert!(changes.main_config);
+    }
+}
@@ -1,257 +1,723 @@
 /* src/resolver.rs */
 
+use crate::cache::ResponseCache;
+use crate::cidr::CidrTable;
 use crate::config::AppConfig;
+use crate::dnstap::Dnstap;
+use crate::geo_provider::Lookup;
 use crate::geoip::GeoIpClient;
-use crate::records::{RecordSet, ZoneConfig};
-use fancy_log::{LogLevel, log};
+use crate::location::{Location, LocationKey};
+use crate::metrics;
+use crate::privacy;
+use crate::query_log::QueryLog;
+use crate::records::{AsnPrecedence, FallbackPolicy};
+use crate::zone::{CompiledNode, CompiledZone, RecordBundle, UNAVAILABLE_KEY, UNKNOWN_KEY};
 use hickory_proto::op::Query;
-use hickory_proto::rr::rdata::{A, AAAA, CNAME, MX, NS, SOA, TXT};
-use hickory_proto::rr::{Name, RData, Record, RecordType};
+use hickory_proto::rr::{Name, Record, RecordType};
+use parking_lot::RwLock;
 use rand::seq::SliceRandom;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::str::FromStr;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::debug;
 
 pub struct DnsResolver {
-    config: Arc<AppConfig>,
+    config: RwLock<Arc<AppConfig>>,
     geoip: Arc<GeoIpClient>,
+    cache: ResponseCache,
+    /// Held by reloads from reading the current config until the new one is
+    /// swapped in, so concurrent reloads cannot undo each other's changes.
+    reload_lock: Mutex<()>,
+    query_log: Option<QueryLog>,
+    dnstap: Option<Dnstap>,
 }
 
 impl DnsResolver {
-    pub fn new(config: Arc<AppConfig>, geoip: Arc<GeoIpClient>) -> Self {
-        Self { config, geoip }
+    pub fn new(
+        config: Arc<AppConfig>,
+        geoip: Arc<GeoIpClient>,
+        query_log: Option<QueryLog>,
+        dnstap: Option<Dnstap>,
+    ) -> Self {
+        let cache = ResponseCache::new(&config.cache);
+        privacy::configure(&config.privacy);
+        Self {
+            config: RwLock::new(config),
+            geoip,
+            cache,
+            reload_lock: Mutex::new(()),
+            query_log,
+            dnstap,
+        }
     }
 
-    pub fn config(&self) -> &Arc<AppConfig> {
-        &self.config
+    /// A snapshot of the current configuration.
+    pub fn config(&self) -> Arc<AppConfig> {
+        self.config.read().clone()
     }
 
-    pub async fn resolve(&self, query: &Query, source_ip: IpAddr) -> Vec<Record> {
-        let q_name_str = query.name().to_string();
-        let q_name_str_lower = q_name_str.to_lowercase();
-        let q_name_lookup = q_name_str_lower
-            .strip_suffix('.')
-            .unwrap_or(&q_name_str_lower);
+    /// Waits for any other reload to finish. Hold the guard from reading the
+    /// config a new one is derived from until `swap_config`.
+    pub async fn lock_reload(&self) -> MutexGuard<'_, ()> {
+        self.reload_lock.lock().await
+    }
 
-        let (zone_name, zone_config) = match self.find_zone(q_name_lookup) {
-            Some(zone) => zone,
-            None => return vec![],
-        };
+    /// Atomically replaces the configuration. Queries already in progress keep
+    /// the snapshot they started with; cached answers are dropped.
+    pub fn swap_config(&self, config: Arc<AppConfig>) {
+        privacy::configure(&config.privacy);
+        *self.config.write() = config;
+        self.cache.clear();
+    }
 
-        let subdomain_part = q_name_lookup
-            .strip_suffix(zone_name)
-            .map(|s| s.strip_suffix('.').unwrap_or(s))
-            .filter(|s| !s.is_empty());
+    pub fn geoip(&self) -> &GeoIpClient {
+        &self.geoip
+    }
 
-        let ttl = zone_config.ttl.unwrap_or(self.config.default_ttl) * 60;
-        let records = self
-            .get_records_for_query(source_ip, zone_config, subdomain_part)
-            .await;
+    pub fn cache(&self) -> &ResponseCache {
+        &self.cache
+    }
 
-        log(
-            LogLevel::Debug,
-            &format!("Found records for query '{}': {:?}", q_name_lookup, records),
-        );
+    /// Whether the answer to `query` depends on the client, because its name
+    /// has GeoIP data. Every other answer is the same for all clients.
+    pub fn is_geo_dependent(&self, query: &Query) -> bool {
+        let q_name_str = query.name().to_string().to_lowercase();
+        let q_name_lookup = q_name_str.strip_suffix('.').unwrap_or(&q_name_str);
+        let config = self.config();
+        find_zone(&config, q_name_lookup)
+            .and_then(|(zone_name, zone)| zone.node(subdomain_of(q_name_lookup, zone_name)))
+            .is_some_and(CompiledNode::has_geo)
+    }
 
-        self.build_response_records(&q_name_str, query.query_type(), ttl, &records)
+    pub fn query_log(&self) -> Option<&QueryLog> {
+        self.query_log.as_ref()
     }
 
-    fn find_zone<'a>(&'a self, query_name: &'a str) -> Option<(&'a str, &'a ZoneConfig)> {
-        self.config
-            .zones
-            .iter()
-            .filter(|(zone_name, _)| query_name.ends_with(*zone_name))
-            .max_by_key(|(zone_name, _)| zone_name.len())
-            .map(|(name, config)| (name.as_str(), config))
+    pub fn dnstap(&self) -> Option<&Dnstap> {
+        self.dnstap.as_ref()
     }
 
-    async fn get_records_for_query(
-        &self,
-        source_ip: IpAddr,
-        zone_config: &ZoneConfig,
-        subdomain: Option<&str>,
-    ) -> RecordSet {
-        let (default_records, geo_map) = if let Some(sub_name) = subdomain {
-            if let Some(sub_config) = zone_config.subdomains.get(sub_name) {
-                (&sub_config.records, &sub_config.country)
-            } else {
-                return RecordSet::default();
-            }
-        } else {
-            (&zone_config.apex, &zone_config.country)
+    /// Flushes and closes the query log and dnstap. Called once on shutdown,
+    /// after in-flight queries have drained or the grace period has run out.
+    pub fn close_sinks(&self) {
+        if let Some(query_log) = &self.query_log {
+            query_log.close();
+        }
+        if let Some(dnstap) = &self.dnstap {
+            dnstap.close(Duration::from_secs(2));
+        }
+    }
+
+    /// Finds the zone, owner name and GeoIP bucket that answer a query.
+    pub async fn route(&self, query: &Query, source_ip: IpAddr) -> Route {
+        let q_name_lookup = lookup_name(query.name());
+        let q_name_lookup = q_name_lookup.as_str();
+
+        let config = self.config();
+        let (zone_name, zone) = match find_zone(&config, q_name_lookup) {
+            Some(zone) => zone,
+            None => return Route::default(),
         };
 
-        // --- FIX: Logic changed from replacement to merge/override ---
-        let mut final_records = default_records.clone();
+        let subdomain_part = subdomain_of(q_name_lookup, zone_name);
 
-        if let Some(geo_overrides) = self.get_geo_records(source_ip, geo_map).await {
-            // Override fields only if they are present in the geo-specific config
-            if !geo_overrides.a.is_empty() {
-                final_records.a = geo_overrides.a;
-            }
-            if !geo_overrides.aaaa.is_empty() {
-                final_records.aaaa = geo_overrides.aaaa;
-            }
-            if !geo_overrides.cname.is_empty() {
-                final_records.cname = geo_overrides.cname;
-            }
-            if !geo_overrides.mx.is_empty() {
-                final_records.mx = geo_overrides.mx;
+        let node = match zone.node(subdomain_part) {
+            Some(node) => node,
+            None => {
+                return Route {
+                    zone: Some(zone_name.to_string()),
+                    ..Route::default()
+                };
             }
-            if !geo_overrides.txt.is_empty() {
-                final_records.txt = geo_overrides.txt;
-            }
-            // NS records are typically global and not overridden by GeoIP
+        };
+
+        let Some((bundle, geo_bucket)) = select_bundle(
+            &self.geoip,
+            source_ip,
+            zone_name,
+            zone,
+            node,
+            &config.geo_overrides,
+        )
+        .await
+        else {
+            return Route {
+                zone: Some(zone_name.to_string()),
+                servfail: true,
+                ..Route::default()
+            };
+        };
+
+        debug!(zone = zone_name, records = ?bundle, "Found records");
+
+        Route {
+            zone: Some(zone_name.to_string()),
+            soa: if subdomain_part.is_none() && query.query_type() == RecordType::SOA {
+                zone.soa.clone()
+            } else {
+                None
+            },
+            bundle: Some(bundle),
+            geo_bucket,
+            servfail: false,
         }
+    }
+}
 
-        final_records
+/// Picks the GeoIP bundle for the client, with its bucket name, or `None`
+/// when the zone's policy is to answer SERVFAIL.
+///
+/// A `[geo_overrides]` network containing the client decides its location
+/// without a GeoIP lookup, private addresses included. The most specific
+/// key the node has wins: city, subdivision, country, then continent. The
+/// client's ASN comes before or after those, as the zone's
+/// `asn_precedence` says. Only when nothing matches is the nearest PoP
+/// used, if the node has any. After that the zone's fallbacks apply.
+async fn select_bundle(
+    geoip: &GeoIpClient,
+    source_ip: IpAddr,
+    zone_name: &str,
+    zone: &CompiledZone,
+    node: &CompiledNode,
+    overrides: &CidrTable<LocationKey>,
+) -> Option<(Arc<RecordBundle>, Option<String>)> {
+    if !node.has_geo() {
+        return Some((node.default.clone(), None));
     }
 
-    async fn get_geo_records(
-        &self,
-        source_ip: IpAddr,
-        geo_map: &std::collections::HashMap<String, RecordSet>,
-    ) -> Option<RecordSet> {
+    let location = if let Some((network, key)) = overrides.lookup(source_ip) {
+        let key_str = key.to_string();
+        metrics::GEO_OVERRIDES.inc(&[&key_str]);
+        debug!(
+            network = %network,
+            location = %key_str,
+            "Client located by [geo_overrides]"
+        );
+        Location::from(key)
+    } else {
         let is_private = matches!(source_ip, IpAddr::V4(v4) if v4.is_private());
         if source_ip.is_loopback() || is_private {
-            return None;
+            return Some((node.default.clone(), None));
         }
 
-        if let Some(country_code) = self.geoip.lookup(source_ip).await {
-            if let Some(records) = geo_map.get(&country_code) {
-                log(
-                    LogLevel::Debug,
-                    &format!("Found GeoIP match for {} -> {}", source_ip, country_code),
-                );
-                return Some(records.clone());
+        match geoip.lookup(source_ip).await {
+            Lookup::Hit(location) => location,
+            Lookup::Miss => return fallback(zone_name, zone, node, Fallback::Unknown),
+            Lookup::Unavailable | Lookup::Timeout | Lookup::Error => {
+                return fallback(zone_name, zone, node, Fallback::Unavailable);
             }
         }
-        None
+    };
+
+    let by_asn = location
+        .asn
+        .and_then(|asn| Some((node.asn.get(&asn)?, format!("AS{}", asn))));
+    if zone.asn_precedence == AsnPrecedence::Asn
+        && let Some((bundle, key)) = by_asn
+    {
+        debug!(asn = %key, "Using ASN override");
+        return Some((bundle.clone(), Some(key)));
+    }
+    for key in location.keys() {
+        if let Some(bundle) = node.country.get(&key) {
+            debug!(location = %key, "Using GeoIP override");
+            return Some((bundle.clone(), Some(key)));
+        }
+    }
+    if let Some((bundle, key)) = by_asn {
+        debug!(asn = %key, "Using ASN override");
+        return Some((bundle.clone(), Some(key)));
+    }
+    if let Some(pop) = location
+        .coordinates
+        .and_then(|coordinates| node.nearest_pop(coordinates))
+    {
+        debug!(pop = %pop.name, "Using nearest PoP");
+        return Some((pop.bundle.clone(), Some(format!("pop:{}", pop.name))));
     }
+    debug!(location = ?location, "No override for location");
+    fallback(zone_name, zone, node, Fallback::Unknown)
+}
 
-    fn build_response_records(
-        &self,
-        q_name: &str,
-        q_type: RecordType,
-        ttl: u32,
-        records: &RecordSet,
-    ) -> Vec<Record> {
-        let mut answers = Vec::new();
-        let name = Name::from_str(q_name).unwrap();
+/// Why a client with GeoIP data on its name got none of it.
+#[derive(Clone, Copy)]
+enum Fallback {
+    /// The client was located, or GeoIP answered, but nothing matched.
+    Unknown,
+    /// GeoIP could not answer in time, or at all.
+    Unavailable,
+}
 
-        if q_type == RecordType::A || q_type == RecordType::ANY {
-            answers.extend(self.create_a_records(&name, ttl, &records.a));
-        }
-        if q_type == RecordType::AAAA || q_type == RecordType::ANY {
-            answers.extend(self.create_aaaa_records(&name, ttl, &records.aaaa));
-        }
-        if q_type == RecordType::CNAME || q_type == RecordType::ANY {
-            answers.extend(self.create_cname_records(&name, ttl, &records.cname));
-        }
-        if q_type == RecordType::MX || q_type == RecordType::ANY {
-            answers.extend(self.create_mx_records(&name, ttl, &records.mx));
-        }
-        if q_type == RecordType::TXT || q_type == RecordType::ANY {
-            answers.extend(self.create_txt_records(&name, ttl, &records.txt));
+/// The node's `_unknown` or `_unavailable` records if it has them, else what
+/// the zone's policy says: the default records, or `None` for SERVFAIL.
+fn fallback(
+    zone_name: &str,
+    zone: &CompiledZone,
+    node: &CompiledNode,
+    reason: Fallback,
+) -> Option<(Arc<RecordBundle>, Option<String>)> {
+    let (label, set, key, policy) = match reason {
+        Fallback::Unknown => ("unknown", &node.unknown, UNKNOWN_KEY, zone.unknown_policy),
+        Fallback::Unavailable => (
+            "unavailable",
+            &node.unavailable,
+            UNAVAILABLE_KEY,
+            zone.unavailable_policy,
+        ),
+    };
+    let (action, selected) = match (set, policy) {
+        (Some(bundle), _) => ("set", Some((bundle.clone(), Some(key.to_string())))),
+        (None, FallbackPolicy::Default) => ("default", Some((node.default.clone(), None))),
+        (None, FallbackPolicy::Servfail) => ("servfail", None),
+    };
+    metrics::GEO_FALLBACKS.inc(&[zone_name, label, action]);
+    debug!(reason = label, action, "Using GeoIP fallback");
+    selected
+}
+
+/// `name` in lower case, without the trailing dot, as zones are keyed.
+fn lookup_name(name: &Name) -> String {
+    let name = name.to_string().to_lowercase();
+    match name.strip_suffix('.') {
+        Some(stripped) => stripped.to_string(),
+        None => name,
+    }
+}
+
+fn find_zone<'a>(config: &'a AppConfig, query_name: &str) -> Option<(&'a str, &'a CompiledZone)> {
+    config
+        .zones
+        .iter()
+        .filter(|(zone_name, _)| query_name.ends_with(zone_name.as_str()))
+        .max_by_key(|(zone_name, _)| zone_name.len())
+        .map(|(name, zone)| (name.as_str(), zone.as_ref()))
+}
+
+/// The labels of `query_name` below `zone_name`, or `None` at the apex.
+fn subdomain_of<'a>(query_name: &'a str, zone_name: &str) -> Option<&'a str> {
+    query_name
+        .strip_suffix(zone_name)
+        .map(|s| s.strip_suffix('.').unwrap_or(s))
+        .filter(|s| !s.is_empty())
+}
+
+/// The answer data selected for one query, before it is encoded.
+#[derive(Default)]
+pub struct Route {
+    /// The zone the query falls in, even if the name itself does not exist.
+    zone: Option<String>,
+    /// `None` when no configured name matches the query.
+    bundle: Option<Arc<RecordBundle>>,
+    /// Present only for SOA queries at the zone apex.
+    soa: Option<Record>,
+    geo_bucket: Option<String>,
+    /// Set when a GeoIP fallback policy says to answer SERVFAIL.
+    servfail: bool,
+}
+
+impl Route {
+    pub fn zone(&self) -> Option<&str> {
+        self.zone.as_deref()
+    }
+
+    /// The GeoIP key whose overrides were applied, if any.
+    pub fn geo_bucket(&self) -> Option<&str> {
+        self.geo_bucket.as_deref()
+    }
+
+    pub fn is_servfail(&self) -> bool {
+        self.servfail
+    }
+
+    pub fn answers(&self, q_type: RecordType) -> Vec<Record> {
+        let mut answers = match &self.bundle {
+            Some(bundle) => build_answers(q_type, bundle),
+            None => Vec::new(),
+        };
+        answers.extend(self.soa.iter().cloned());
+        answers
+    }
+
+    /// Responses can be cached unless they contain address records that are
+    /// shuffled on every query.
+    pub fn is_cacheable(&self, q_type: RecordType) -> bool {
+        let Some(bundle) = &self.bundle else {
+            return true;
+        };
+        let any = q_type == RecordType::ANY;
+        let shuffles_a = (q_type == RecordType::A || any) && bundle.a.len() > 1;
+        let shuffles_aaaa = (q_type == RecordType::AAAA || any) && bundle.aaaa.len() > 1;
+        !(shuffles_a || shuffles_aaaa)
+    }
+}
+
+/// Collects the records of a bundle that answer the given query type.
+fn build_answers(q_type: RecordType, bundle: &RecordBundle) -> Vec<Record> {
+    let any = q_type == RecordType::ANY;
+    let mut answers = Vec::new();
+
+    if q_type == RecordType::A || any {
+        answers.extend(shuffled(&bundle.a));
+    }
+    if q_type == RecordType::AAAA || any {
+        answers.extend(shuffled(&bundle.aaaa));
+    }
+    if q_type == RecordType::CNAME || any {
+        answers.extend(bundle.cname.iter().cloned());
+    }
+    if q_type == RecordType::MX || any {
+        answers.extend(bundle.mx.iter().cloned());
+    }
+    if q_type == RecordType::TXT || any {
+        answers.extend(bundle.txt.iter().cloned());
+    }
+    if q_type == RecordType::NS || any {
+        answers.extend(bundle.ns.iter().cloned());
+    }
+
+    answers
+}
+
+/// Address records are returned in random order for basic load balancing.
+fn shuffled(records: &[Record]) -> Vec<Record> {
+    let mut records = records.to_vec();
+    records.shuffle(&mut rand::thread_rng());
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GeoIpCacheConfig;
+    use crate::geo_provider::{FakeProvider, Lookup};
+    use crate::location::GeoGroups;
+    use crate::records::ZoneConfig;
+
+    const ZONE: &str = r#"
+        [www]
+        a = ["192.0.2.1"]
+
+        [www.country]
+        "city:US/Los Angeles" = { a = ["192.0.2.10"] }
+        US-CA = { a = ["192.0.2.11"] }
+        US = { a = ["192.0.2.12"] }
+        "continent:NA" = { a = ["192.0.2.13"] }
+
+        [www.asn]
+        AS64500 = { a = ["192.0.2.20"] }
+
+        [www.pops.fra]
+        latitude = 50.11
+        longitude = 8.68
+        a = ["192.0.2.30"]
+    "#;
+
+    const FALLBACK_ZONE: &str = r#"
+        [www]
+        a = ["192.0.2.1"]
+
+        [www.country]
+        US = { a = ["192.0.2.12"] }
+        _unknown = { a = ["192.0.2.80"] }
+        _unavailable = { a = ["192.0.2.81"] }
+    "#;
+
+    fn zone(source: &str) -> CompiledZone {
+        let zone: ZoneConfig = toml::from_str(source).unwrap();
+        CompiledZone::compile("example.com", &zone, 5, &GeoGroups::default()).unwrap()
+    }
+
+    fn located(
+        continent: &str,
+        country: &str,
+        subdivision: Option<&str>,
+        city: Option<&str>,
+    ) -> Location {
+        Location::from_parts(Some(continent), Some(country), subdivision, city)
+    }
+
+    /// A GeoIP client without a cache, answering from `answers`.
+    fn geoip<const N: usize>(answers: [(&str, Lookup); N]) -> (GeoIpClient, Arc<FakeProvider>) {
+        let provider = Arc::new(FakeProvider::new(
+            answers.map(|(ip, lookup)| (ip.parse().unwrap(), lookup)),
+        ));
+        let cache = GeoIpCacheConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        (GeoIpClient::new(provider.clone(), &cache), provider)
+    }
+
+    /// The first A record and the bucket `www` gets for `ip`, or `None` for
+    /// SERVFAIL.
+    async fn select(
+        geoip: &GeoIpClient,
+        zone: &CompiledZone,
+        ip: &str,
+        overrides: &CidrTable<LocationKey>,
+    ) -> Option<(String, Option<String>)> {
+        let node = zone.node(Some("www")).unwrap();
+        let (bundle, bucket) = select_bundle(
+            geoip,
+            ip.parse().unwrap(),
+            "resolver.test",
+            zone,
+            node,
+            overrides,
+        )
+        .await?;
+        Some((bundle.a[0].data().to_string(), bucket))
+    }
+
+    fn picked(address: &str, bucket: &str) -> Option<(String, Option<String>)> {
+        Some((address.to_string(), Some(bucket.to_string())))
+    }
+
+    fn default_records() -> Option<(String, Option<String>)> {
+        Some(("192.0.2.1".to_string(), None))
+    }
+
+    #[tokio::test]
+    async fn picks_the_country_override_or_the_default_records() {
+        let zone = zone(ZONE);
+        let (geoip, provider) = geoip([
+            ("203.0.113.1", Lookup::Hit(located("NA", "US", None, None))),
+            ("203.0.113.2", Lookup::Hit(located("EU", "DE", None, None))),
+            ("203.0.113.3", Lookup::Miss),
+            ("203.0.113.4", Lookup::Unavailable),
+            ("203.0.113.5", Lookup::Timeout),
+            ("203.0.113.6", Lookup::Error),
+        ]);
+        let none = CidrTable::default();
+
+        let cases = [
+            ("203.0.113.1", picked("192.0.2.12", "US")),
+            ("203.0.113.2", default_records()),
+            ("203.0.113.3", default_records()),
+            ("203.0.113.4", default_records()),
+            ("203.0.113.5", default_records()),
+            ("203.0.113.6", default_records()),
+        ];
+        for (ip, expected) in cases {
+            assert_eq!(select(&geoip, &zone, ip, &none).await, expected, "{}", ip);
         }
-        if q_type == RecordType::NS || q_type == RecordType::ANY {
-            answers.extend(self.create_ns_records(&name, ttl, &records.ns));
+        assert_eq!(provider.lookups(), 6);
+    }
+
+    #[tokio::test]
+    async fn private_addresses_skip_the_lookup() {
+        let zone = zone(ZONE);
+        let (geoip, provider) = geoip([]);
+        let none = CidrTable::default();
+
+        for ip in ["10.1.2.3", "192.168.0.1", "127.0.0.1", "::1"] {
+            assert_eq!(
+                select(&geoip, &zone, ip, &none).await,
+                default_records(),
+                "{}",
+                ip
+            );
         }
+        assert_eq!(provider.lookups(), 0);
+    }
 
-        let q_name_lookup = q_name.strip_suffix('.').unwrap_or(q_name);
-        if q_type == RecordType::SOA
-            && self
-                .find_zone(q_name_lookup)
-                .map_or(false, |(zn, _)| zn == q_name_lookup)
-        {
-            if let Some(zone_config) = self.find_zone(q_name_lookup).map(|(_, zc)| zc) {
-                if let Some(soa_rec) = self.create_soa_record(&name, ttl, zone_config) {
-                    answers.push(soa_rec);
-                }
-            }
+    #[tokio::test]
+    async fn picks_the_most_specific_location_then_asn_then_pop() {
+        let zone = zone(&format!("asn_precedence = \"location\"\n{}", ZONE));
+        let frankfurt = Location {
+            coordinates: Some((50.0, 8.5)),
+            ..located("EU", "DE", None, None)
+        };
+        let (geoip, _) = geoip([
+            (
+                "203.0.113.1",
+                Lookup::Hit(located("NA", "US", Some("CA"), Some("Los Angeles"))),
+            ),
+            (
+                "203.0.113.2",
+                Lookup::Hit(located("NA", "US", Some("CA"), Some("San Diego"))),
+            ),
+            (
+                "203.0.113.3",
+                Lookup::Hit(located("NA", "US", Some("NY"), None)),
+            ),
+            ("203.0.113.4", Lookup::Hit(located("NA", "CA", None, None))),
+            (
+                "203.0.113.5",
+                Lookup::Hit(Location {
+                    asn: Some(64500),
+                    ..frankfurt.clone()
+                }),
+            ),
+            ("203.0.113.6", Lookup::Hit(frankfurt)),
+            ("203.0.113.7", Lookup::Hit(located("EU", "DE", None, None))),
+            (
+                "203.0.113.8",
+                Lookup::Hit(Location {
+                    asn: Some(64500),
+                    ..located("NA", "US", None, None)
+                }),
+            ),
+        ]);
+        let none = CidrTable::default();
+
+        let cases = [
+            ("203.0.113.1", picked("192.0.2.10", "city:US/Los Angeles")),
+            ("203.0.113.2", picked("192.0.2.11", "US-CA")),
+            ("203.0.113.3", picked("192.0.2.12", "US")),
+            ("203.0.113.4", picked("192.0.2.13", "continent:NA")),
+            ("203.0.113.5", picked("192.0.2.20", "AS64500")),
+            ("203.0.113.6", picked("192.0.2.30", "pop:fra")),
+            ("203.0.113.7", default_records()),
+            ("203.0.113.8", picked("192.0.2.12", "US")),
+        ];
+        for (ip, expected) in cases {
+            assert_eq!(select(&geoip, &zone, ip, &none).await, expected, "{}", ip);
         }
+    }
 
-        answers
+    #[tokio::test]
+    async fn asn_precedence_puts_the_asn_first() {
+        let zone = zone(ZONE);
+        assert_eq!(zone.asn_precedence, AsnPrecedence::Asn);
+        let (geoip, _) = geoip([
+            (
+                "203.0.113.1",
+                Lookup::Hit(Location {
+                    asn: Some(64500),
+                    ..located("NA", "US", Some("CA"), Some("Los Angeles"))
+                }),
+            ),
+            (
+                "203.0.113.2",
+                Lookup::Hit(Location {
+                    asn: Some(64501),
+                    ..located("NA", "US", None, None)
+                }),
+            ),
+        ]);
+        let none = CidrTable::default();
+
+        assert_eq!(
+            select(&geoip, &zone, "203.0.113.1", &none).await,
+            picked("192.0.2.20", "AS64500")
+        );
+        assert_eq!(
+            select(&geoip, &zone, "203.0.113.2", &none).await,
+            picked("192.0.2.12", "US")
+        );
     }
 
-    fn create_soa_record(&self, name: &Name, ttl: u32, zone: &ZoneConfig) -> Option<Record> {
-        zone.soa.as_ref().map(|soa_config| {
-            let rdata = RData::SOA(SOA::new(
-                Name::from_str(&soa_config.mname).unwrap(),
-                Name::from_str(&soa_config.rname).unwrap(),
-                soa_config.serial,
-                soa_config.refresh.unwrap_or(86400) as i32,
-                soa_config.retry.unwrap_or(7200) as i32,
-                soa_config.expire.unwrap_or(3600000) as i32,
-                soa_config.minimum.unwrap_or(300),
+    #[tokio::test]
+    async fn overrides_win_over_geoip_and_apply_to_private_addresses() {
+        let zone = zone(ZONE);
+        let (geoip, provider) =
+            geoip([("203.0.113.1", Lookup::Hit(located("EU", "DE", None, None)))]);
+        let mut overrides = CidrTable::default();
+        overrides.insert("203.0.113.0/24".parse().unwrap(), "US".parse().unwrap());
+        overrides.insert("10.1.0.0/16".parse().unwrap(), "US-CA".parse().unwrap());
+        overrides.insert(
+            "127.0.0.0/8".parse().unwrap(),
+            "continent:NA".parse().unwrap(),
+        );
+
+        assert_eq!(
+            select(&geoip, &zone, "203.0.113.1", &overrides).await,
+            picked("192.0.2.12", "US")
+        );
+        assert_eq!(
+            select(&geoip, &zone, "10.1.2.3", &overrides).await,
+            picked("192.0.2.11", "US-CA")
+        );
+        assert_eq!(
+            select(&geoip, &zone, "127.0.0.1", &overrides).await,
+            picked("192.0.2.13", "continent:NA")
+        );
+        assert_eq!(
+            select(&geoip, &zone, "10.2.0.1", &overrides).await,
+            default_records()
+        );
+        assert_eq!(provider.lookups(), 0);
+    }
+
+    #[tokio::test]
+    async fn miss_and_unavailable_use_their_own_fallbacks() {
+        let zone = zone(FALLBACK_ZONE);
+        let (geoip, provider) = geoip([
+            ("203.0.113.1", Lookup::Miss),
+            ("203.0.113.2", Lookup::Unavailable),
+            ("203.0.113.3", Lookup::Timeout),
+            ("203.0.113.4", Lookup::Error),
+            ("203.0.113.5", Lookup::Hit(located("EU", "DE", None, None))),
+        ]);
+        let none = CidrTable::default();
+
+        let cases = [
+            ("203.0.113.1", picked("192.0.2.80", UNKNOWN_KEY)),
+            ("203.0.113.2", picked("192.0.2.81", UNAVAILABLE_KEY)),
+            ("203.0.113.3", picked("192.0.2.81", UNAVAILABLE_KEY)),
+            ("203.0.113.4", picked("192.0.2.81", UNAVAILABLE_KEY)),
+            ("203.0.113.5", picked("192.0.2.80", UNKNOWN_KEY)),
+        ];
+        for (ip, expected) in cases {
+            assert_eq!(select(&geoip, &zone, ip, &none).await, expected, "{}", ip);
+        }
+        assert_eq!(provider.lookups(), 5);
+    }
+
+    #[tokio::test]
+    async fn fallback_sets_win_over_the_zone_policy() {
+        let (geoip, _) = geoip([
+            ("203.0.113.1", Lookup::Miss),
+            ("203.0.113.2", Lookup::Unavailable),
+        ]);
+        let none = CidrTable::default();
+        let without_sets =
+            "[www]\na = [\"192.0.2.1\"]\n\n[www.country]\nUS = { a = [\"192.0.2.12\"] }";
+        let cases = [
+            ("set-default.fallback.test", FALLBACK_ZONE, "default", "set"),
+            (
+                "set-servfail.fallback.test",
+                FALLBACK_ZONE,
+                "servfail",
+                "set",
+            ),
+            ("default.fallback.test", without_sets, "default", "default"),
+            (
+                "servfail.fallback.test",
+                without_sets,
+                "servfail",
+                "servfail",
+            ),
+        ];
+        for (zone_name, source, policy, action) in cases {
+            let zone = zone(&format!(
+                "unknown_policy = \"{policy}\"\nunavailable_policy = \"{policy}\"\n{source}"
             ));
-            Record::from_rdata(name.clone(), ttl, rdata)
-        })
-    }
-
-    fn create_ns_records(&self, name: &Name, ttl: u32, values: &[String]) -> Vec<Record> {
-        values
-            .iter()
-            .filter_map(|val| Name::from_str(val).ok())
-            .map(|ns_name| Record::from_rdata(name.clone(), ttl, RData::NS(NS(ns_name))))
-            .collect()
-    }
-
-    fn create_a_records(&self, name: &Name, ttl: u32, values: &[String]) -> Vec<Record> {
-        let mut records: Vec<_> = values
-            .iter()
-            .filter_map(|val| val.parse::<Ipv4Addr>().ok())
-            .map(|ip| Record::from_rdata(name.clone(), ttl, RData::A(A::from(ip))))
-            .collect();
-
-        records.shuffle(&mut rand::thread_rng());
-        records
-    }
-
-    fn create_aaaa_records(&self, name: &Name, ttl: u32, values: &[String]) -> Vec<Record> {
-        let mut records: Vec<_> = values
-            .iter()
-            .filter_map(|val| val.parse::<Ipv6Addr>().ok())
-            .map(|ip| Record::from_rdata(name.clone(), ttl, RData::AAAA(AAAA::from(ip))))
-            .collect();
-
-        records.shuffle(&mut rand::thread_rng());
-        records
-    }
-
-    fn create_cname_records(&self, name: &Name, ttl: u32, values: &[String]) -> Vec<Record> {
-        values
-            .iter()
-            .filter_map(|val| Name::from_str(val).ok())
-            .map(|cname| Record::from_rdata(name.clone(), ttl, RData::CNAME(CNAME(cname))))
-            .collect()
-    }
-
-    fn create_mx_records(
-        &self,
-        name: &Name,
-        ttl: u32,
-        values: &[crate::records::MXRecord],
-    ) -> Vec<Record> {
-        values
-            .iter()
-            .filter_map(|val| {
-                Name::from_str(&val.exchange)
-                    .ok()
-                    .map(|exchange| (val.preference, exchange))
-            })
-            .map(|(preference, exchange)| {
-                Record::from_rdata(name.clone(), ttl, RData::MX(MX::new(preference, exchange)))
-            })
-            .collect()
-    }
-
-    fn create_txt_records(&self, name: &Name, ttl: u32, values: &[String]) -> Vec<Record> {
-        values
-            .iter()
-            .map(|val| {
-                Record::from_rdata(name.clone(), ttl, RData::TXT(TXT::new(vec![val.clone()])))
-            })
-            .collect()
+            let node = zone.node(Some("www")).unwrap();
+            for (ip, reason, set, key) in [
+                ("203.0.113.1", "unknown", "192.0.2.80", UNKNOWN_KEY),
+                ("203.0.113.2", "unavailable", "192.0.2.81", UNAVAILABLE_KEY),
+            ] {
+                let selected =
+                    select_bundle(&geoip, ip.parse().unwrap(), zone_name, &zone, node, &none)
+                        .await
+                        .map(|(bundle, bucket)| (bundle.a[0].data().to_string(), bucket));
+                let expected = match action {
+                    "set" => picked(set, key),
+                    "default" => default_records(),
+                    _ => None,
+                };
+                assert_eq!(selected, expected, "{} {}", zone_name, reason);
+                assert_eq!(
+                    metrics::GEO_FALLBACKS.get(&[zone_name, reason, action]),
+                    1,
+                    "{} {}",
+                    zone_name,
+                    reason
+                );
+            }
+        }
     }
 }
@@ -0,0 +1,68 @@
+/* src/stats.rs */
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Process-wide server counters and gauges, updated lock-free on the hot path.
+pub struct ServerStats {
+    /// Whether the UDP and TCP listeners are bound and accepting queries.
+    pub listening: AtomicBool,
+    /// Well-formed queries received, over UDP and TCP.
+    pub queries: AtomicU64,
+    /// Queries currently being answered, over UDP and TCP.
+    pub inflight_queries: AtomicU64,
+    /// Open TCP client connections.
+    pub tcp_connections: AtomicU64,
+    /// Queries dropped or refused because `max_inflight_queries` was reached.
+    pub shed_queries: AtomicU64,
+    /// TCP connections closed right after accept because of a client limit.
+    pub tcp_rejected: AtomicU64,
+    /// TCP connections closed because a read or write took too long.
+    pub tcp_timeouts: AtomicU64,
+    /// Query log entries dropped because the writer fell behind.
+    pub query_log_dropped: AtomicU64,
+    /// dnstap frames dropped because the writer fell behind or was disconnected.
+    pub dnstap_dropped: AtomicU64,
+    /// Unix time of the last load or reload in which every zone loaded.
+    pub last_reload_success: AtomicU64,
+}
+
+impl ServerStats {
+    const fn new() -> Self {
+        Self {
+            listening: AtomicBool::new(false),
+            queries: AtomicU64::new(0),
+            inflight_queries: AtomicU64::new(0),
+            tcp_connections: AtomicU64::new(0),
+            shed_queries: AtomicU64::new(0),
+            tcp_rejected: AtomicU64::new(0),
+            tcp_timeouts: AtomicU64::new(0),
+            query_log_dropped: AtomicU64::new(0),
+            dnstap_dropped: AtomicU64::new(0),
+            last_reload_success: AtomicU64::new(0),
+        }
+    }
+}
+
+pub static SERVER: ServerStats = ServerStats::new();
+
+pub fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn decr(counter: &AtomicU64) {
+    counter.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Records that every zone just loaded successfully.
+pub fn mark_reload_success() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    SERVER.last_reload_success.store(now, Ordering::Relaxed);
+}
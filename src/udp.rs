@@ -0,0 +1,513 @@
+/* src/udp.rs */
+
+use crate::config::ServerConfig;
+use crate::dns_server::{Transport, handle_request, shed_response};
+use crate::limits::Limits;
+use crate::privacy;
+use crate::resolver::DnsResolver;
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::{Semaphore, mpsc};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+/// Largest query accepted over UDP; bigger datagrams are discarded.
+const MAX_QUERY_SIZE: usize = 4096;
+
+/// Upper bound for `udp_batch_size`, also the size of the `recvmmsg` arrays.
+const MAX_BATCH_SIZE: usize = 64;
+
+/// A received datagram: index of the buffer it landed in, its length and sender.
+type Received = (usize, usize, SocketAddr);
+
+/// An encoded response waiting to be sent.
+type Outgoing = (Vec<u8>, SocketAddr);
+
+/// Binds `count` UDP sockets to the same address, so the kernel spreads
+/// incoming queries across them.
+///
+/// SO_REUSEPORT is only set when there is more than one socket: it is what
+/// lets them share the port, but it would also let any other process of the
+/// same user bind the port and take a share of the queries. Port 0 picks one
+/// free port for all of them.
+pub fn bind_sockets(addr: SocketAddr, count: usize) -> io::Result<Vec<UdpSocket>> {
+    let reuse_port = count > 1;
+    let first = bind(addr, reuse_port)?;
+    let addr = first.local_addr()?;
+    let mut sockets = vec![first];
+    for _ in 1..count {
+        sockets.push(bind(addr, reuse_port)?);
+    }
+    Ok(sockets)
+}
+
+fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Starts one receive and one send task per socket. The tasks run on the
+/// shared Tokio runtime, whose threads pick them up as datagrams arrive; the
+/// sockets only keep one busy receive loop from serializing every query.
+pub fn spawn_workers(
+    sockets: Vec<UdpSocket>,
+    resolver: Arc<DnsResolver>,
+    limits: Arc<Limits>,
+    config: &ServerConfig,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+) {
+    let batch = config.udp_batch_size.clamp(1, MAX_BATCH_SIZE);
+    let max_inflight = config.udp_max_inflight.max(1);
+
+    info!(
+        "UDP: {} worker(s), batch size {}, up to {} queries in flight per worker",
+        sockets.len(),
+        batch,
+        max_inflight
+    );
+
+    for socket in sockets {
+        let socket = Arc::new(socket);
+        let (tx, rx) = mpsc::channel(max_inflight);
+        tasks.spawn(send_loop(socket.clone(), rx, batch));
+        tasks.spawn(recv_loop(
+            socket,
+            tx,
+            resolver.clone(),
+            limits.clone(),
+            Worker {
+                batch,
+                max_inflight,
+                shutdown: shutdown.clone(),
+                tasks: tasks.clone(),
+            },
+        ));
+    }
+}
+
+/// Per-socket settings and shutdown handles for a receive loop.
+struct Worker {
+    batch: usize,
+    max_inflight: usize,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+}
+
+/// Recycles query buffers so the receive path does not allocate per packet.
+struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    fn new(capacity: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    fn take(&self) -> Vec<u8> {
+        let mut buf = self.buffers.lock().pop().unwrap_or_default();
+        buf.resize(MAX_QUERY_SIZE, 0);
+        buf
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        let mut buffers = self.buffers.lock();
+        if buffers.len() < self.capacity {
+            buffers.push(buf);
+        }
+    }
+}
+
+async fn recv_loop(
+    socket: Arc<UdpSocket>,
+    responses: mpsc::Sender<Outgoing>,
+    resolver: Arc<DnsResolver>,
+    limits: Arc<Limits>,
+    worker: Worker,
+) {
+    let Worker {
+        batch,
+        max_inflight,
+        shutdown,
+        tasks,
+    } = worker;
+    let inflight = Arc::new(Semaphore::new(max_inflight));
+    let pool = Arc::new(BufferPool::new(max_inflight + batch));
+    let mut bufs: Vec<Vec<u8>> = (0..batch).map(|_| pool.take()).collect();
+    let mut received: Vec<Received> = Vec::with_capacity(batch);
+
+    loop {
+        received.clear();
+        let result = tokio::select! {
+            // Returning drops the sender; the send task exits once every
+            // spawned query has delivered its response.
+            _ = shutdown.cancelled() => return,
+            result = recv_batch(&socket, &mut bufs, &mut received) => result,
+        };
+        if let Err(e) = result {
+            warn!("Failed to receive UDP packets: {}", e);
+            continue;
+        }
+
+        for &(index, len, addr) in &received {
+            // Waiting here stops reading from the socket, so a flood backs up
+            // into the kernel buffer instead of spawning unbounded tasks.
+            let permit = match inflight.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+            let Some(guard) = limits.try_begin_query() else {
+                drop(permit);
+                let data = &bufs[index][..len];
+                if let Some(bytes) = shed_response(data, limits.overload_policy()) {
+                    let _ = responses.try_send((bytes, addr));
+                }
+                continue;
+            };
+            let mut data = std::mem::replace(&mut bufs[index], pool.take());
+            data.truncate(len);
+
+            let resolver = resolver.clone();
+            let responses = responses.clone();
+            let pool = pool.clone();
+            tasks.spawn(async move {
+                if let Some(response_bytes) =
+                    handle_request(&data, addr, Transport::Udp, resolver).await
+                {
+                    let _ = responses.send((response_bytes, addr)).await;
+                }
+                pool.put(data);
+                drop(guard);
+                drop(permit);
+            });
+        }
+    }
+}
+
+async fn send_loop(socket: Arc<UdpSocket>, mut responses: mpsc::Receiver<Outgoing>, batch: usize) {
+    let mut pending = Vec::with_capacity(batch);
+    while responses.recv_many(&mut pending, batch).await > 0 {
+        send_batch(&socket, &pending).await;
+        pending.clear();
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn recv_batch(
+    socket: &UdpSocket,
+    bufs: &mut [Vec<u8>],
+    out: &mut Vec<Received>,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || {
+            sys::recvmmsg(socket.as_raw_fd(), bufs, out)
+        }) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn recv_batch(
+    socket: &UdpSocket,
+    bufs: &mut [Vec<u8>],
+    out: &mut Vec<Received>,
+) -> io::Result<()> {
+    let (len, addr) = socket.recv_from(&mut bufs[0]).await?;
+    out.push((0, len, addr));
+    for (index, buf) in bufs.iter_mut().enumerate().skip(1) {
+        match socket.try_recv_from(buf) {
+            Ok((len, addr)) => out.push((index, len, addr)),
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+async fn send_batch(socket: &UdpSocket, pending: &[Outgoing]) {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    let mut sent = 0;
+    while sent < pending.len() {
+        if socket.writable().await.is_err() {
+            return;
+        }
+        match socket.try_io(Interest::WRITABLE, || {
+            sys::sendmmsg(socket.as_raw_fd(), &pending[sent..])
+        }) {
+            Ok(count) => sent += count.max(1),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => {
+                // The first remaining message failed; skip it and keep going.
+                error!(
+                    "Failed to send UDP response to {}: {}",
+                    privacy::display(pending[sent].1),
+                    e
+                );
+                sent += 1;
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn send_batch(socket: &UdpSocket, pending: &[Outgoing]) {
+    for (bytes, addr) in pending {
+        if let Err(e) = socket.send_to(bytes, addr).await {
+            error!(
+                "Failed to send UDP response to {}: {}",
+                privacy::display(*addr),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::{MAX_BATCH_SIZE, Outgoing, Received};
+    use socket2::SockAddr;
+    use std::io;
+    use std::mem;
+    use std::os::fd::RawFd;
+
+    /// Receives up to `bufs.len()` datagrams with a single `recvmmsg` call.
+    pub fn recvmmsg(fd: RawFd, bufs: &mut [Vec<u8>], out: &mut Vec<Received>) -> io::Result<()> {
+        let count = bufs.len().min(MAX_BATCH_SIZE);
+        // SAFETY: all-zero is a valid value for these plain C structs.
+        let mut addrs: [libc::sockaddr_storage; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for (i, buf) in bufs.iter_mut().take(count).enumerate() {
+            iovecs[i].iov_base = buf.as_mut_ptr().cast();
+            iovecs[i].iov_len = buf.len();
+            msgs[i].msg_hdr.msg_name = (&mut addrs[i] as *mut libc::sockaddr_storage).cast();
+            msgs[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: each header points at a live iovec and address slot from the
+        // arrays above, and each iovec at a buffer of exactly `iov_len` bytes.
+        let received = unsafe {
+            libc::recvmmsg(
+                fd,
+                msgs.as_mut_ptr(),
+                count as _,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (i, msg) in msgs.iter().take(received as usize).enumerate() {
+            if msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                continue;
+            }
+            // SAFETY: the kernel filled `addrs[i]` and set `msg_namelen`.
+            let addr = unsafe { SockAddr::new(addrs[i], msg.msg_hdr.msg_namelen) };
+            if let Some(addr) = addr.as_socket() {
+                out.push((i, msg.msg_len as usize, addr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends as many of `pending` as the kernel accepts in one `sendmmsg` call
+    /// and returns how many went out.
+    pub fn sendmmsg(fd: RawFd, pending: &[Outgoing]) -> io::Result<usize> {
+        let count = pending.len().min(MAX_BATCH_SIZE);
+        let addrs: Vec<SockAddr> = pending[..count]
+            .iter()
+            .map(|(_, addr)| SockAddr::from(*addr))
+            .collect();
+        // SAFETY: all-zero is a valid value for these plain C structs.
+        let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for (i, ((bytes, _), addr)) in pending.iter().zip(&addrs).enumerate() {
+            iovecs[i].iov_base = bytes.as_ptr() as *mut _;
+            iovecs[i].iov_len = bytes.len();
+            msgs[i].msg_hdr.msg_name = addr.as_ptr() as *mut _;
+            msgs[i].msg_hdr.msg_namelen = addr.len();
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: the headers point at addresses and payloads that outlive the
+        // call; the kernel only reads through them.
+        let sent = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), count as _, libc::MSG_DONTWAIT) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, GeoIpCacheConfig, LimitsConfig};
+    use crate::geo_provider::NoopProvider;
+    use crate::geoip::GeoIpClient;
+    use crate::location::GeoGroups;
+    use crate::records::ZoneConfig;
+    use crate::zone::CompiledZone;
+    use hickory_proto::op::{Message, Query};
+    use hickory_proto::rr::{Name, RecordType};
+    use std::collections::{HashMap, HashSet};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    fn resolver() -> Arc<DnsResolver> {
+        let zone: ZoneConfig = toml::from_str("[www]\na = [\"192.0.2.1\"]\n").unwrap();
+        let zone = CompiledZone::compile("example.com", &zone, 5, &GeoGroups::default()).unwrap();
+        let config =
+            AppConfig::with_zones(HashMap::from([("example.com".to_string(), Arc::new(zone))]));
+        Arc::new(DnsResolver::new(
+            Arc::new(config),
+            Arc::new(GeoIpClient::new(
+                Arc::new(NoopProvider),
+                &GeoIpCacheConfig::default(),
+            )),
+            None,
+            None,
+        ))
+    }
+
+    fn request(id: u16) -> Vec<u8> {
+        let mut message = Message::new();
+        message.set_id(id).add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        message.to_vec().unwrap()
+    }
+
+    #[test]
+    fn binds_every_worker_to_the_same_port() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+        let sockets = bind_sockets("127.0.0.1:0".parse().unwrap(), 3).unwrap();
+        let port = sockets[0].local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        assert!(
+            sockets
+                .iter()
+                .all(|socket| socket.local_addr().unwrap().port() == port)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn answers_every_query_in_a_burst_exactly_once() {
+        const QUERIES: u16 = 200;
+        let sockets = bind_sockets("127.0.0.1:0".parse().unwrap(), 2).unwrap();
+        let server = sockets[0].local_addr().unwrap();
+        let config = ServerConfig {
+            udp_workers: 2,
+            udp_batch_size: 8,
+            udp_max_inflight: 4,
+            ..ServerConfig::default()
+        };
+        let limits = Arc::new(Limits::new(LimitsConfig::default()));
+        let shutdown = CancellationToken::new();
+        let tasks = TaskTracker::new();
+        spawn_workers(
+            sockets,
+            resolver(),
+            limits,
+            &config,
+            shutdown.clone(),
+            tasks.clone(),
+        );
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for id in 0..QUERIES {
+            client.send_to(&request(id), server).await.unwrap();
+        }
+
+        let mut seen = HashSet::new();
+        let mut buf = [0u8; 512];
+        while seen.len() < QUERIES as usize {
+            let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .expect("missing replies")
+                .unwrap();
+            let response = Message::from_vec(&buf[..len]).unwrap();
+            assert!(
+                seen.insert(response.id()),
+                "duplicate reply {}",
+                response.id()
+            );
+        }
+        let extra = tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buf)).await;
+        assert!(extra.is_err(), "unexpected extra reply");
+
+        // Cancelling stops the workers, so the tracker drains.
+        shutdown.cancel();
+        tasks.close();
+        tokio::time::timeout(Duration::from_secs(5), tasks.wait())
+            .await
+            .expect("UDP workers did not stop on shutdown");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn batches_datagrams_through_mmsg_calls() {
+        use std::net::UdpSocket;
+        use std::os::fd::AsRawFd;
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        let payloads: Vec<Outgoing> = (0..3u8)
+            .map(|i| (vec![i; 10 + i as usize], server_addr))
+            .collect();
+        assert_eq!(sys::sendmmsg(client.as_raw_fd(), &payloads).unwrap(), 3);
+        // Too big for the receive buffers below, so it is dropped as truncated.
+        client.send_to(&[9; 64], server_addr).unwrap();
+
+        let mut bufs = vec![vec![0; 32]; 2];
+        let mut out = Vec::new();
+        sys::recvmmsg(server.as_raw_fd(), &mut bufs, &mut out).unwrap();
+        assert_eq!(out, vec![(0, 10, client_addr), (1, 11, client_addr)]);
+        assert_eq!(&bufs[1][..11], &[1; 11]);
+
+        out.clear();
+        sys::recvmmsg(server.as_raw_fd(), &mut bufs, &mut out).unwrap();
+        assert_eq!(out, vec![(0, 12, client_addr)]);
+    }
+}
@@ -0,0 +1,578 @@
+/* src/unix_http.rs */
+
+use parking_lot::Mutex;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::time::{Instant, timeout};
+
+/// Longest status line or header line accepted.
+const MAX_LINE: usize = 8 * 1024;
+/// Most header lines accepted in one response.
+const MAX_HEADERS: usize = 64;
+/// Largest response body accepted.
+const MAX_BODY: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum HttpError {
+    Connect(io::Error),
+    Io(io::Error),
+    Timeout,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::Connect(e) => write!(f, "connect failed: {}", e),
+            HttpError::Io(e) => write!(f, "{}", e),
+            HttpError::Timeout => write!(f, "timed out"),
+            HttpError::Malformed(what) => write!(f, "malformed response: {}", what),
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(e: io::Error) -> Self {
+        HttpError::Io(e)
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+struct Idle {
+    conn: BufReader<UnixStream>,
+    since: Instant,
+}
+
+/// A minimal HTTP/1.1 client for a server on a Unix socket, keeping up to
+/// `max_idle` keep-alive connections for reuse.
+pub struct UnixHttpPool {
+    socket_path: String,
+    max_idle: usize,
+    idle_timeout: Duration,
+    idle: Mutex<Vec<Idle>>,
+}
+
+impl UnixHttpPool {
+    pub fn new(socket_path: String, max_idle: usize, idle_timeout: Duration) -> Self {
+        Self {
+            socket_path,
+            max_idle,
+            idle_timeout,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn socket_path(&self) -> &str {
+        &self.socket_path
+    }
+
+    /// Sends `GET target` and reads the whole response, all within `deadline`.
+    pub async fn get(&self, target: &str, deadline: Duration) -> Result<Response, HttpError> {
+        timeout(deadline, self.get_inner(target))
+            .await
+            .unwrap_or(Err(HttpError::Timeout))
+    }
+
+    async fn get_inner(&self, target: &str) -> Result<Response, HttpError> {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n",
+            target
+        );
+
+        // The server may have closed an idle connection since it was pooled;
+        // that shows up as an error before any byte of the response, and is
+        // worth one retry on a fresh connection.
+        if let Some(mut conn) = self.take_idle() {
+            match exchange(&mut conn, &request).await {
+                Ok((response, keep_alive)) => {
+                    self.put_idle(conn, keep_alive);
+                    return Ok(response);
+                }
+                Err(Exchange::Stale) => {}
+                Err(Exchange::Failed(e)) => return Err(e),
+            }
+        }
+
+        let stream = UnixStream::connect(&self.socket_path)
+            .await
+            .map_err(HttpError::Connect)?;
+        let mut conn = BufReader::new(stream);
+        match exchange(&mut conn, &request).await {
+            Ok((response, keep_alive)) => {
+                self.put_idle(conn, keep_alive);
+                Ok(response)
+            }
+            Err(Exchange::Stale) => Err(HttpError::Malformed("connection closed")),
+            Err(Exchange::Failed(e)) => Err(e),
+        }
+    }
+
+    fn take_idle(&self) -> Option<BufReader<UnixStream>> {
+        let mut idle = self.idle.lock();
+        while let Some(entry) = idle.pop() {
+            if entry.since.elapsed() < self.idle_timeout {
+                return Some(entry.conn);
+            }
+        }
+        None
+    }
+
+    fn put_idle(&self, conn: BufReader<UnixStream>, keep_alive: bool) {
+        if !keep_alive || !conn.buffer().is_empty() {
+            return;
+        }
+        let mut idle = self.idle.lock();
+        if idle.len() < self.max_idle {
+            idle.push(Idle {
+                conn,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Closes every pooled connection.
+    pub fn clear(&self) {
+        self.idle.lock().clear();
+    }
+}
+
+enum Exchange {
+    /// The connection failed before the response started.
+    Stale,
+    Failed(HttpError),
+}
+
+/// Writes `request` and reads one response. Also returns whether the
+/// connection can carry another request.
+async fn exchange(
+    conn: &mut BufReader<UnixStream>,
+    request: &str,
+) -> Result<(Response, bool), Exchange> {
+    if conn.get_mut().write_all(request.as_bytes()).await.is_err() {
+        return Err(Exchange::Stale);
+    }
+
+    let status_line = match read_line(conn).await {
+        Ok(Some(line)) => line,
+        Ok(None) | Err(HttpError::Io(_)) => return Err(Exchange::Stale),
+        Err(e) => return Err(Exchange::Failed(e)),
+    };
+    read_response(conn, &status_line)
+        .await
+        .map_err(Exchange::Failed)
+}
+
+async fn read_response(
+    conn: &mut BufReader<UnixStream>,
+    status_line: &str,
+) -> Result<(Response, bool), HttpError> {
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    let status = parts
+        .next()
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or(HttpError::Malformed("bad status line"))?;
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::Malformed("not HTTP/1.x"));
+    }
+
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut keep_alive = version == "HTTP/1.1";
+    for _ in 0..=MAX_HEADERS {
+        let line = read_line(conn)
+            .await?
+            .ok_or(HttpError::Malformed("truncated headers"))?;
+        if line.is_empty() {
+            let body = if chunked {
+                read_chunked(conn).await?
+            } else if let Some(len) = content_length {
+                if len > MAX_BODY {
+                    return Err(HttpError::Malformed("body too large"));
+                }
+                let mut body = vec![0; len];
+                conn.read_exact(&mut body).await?;
+                body
+            } else {
+                // Delimited by the end of the connection.
+                keep_alive = false;
+                let mut body = Vec::new();
+                conn.take(MAX_BODY as u64 + 1)
+                    .read_to_end(&mut body)
+                    .await?;
+                if body.len() > MAX_BODY {
+                    return Err(HttpError::Malformed("body too large"));
+                }
+                body
+            };
+            return Ok((Response { status, body }, keep_alive));
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(HttpError::Malformed("bad header line"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(
+                value
+                    .parse::<usize>()
+                    .map_err(|_| HttpError::Malformed("bad Content-Length"))?,
+            );
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value
+                .rsplit(',')
+                .next()
+                .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        } else if name.eq_ignore_ascii_case("connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+        }
+    }
+    Err(HttpError::Malformed("too many headers"))
+}
+
+async fn read_chunked(conn: &mut BufReader<UnixStream>) -> Result<Vec<u8>, HttpError> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(conn)
+            .await?
+            .ok_or(HttpError::Malformed("truncated chunk"))?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size =
+            usize::from_str_radix(size, 16).map_err(|_| HttpError::Malformed("bad chunk size"))?;
+        if size == 0 {
+            // Skip trailers up to the final empty line.
+            for _ in 0..=MAX_HEADERS {
+                match read_line(conn).await? {
+                    Some(line) if line.is_empty() => return Ok(body),
+                    Some(_) => {}
+                    None => return Err(HttpError::Malformed("truncated trailers")),
+                }
+            }
+            return Err(HttpError::Malformed("too many trailers"));
+        }
+        if body.len() + size > MAX_BODY {
+            return Err(HttpError::Malformed("body too large"));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        conn.read_exact(&mut body[start..]).await?;
+        let mut crlf = [0; 2];
+        conn.read_exact(&mut crlf).await?;
+        if &crlf != b"\r\n" {
+            return Err(HttpError::Malformed("bad chunk terminator"));
+        }
+    }
+}
+
+/// Reads one CRLF- or LF-terminated line, without the terminator. `None` at
+/// the end of the stream.
+async fn read_line(conn: &mut BufReader<UnixStream>) -> Result<Option<String>, HttpError> {
+    let mut line = Vec::new();
+    let read = (&mut *conn)
+        .take(MAX_LINE as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(if line.len() > MAX_LINE {
+            HttpError::Malformed("line too long")
+        } else {
+            HttpError::Malformed("truncated line")
+        });
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| HttpError::Malformed("non-UTF-8 header"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::UnixListener;
+
+    /// Parses `raw` as a response sent by a peer that closes its end after it.
+    async fn parse(raw: &[u8]) -> Result<(Response, bool), HttpError> {
+        let (mut server, client) = UnixStream::pair().unwrap();
+        let raw = raw.to_vec();
+        tokio::spawn(async move {
+            let _ = server.write_all(&raw).await;
+        });
+        let mut conn = BufReader::new(client);
+        let status_line = read_line(&mut conn).await?.unwrap();
+        read_response(&mut conn, &status_line).await
+    }
+
+    #[tokio::test]
+    async fn reads_content_length_body() {
+        let (response, keep_alive) =
+            parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloEXTRA")
+                .await
+                .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+        assert!(keep_alive);
+    }
+
+    #[tokio::test]
+    async fn reads_chunked_body_with_extensions_and_trailers() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+            5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let (response, keep_alive) = parse(raw).await.unwrap();
+        assert_eq!(response.body, b"hello, world");
+        assert!(keep_alive);
+    }
+
+    #[tokio::test]
+    async fn reads_close_delimited_body() {
+        let (response, keep_alive) = parse(b"HTTP/1.1 404 Not Found\r\n\r\nnot here")
+            .await
+            .unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"not here");
+        assert!(!keep_alive);
+    }
+
+    #[tokio::test]
+    async fn honors_connection_header_and_version() {
+        let raw = b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+        assert!(!parse(raw).await.unwrap().1);
+        let raw = b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n";
+        assert!(!parse(raw).await.unwrap().1);
+        let raw = b"HTTP/1.0 200 OK\r\nConnection: Keep-Alive\r\nContent-Length: 0\r\n\r\n";
+        assert!(parse(raw).await.unwrap().1);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_responses() {
+        let result = parse(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort").await;
+        assert!(matches!(result, Err(HttpError::Io(_))));
+        let result = parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel").await;
+        assert!(matches!(result, Err(HttpError::Io(_))));
+        let result = parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n").await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("truncated headers"))
+        ));
+        let result = parse(b"HTTP/1.1 200 OK\r\nContent-Len").await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("truncated line"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_responses() {
+        let raw = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        let result = parse(raw.as_bytes()).await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("body too large"))
+        ));
+
+        let raw = format!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+            MAX_BODY + 1
+        );
+        let result = parse(raw.as_bytes()).await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("body too large"))
+        ));
+
+        let mut raw = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        raw.resize(raw.len() + MAX_BODY + 1, b'x');
+        let result = parse(&raw).await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("body too large"))
+        ));
+
+        let raw = format!(
+            "HTTP/1.1 200 OK\r\nX-Long: {}\r\n\r\n",
+            "x".repeat(MAX_LINE)
+        );
+        let result = parse(raw.as_bytes()).await;
+        assert!(matches!(result, Err(HttpError::Malformed("line too long"))));
+
+        let raw = format!(
+            "HTTP/1.1 200 OK\r\n{}\r\n",
+            "X-Header: 1\r\n".repeat(MAX_HEADERS + 1)
+        );
+        let result = parse(raw.as_bytes()).await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("too many headers"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_framing() {
+        let result = parse(b"HTTP/1.1 200 OK\r\nContent-Length: five\r\n\r\n").await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("bad Content-Length"))
+        ));
+        let result = parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("bad chunk size"))
+        ));
+        let result =
+            parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhiXX0\r\n\r\n").await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("bad chunk terminator"))
+        ));
+        let result = parse(b"SPDY/3 200 OK\r\n\r\n").await;
+        assert!(matches!(result, Err(HttpError::Malformed("not HTTP/1.x"))));
+    }
+
+    /// How the test server treats each connection.
+    #[derive(Clone, Copy)]
+    enum Serve {
+        /// Answers every request and keeps the connection open.
+        KeepAlive,
+        /// Answers one request, then closes the connection.
+        Once,
+        /// Reads the request and closes the connection without answering.
+        Hangup,
+        /// Reads the request and never answers.
+        Silent,
+    }
+
+    /// Serves `ok` on a fresh socket; returns its path and a count of the
+    /// connections accepted.
+    fn serve(name: &str, mode: Serve) -> (PathBuf, Arc<AtomicUsize>) {
+        let path =
+            std::env::temp_dir().join(format!("lazy-dns-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut conn = BufReader::new(stream);
+                    loop {
+                        // Read one request, up to its empty line.
+                        loop {
+                            match read_line(&mut conn).await {
+                                Ok(Some(line)) if line.is_empty() => break,
+                                Ok(Some(_)) => {}
+                                _ => return,
+                            }
+                        }
+                        match mode {
+                            Serve::Hangup => return,
+                            Serve::Silent => {
+                                std::future::pending::<()>().await;
+                            }
+                            Serve::KeepAlive | Serve::Once => {
+                                let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                                if conn.get_mut().write_all(response).await.is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                        if matches!(mode, Serve::Once) {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (path, accepted)
+    }
+
+    fn pool(path: &std::path::Path) -> UnixHttpPool {
+        UnixHttpPool::new(
+            path.to_string_lossy().into_owned(),
+            4,
+            Duration::from_secs(60),
+        )
+    }
+
+    const DEADLINE: Duration = Duration::from_secs(2);
+
+    #[tokio::test]
+    async fn reuses_keep_alive_connections() {
+        let (path, accepted) = serve("http-keepalive", Serve::KeepAlive);
+        let pool = pool(&path);
+        for _ in 0..3 {
+            let response = pool.get("/", DEADLINE).await.unwrap();
+            assert_eq!(
+                (response.status, response.body.as_slice()),
+                (200, &b"ok"[..])
+            );
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn retries_once_on_a_stale_connection() {
+        let (path, accepted) = serve("http-stale", Serve::Once);
+        let pool = pool(&path);
+        pool.get("/", DEADLINE).await.unwrap();
+        // Give the server time to close the pooled connection.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = pool.get("/", DEADLINE).await.unwrap();
+        assert_eq!(response.body, b"ok");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_a_fresh_connection() {
+        let (path, accepted) = serve("http-hangup", Serve::Hangup);
+        let result = pool(&path).get("/", DEADLINE).await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Malformed("connection closed"))
+        ));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn gives_up_at_the_deadline() {
+        let (path, _) = serve("http-silent", Serve::Silent);
+        let result = pool(&path).get("/", Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(HttpError::Timeout)));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn reports_connect_failures() {
+        let path =
+            std::env::temp_dir().join(format!("lazy-dns-http-none-{}.sock", std::process::id()));
+        let result = pool(&path).get("/", DEADLINE).await;
+        assert!(matches!(result, Err(HttpError::Connect(_))));
+    }
+}
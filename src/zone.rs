@@ -0,0 +1,851 @@
+/* src/zone.rs */
+
+use crate::location::{GeoGroups, LocationKey, distance_km, parse_asn};
+use crate::records::{AsnPrecedence, FallbackPolicy, MXRecord, PopConfig, RecordSet, ZoneConfig};
+use hickory_proto::rr::rdata::{A, AAAA, CNAME, MX, NS, SOA, TXT};
+use hickory_proto::rr::{Name, RData, Record};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Maximum length of a single TXT character-string on the wire.
+const MAX_TXT_STRING_LEN: usize = 255;
+
+/// `country` key of the records served when no other key matches a client.
+pub const UNKNOWN_KEY: &str = "_unknown";
+/// `country` key of the records served when GeoIP cannot be reached.
+pub const UNAVAILABLE_KEY: &str = "_unavailable";
+
+/// Error raised when a zone file contains data that cannot be served.
+#[derive(Debug)]
+pub enum ZoneError {
+    InvalidOwner {
+        owner: String,
+        reason: String,
+    },
+    InvalidName {
+        location: String,
+        field: &'static str,
+        value: String,
+        reason: String,
+    },
+    InvalidAddress {
+        location: String,
+        field: &'static str,
+        value: String,
+    },
+    InvalidTxt {
+        location: String,
+        len: usize,
+    },
+    InvalidTtl {
+        ttl: u32,
+    },
+    InvalidLocationKey {
+        location: String,
+        reason: String,
+    },
+    InvalidPop {
+        location: String,
+        reason: String,
+    },
+    MissingSoa,
+}
+
+impl fmt::Display for ZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZoneError::InvalidOwner { owner, reason } => {
+                write!(f, "invalid owner name '{}': {}", owner, reason)
+            }
+            ZoneError::InvalidName {
+                location,
+                field,
+                value,
+                reason,
+            } => write!(
+                f,
+                "invalid {} name '{}' at {}: {}",
+                field, value, location, reason
+            ),
+            ZoneError::InvalidAddress {
+                location,
+                field,
+                value,
+            } => write!(f, "invalid {} address '{}' at {}", field, value, location),
+            ZoneError::InvalidTxt { location, len } => write!(
+                f,
+                "TXT string at {} is {} bytes, longer than {}",
+                location, len, MAX_TXT_STRING_LEN
+            ),
+            ZoneError::InvalidTtl { ttl } => {
+                write!(
+                    f,
+                    "ttl of {} minutes does not fit in 32 bits of seconds",
+                    ttl
+                )
+            }
+            ZoneError::InvalidLocationKey { location, reason } => {
+                write!(f, "invalid GeoIP key at {}: {}", location, reason)
+            }
+            ZoneError::InvalidPop { location, reason } => {
+                write!(f, "invalid PoP at {}: {}", location, reason)
+            }
+            ZoneError::MissingSoa => write!(f, "zone has NS records but no SOA record"),
+        }
+    }
+}
+
+impl std::error::Error for ZoneError {}
+
+/// Ready-made records for one owner name, grouped by type.
+///
+/// Every field is shared, so GeoIP bundles that only override a few types
+/// reuse the default slices instead of copying them.
+#[derive(Debug, Clone, Default)]
+pub struct RecordBundle {
+    pub a: Arc<[Record]>,
+    pub aaaa: Arc<[Record]>,
+    pub cname: Arc<[Record]>,
+    pub mx: Arc<[Record]>,
+    pub txt: Arc<[Record]>,
+    pub ns: Arc<[Record]>,
+}
+
+impl RecordBundle {
+    pub fn len(&self) -> usize {
+        self.a.len()
+            + self.aaaa.len()
+            + self.cname.len()
+            + self.mx.len()
+            + self.txt.len()
+            + self.ns.len()
+    }
+
+    /// Layers a GeoIP override on top of this bundle. Non-empty fields in the
+    /// override replace the default ones; NS records are never overridden.
+    fn merged_with(&self, overrides: &RecordBundle) -> RecordBundle {
+        let pick = |over: &Arc<[Record]>, base: &Arc<[Record]>| {
+            if over.is_empty() {
+                base.clone()
+            } else {
+                over.clone()
+            }
+        };
+        RecordBundle {
+            a: pick(&overrides.a, &self.a),
+            aaaa: pick(&overrides.aaaa, &self.aaaa),
+            cname: pick(&overrides.cname, &self.cname),
+            mx: pick(&overrides.mx, &self.mx),
+            txt: pick(&overrides.txt, &self.txt),
+            ns: self.ns.clone(),
+        }
+    }
+}
+
+/// A single owner name (apex or subdomain) with its default and GeoIP bundles.
+#[derive(Debug)]
+pub struct CompiledNode {
+    pub default: Arc<RecordBundle>,
+    /// Normalized location key (`JP`, `US-CA`, `continent:EU`,
+    /// `city:US/Los Angeles`) to fully merged bundle, groups expanded.
+    pub country: HashMap<String, Arc<RecordBundle>>,
+    /// Autonomous system number to fully merged bundle.
+    pub asn: HashMap<u32, Arc<RecordBundle>>,
+    /// Points of presence, sorted by name.
+    pub pops: Vec<Pop>,
+    /// The `_unknown` entry, fully merged.
+    pub unknown: Option<Arc<RecordBundle>>,
+    /// The `_unavailable` entry, fully merged.
+    pub unavailable: Option<Arc<RecordBundle>>,
+}
+
+/// A point of presence of one owner name, for nearest-PoP routing.
+#[derive(Debug)]
+pub struct Pop {
+    pub name: String,
+    /// Latitude and longitude in degrees.
+    pub coordinates: (f64, f64),
+    pub weight: f64,
+    /// Fully merged with the owner's default records.
+    pub bundle: Arc<RecordBundle>,
+    /// Records defined for the PoP itself.
+    record_count: usize,
+}
+
+impl CompiledNode {
+    pub fn has_geo(&self) -> bool {
+        !self.country.is_empty()
+            || !self.asn.is_empty()
+            || !self.pops.is_empty()
+            || self.unknown.is_some()
+            || self.unavailable.is_some()
+    }
+
+    /// The PoP closest to `coordinates` once distances are divided by the
+    /// PoPs' weights. Ties go to the first PoP by name.
+    pub fn nearest_pop(&self, coordinates: (f64, f64)) -> Option<&Pop> {
+        self.pops
+            .iter()
+            .map(|pop| (distance_km(coordinates, pop.coordinates) / pop.weight, pop))
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, pop)| pop)
+    }
+}
+
+/// Immutable, query-ready form of a `ZoneConfig`.
+#[derive(Debug)]
+pub struct CompiledZone {
+    pub soa: Option<Record>,
+    pub apex: CompiledNode,
+    pub subdomains: HashMap<String, CompiledNode>,
+    pub asn_precedence: AsnPrecedence,
+    pub unknown_policy: FallbackPolicy,
+    pub unavailable_policy: FallbackPolicy,
+    /// Hash of the zone source, used to tell real changes from mere touches.
+    pub fingerprint: u64,
+    /// Records defined in the zone, GeoIP overrides included.
+    pub record_count: usize,
+    pub loaded_at: SystemTime,
+}
+
+impl CompiledZone {
+    /// Validates a zone and turns every value into hickory records.
+    /// Keys of `country` tables that name a `[geo_groups]` group are expanded
+    /// into its members.
+    pub fn compile(
+        domain: &str,
+        zone: &ZoneConfig,
+        default_ttl: u32,
+        groups: &GeoGroups,
+    ) -> Result<Self, ZoneError> {
+        if !zone.apex.ns.is_empty() && zone.soa.is_none() {
+            return Err(ZoneError::MissingSoa);
+        }
+
+        let ttl_minutes = zone.ttl.unwrap_or(default_ttl);
+        let ttl = ttl_minutes
+            .checked_mul(60)
+            .ok_or(ZoneError::InvalidTtl { ttl: ttl_minutes })?;
+
+        let origin = parse_owner(domain)?;
+
+        let soa = match &zone.soa {
+            Some(soa_config) => {
+                let mname = parse_name("@ (soa)", "mname", &soa_config.mname)?;
+                let rname = parse_name("@ (soa)", "rname", &soa_config.rname)?;
+                let rdata = RData::SOA(SOA::new(
+                    mname,
+                    rname,
+                    soa_config.serial,
+                    soa_config.refresh.unwrap_or(86400) as i32,
+                    soa_config.retry.unwrap_or(7200) as i32,
+                    soa_config.expire.unwrap_or(3600000) as i32,
+                    soa_config.minimum.unwrap_or(300),
+                ));
+                Some(Record::from_rdata(origin.clone(), ttl, rdata))
+            }
+            None => None,
+        };
+
+        let (mut apex, mut record_count) = compile_node(
+            &origin,
+            "@",
+            &zone.apex,
+            &zone.country,
+            &zone.asn,
+            groups,
+            ttl,
+        )?;
+        apex.pops = compile_pops(&origin, "@", &zone.pops, &apex.default, ttl)?;
+        record_count += usize::from(soa.is_some());
+        record_count += apex.pops.iter().map(|pop| pop.record_count).sum::<usize>();
+
+        let mut subdomains = HashMap::with_capacity(zone.subdomains.len());
+        for (label, sub) in &zone.subdomains {
+            let label = label.to_lowercase();
+            if subdomains.contains_key(&label) {
+                return Err(ZoneError::InvalidOwner {
+                    owner: label,
+                    reason: "listed twice; names are case-insensitive".to_string(),
+                });
+            }
+            let owner = parse_owner(&format!("{}.{}", label, domain))?;
+            let (mut node, mut count) = compile_node(
+                &owner,
+                &label,
+                &sub.records,
+                &sub.country,
+                &sub.asn,
+                groups,
+                ttl,
+            )?;
+            node.pops = compile_pops(&owner, &label, &sub.pops, &node.default, ttl)?;
+            count += node.pops.iter().map(|pop| pop.record_count).sum::<usize>();
+            record_count += count;
+            subdomains.insert(label, node);
+        }
+
+        Ok(CompiledZone {
+            soa,
+            apex,
+            subdomains,
+            asn_precedence: zone.asn_precedence.unwrap_or_default(),
+            unknown_policy: zone.unknown_policy.unwrap_or_default(),
+            unavailable_policy: zone.unavailable_policy.unwrap_or_default(),
+            fingerprint: 0,
+            record_count,
+            loaded_at: SystemTime::now(),
+        })
+    }
+
+    pub fn serial(&self) -> Option<u32> {
+        match self.soa.as_ref()?.data() {
+            RData::SOA(soa) => Some(soa.serial()),
+            _ => None,
+        }
+    }
+
+    /// Replaces the SOA serial, keeping every other SOA field.
+    pub fn set_serial(&mut self, serial: u32) {
+        if let Some(record) = &mut self.soa
+            && let RData::SOA(soa) = record.data()
+        {
+            let rdata = RData::SOA(SOA::new(
+                soa.mname().clone(),
+                soa.rname().clone(),
+                serial,
+                soa.refresh(),
+                soa.retry(),
+                soa.expire(),
+                soa.minimum(),
+            ));
+            record.set_data(rdata);
+        }
+    }
+
+    /// Returns the node for the apex (`None`) or a relative subdomain.
+    pub fn node(&self, subdomain: Option<&str>) -> Option<&CompiledNode> {
+        match subdomain {
+            Some(label) => self.subdomains.get(label),
+            None => Some(&self.apex),
+        }
+    }
+}
+
+/// Compiles one owner's default records and its `country` table. A location
+/// listed explicitly wins over a group containing it; among groups, the one
+/// with the fewest members wins, then the first by name.
+fn compile_node(
+    owner: &Name,
+    location: &str,
+    records: &RecordSet,
+    country: &HashMap<String, RecordSet>,
+    asn: &HashMap<String, RecordSet>,
+    groups: &GeoGroups,
+    ttl: u32,
+) -> Result<(CompiledNode, usize), ZoneError> {
+    let default = compile_set(owner, location, records, ttl)?;
+    let mut record_count = default.len();
+
+    let mut geo = HashMap::with_capacity(country.len());
+    let (mut unknown, mut unavailable) = (None, None);
+    // Location key to (group size, group name, bundle).
+    let mut grouped: HashMap<String, (usize, String, Arc<RecordBundle>)> = HashMap::new();
+    for (code, overrides) in country {
+        let fallback = match code.trim() {
+            UNKNOWN_KEY => Some(&mut unknown),
+            UNAVAILABLE_KEY => Some(&mut unavailable),
+            _ => None,
+        };
+        if let Some(fallback) = fallback {
+            let geo_location = format!("{} ({})", location, code.trim());
+            let bundle = compile_set(owner, &geo_location, overrides, ttl)?;
+            record_count += bundle.len();
+            *fallback = Some(Arc::new(default.merged_with(&bundle)));
+            continue;
+        }
+        if let Some(members) = groups.get(code) {
+            let name = code.trim().to_lowercase();
+            let geo_location = format!("{} (group {})", location, name);
+            let bundle = compile_set(owner, &geo_location, overrides, ttl)?;
+            record_count += bundle.len();
+            let bundle = Arc::new(default.merged_with(&bundle));
+            for member in members {
+                let candidate = (members.len(), name.clone(), bundle.clone());
+                match grouped.get(&member.to_string()) {
+                    Some((len, other, _)) if (*len, other) <= (members.len(), &name) => {}
+                    _ => {
+                        grouped.insert(member.to_string(), candidate);
+                    }
+                }
+            }
+            continue;
+        }
+
+        let key = code
+            .parse::<LocationKey>()
+            .map_err(|reason| ZoneError::InvalidLocationKey {
+                location: location.to_string(),
+                reason,
+            })?
+            .to_string();
+        let geo_location = format!("{} (country {})", location, key);
+        let bundle = compile_set(owner, &geo_location, overrides, ttl)?;
+        record_count += bundle.len();
+        if geo
+            .insert(key.clone(), Arc::new(default.merged_with(&bundle)))
+            .is_some()
+        {
+            return Err(ZoneError::InvalidLocationKey {
+                location: location.to_string(),
+                reason: format!("'{}' is listed twice", key),
+            });
+        }
+    }
+    for (key, (_, _, bundle)) in grouped {
+        geo.entry(key).or_insert(bundle);
+    }
+
+    let mut by_asn = HashMap::with_capacity(asn.len());
+    for (key, overrides) in asn {
+        let number = parse_asn(key).map_err(|reason| ZoneError::InvalidLocationKey {
+            location: location.to_string(),
+            reason,
+        })?;
+        let asn_location = format!("{} (AS{})", location, number);
+        let bundle = compile_set(owner, &asn_location, overrides, ttl)?;
+        record_count += bundle.len();
+        if by_asn
+            .insert(number, Arc::new(default.merged_with(&bundle)))
+            .is_some()
+        {
+            return Err(ZoneError::InvalidLocationKey {
+                location: location.to_string(),
+                reason: format!("AS{} is listed twice", number),
+            });
+        }
+    }
+
+    let node = CompiledNode {
+        default: Arc::new(default),
+        country: geo,
+        asn: by_asn,
+        pops: Vec::new(),
+        unknown,
+        unavailable,
+    };
+    Ok((node, record_count))
+}
+
+/// Compiles an owner's `pops` table, merging each PoP with `default`.
+fn compile_pops(
+    owner: &Name,
+    location: &str,
+    pops: &HashMap<String, PopConfig>,
+    default: &RecordBundle,
+    ttl: u32,
+) -> Result<Vec<Pop>, ZoneError> {
+    let mut compiled = Vec::with_capacity(pops.len());
+    for (name, pop) in pops {
+        let pop_location = format!("{} (pop {})", location, name);
+        let invalid = |reason: &str| ZoneError::InvalidPop {
+            location: pop_location.clone(),
+            reason: reason.to_string(),
+        };
+        if !(-90.0..=90.0).contains(&pop.latitude) {
+            return Err(invalid("latitude must be between -90 and 90"));
+        }
+        if !(-180.0..=180.0).contains(&pop.longitude) {
+            return Err(invalid("longitude must be between -180 and 180"));
+        }
+        let weight = pop.weight.unwrap_or(1.0);
+        if !(weight.is_finite() && weight > 0.0) {
+            return Err(invalid("weight must be a positive number"));
+        }
+        let bundle = compile_set(owner, &pop_location, &pop.records, ttl)?;
+        compiled.push(Pop {
+            name: name.clone(),
+            coordinates: (pop.latitude, pop.longitude),
+            weight,
+            record_count: bundle.len(),
+            bundle: Arc::new(default.merged_with(&bundle)),
+        });
+    }
+    compiled.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(compiled)
+}
+
+fn compile_set(
+    owner: &Name,
+    location: &str,
+    set: &RecordSet,
+    ttl: u32,
+) -> Result<RecordBundle, ZoneError> {
+    let record = |rdata: RData| Record::from_rdata(owner.clone(), ttl, rdata);
+
+    let a = set
+        .a
+        .iter()
+        .map(|val| {
+            val.parse::<Ipv4Addr>()
+                .map(|ip| record(RData::A(A::from(ip))))
+                .map_err(|_| ZoneError::InvalidAddress {
+                    location: location.to_string(),
+                    field: "a",
+                    value: val.clone(),
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let aaaa = set
+        .aaaa
+        .iter()
+        .map(|val| {
+            val.parse::<Ipv6Addr>()
+                .map(|ip| record(RData::AAAA(AAAA::from(ip))))
+                .map_err(|_| ZoneError::InvalidAddress {
+                    location: location.to_string(),
+                    field: "aaaa",
+                    value: val.clone(),
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let cname = set
+        .cname
+        .iter()
+        .map(|val| parse_name(location, "cname", val).map(|n| record(RData::CNAME(CNAME(n)))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let ns = set
+        .ns
+        .iter()
+        .map(|val| parse_name(location, "ns", val).map(|n| record(RData::NS(NS(n)))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mx = set
+        .mx
+        .iter()
+        .map(
+            |MXRecord {
+                 preference,
+                 exchange,
+             }| {
+                parse_name(location, "mx", exchange)
+                    .map(|n| record(RData::MX(MX::new(*preference, n))))
+            },
+        )
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let txt = set
+        .txt
+        .iter()
+        .map(|val| {
+            if val.len() > MAX_TXT_STRING_LEN {
+                return Err(ZoneError::InvalidTxt {
+                    location: location.to_string(),
+                    len: val.len(),
+                });
+            }
+            Ok(record(RData::TXT(TXT::new(vec![val.clone()]))))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(RecordBundle {
+        a: a.into(),
+        aaaa: aaaa.into(),
+        cname: cname.into(),
+        mx: mx.into(),
+        txt: txt.into(),
+        ns: ns.into(),
+    })
+}
+
+fn parse_owner(owner: &str) -> Result<Name, ZoneError> {
+    let mut name = Name::from_str(owner).map_err(|e| ZoneError::InvalidOwner {
+        owner: owner.to_string(),
+        reason: e.to_string(),
+    })?;
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+fn parse_name(location: &str, field: &'static str, value: &str) -> Result<Name, ZoneError> {
+    let mut name = Name::from_str(value).map_err(|e| ZoneError::InvalidName {
+        location: location.to_string(),
+        field,
+        value: value.to_string(),
+        reason: e.to_string(),
+    })?;
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(domain: &str, source: &str, default_ttl: u32) -> Result<CompiledZone, ZoneError> {
+        let zone: ZoneConfig = toml::from_str(source).unwrap();
+        CompiledZone::compile(domain, &zone, default_ttl, &GeoGroups::default())
+    }
+
+    #[test]
+    fn compiles_a_valid_zone() {
+        let zone = compile(
+            "example.com",
+            r#"
+            [soa]
+            mname = "ns1.example.com."
+            rname = "admin.example.com."
+
+            [apex]
+            ns = ["ns1.example.com."]
+            a = ["192.0.2.1"]
+
+            [www]
+            cname = ["example.com."]
+            "#,
+            5,
+        )
+        .unwrap();
+        assert_eq!(zone.record_count, 4);
+        assert_eq!(zone.apex.default.a[0].ttl(), 300);
+        assert!(zone.node(Some("www")).is_some());
+        assert!(zone.node(Some("mail")).is_none());
+    }
+
+    #[test]
+    fn rejects_ns_without_soa() {
+        let error = compile("example.com", "[apex]\nns = [\"ns1.example.com.\"]", 5);
+        assert!(matches!(error, Err(ZoneError::MissingSoa)));
+    }
+
+    #[test]
+    fn rejects_ttl_overflowing_seconds() {
+        let error = compile("example.com", "ttl = 71582789", 5);
+        assert!(matches!(
+            error,
+            Err(ZoneError::InvalidTtl { ttl: 71582789 })
+        ));
+        let error = compile("example.com", "", u32::MAX);
+        assert!(matches!(error, Err(ZoneError::InvalidTtl { .. })));
+        assert!(compile("example.com", "ttl = 71582788", 5).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_owner_names() {
+        let label = "a".repeat(64);
+        let error = compile(&format!("{}.com", label), "", 5);
+        assert!(matches!(error, Err(ZoneError::InvalidOwner { .. })));
+        let error = compile(
+            "example.com",
+            &format!("[{}]\na = [\"192.0.2.1\"]", label),
+            5,
+        );
+        assert!(matches!(error, Err(ZoneError::InvalidOwner { .. })));
+    }
+
+    #[test]
+    fn rejects_labels_differing_only_in_case() {
+        let source = "[www]\na = [\"192.0.2.1\"]\n\n[WWW]\na = [\"192.0.2.2\"]";
+        match compile("example.com", source, 5) {
+            Err(ZoneError::InvalidOwner { owner, .. }) => assert_eq!(owner, "www"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_target_names() {
+        let label = "a".repeat(64);
+        let error = compile(
+            "example.com",
+            &format!("[www]\ncname = [\"{}.com.\"]", label),
+            5,
+        );
+        match error {
+            Err(ZoneError::InvalidName {
+                location, field, ..
+            }) => assert_eq!((location.as_str(), field), ("www", "cname")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let source = format!(
+            "[soa]\nmname = \"{}.com.\"\nrname = \"admin.example.com.\"",
+            label
+        );
+        let error = compile("example.com", &source, 5);
+        assert!(matches!(
+            error,
+            Err(ZoneError::InvalidName { field: "mname", .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        let error = compile("example.com", "[apex]\na = [\"2001:db8::1\"]", 5);
+        assert!(matches!(
+            error,
+            Err(ZoneError::InvalidAddress { field: "a", .. })
+        ));
+        let error = compile(
+            "example.com",
+            "[country]\nUS = { aaaa = [\"192.0.2.1\"] }",
+            5,
+        );
+        match error {
+            Err(ZoneError::InvalidAddress {
+                location, field, ..
+            }) => assert_eq!((location.as_str(), field), ("@ (country US)", "aaaa")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_overlong_txt_strings() {
+        let source = format!("[apex]\ntxt = [\"{}\"]", "x".repeat(256));
+        let error = compile("example.com", &source, 5);
+        assert!(matches!(error, Err(ZoneError::InvalidTxt { len: 256, .. })));
+        let source = format!("[apex]\ntxt = [\"{}\"]", "x".repeat(255));
+        assert!(compile("example.com", &source, 5).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_and_repeated_location_keys() {
+        for table in [
+            "\"continent:\" = { a = [\"192.0.2.10\"] }",
+            "US = { a = [\"192.0.2.10\"] }\nus = { a = [\"192.0.2.11\"] }",
+        ] {
+            let source = format!("[www]\na = [\"192.0.2.1\"]\n\n[www.country]\n{}", table);
+            match compile("example.com", &source, 5) {
+                Err(ZoneError::InvalidLocationKey { location, .. }) => assert_eq!(location, "www"),
+                other => panic!("{}: {:?}", table, other.map(|_| ())),
+            }
+        }
+        let source = "[www.asn]\n4134 = { a = [\"192.0.2.10\"] }";
+        assert!(matches!(
+            compile("example.com", source, 5),
+            Err(ZoneError::InvalidLocationKey { .. })
+        ));
+    }
+
+    #[test]
+    fn explicit_keys_then_smaller_groups_then_names_win() {
+        let group = |members: &[&str]| members.iter().map(|m| m.parse().unwrap()).collect();
+        let groups = GeoGroups::new(HashMap::from([
+            ("big".to_string(), group(&["JP", "KR", "SG", "AU"])),
+            ("east".to_string(), group(&["JP", "KR"])),
+            ("zulu".to_string(), group(&["SG", "NZ"])),
+            ("alfa".to_string(), group(&["NZ", "AU"])),
+        ]));
+        let source = r#"
+            [www]
+            a = ["192.0.2.1"]
+
+            [www.country]
+            big = { a = ["192.0.2.40"] }
+            EAST = { a = ["192.0.2.41"] }
+            zulu = { a = ["192.0.2.42"] }
+            alfa = { a = ["192.0.2.43"] }
+            jp = { a = ["192.0.2.44"] }
+        "#;
+        let zone: ZoneConfig = toml::from_str(source).unwrap();
+        let zone = CompiledZone::compile("example.com", &zone, 5, &groups).unwrap();
+        let country = &zone.node(Some("www")).unwrap().country;
+        let address = |key: &str| country[key].a[0].data().to_string();
+
+        assert_eq!(address("JP"), "192.0.2.44");
+        assert_eq!(address("KR"), "192.0.2.41");
+        assert_eq!(address("SG"), "192.0.2.42");
+        assert_eq!(address("AU"), "192.0.2.43");
+        assert_eq!(address("NZ"), "192.0.2.43");
+        assert_eq!(country.len(), 5);
+    }
+
+    fn pops(pops: &str) -> CompiledZone {
+        let source = format!("[www]\na = [\"192.0.2.1\"]\n{}", pops);
+        compile("example.com", &source, 5).unwrap()
+    }
+
+    fn nearest(zone: &CompiledZone, coordinates: (f64, f64)) -> Option<&str> {
+        let node = zone.node(Some("www")).unwrap();
+        node.nearest_pop(coordinates).map(|pop| pop.name.as_str())
+    }
+
+    const REYKJAVIK: (f64, f64) = (64.15, -21.94);
+
+    #[test]
+    fn nearest_pop_divides_distance_by_weight() {
+        // Reykjavik is about 2380 km from Frankfurt and 4200 km from New York.
+        let unweighted = pops(
+            r#"
+            [www.pops.fra]
+            latitude = 50.11
+            longitude = 8.68
+
+            [www.pops.nyc]
+            latitude = 40.71
+            longitude = -74.01
+            "#,
+        );
+        assert_eq!(nearest(&unweighted, REYKJAVIK), Some("fra"));
+        assert_eq!(nearest(&unweighted, (42.36, -71.06)), Some("nyc"));
+
+        let weighted = pops(
+            r#"
+            [www.pops.fra]
+            latitude = 50.11
+            longitude = 8.68
+
+            [www.pops.nyc]
+            latitude = 40.71
+            longitude = -74.01
+            weight = 2
+            "#,
+        );
+        assert_eq!(nearest(&weighted, REYKJAVIK), Some("nyc"));
+        assert_eq!(nearest(&weighted, (48.86, 2.35)), Some("fra"));
+        assert_eq!(nearest(&pops(""), REYKJAVIK), None);
+    }
+
+    #[test]
+    fn nearest_pop_ties_go_to_the_first_name() {
+        let zone = pops(
+            r#"
+            [www.pops.zrh]
+            latitude = 47.37
+            longitude = 8.54
+
+            [www.pops.ams]
+            latitude = 47.37
+            longitude = 8.54
+
+            [www.pops.mil]
+            latitude = 47.37
+            longitude = 8.54
+            "#,
+        );
+        assert_eq!(nearest(&zone, REYKJAVIK), Some("ams"));
+    }
+
+    #[test]
+    fn rejects_invalid_pops() {
+        for pop in [
+            "latitude = 90.5\nlongitude = 0.0",
+            "latitude = 0.0\nlongitude = -180.5",
+            "latitude = 0.0\nlongitude = 0.0\nweight = 0",
+            "latitude = 0.0\nlongitude = 0.0\nweight = -1",
+        ] {
+            let source = format!("[www.pops.fra]\n{}", pop);
+            match compile("example.com", &source, 5) {
+                Err(ZoneError::InvalidPop { location, .. }) => {
+                    assert_eq!(location, "www (pop fra)")
+                }
+                other => panic!("{}: {:?}", pop, other.map(|_| ())),
+            }
+        }
+    }
+}